@@ -0,0 +1,203 @@
+use super::*;
+use crate::{
+    reaper::{reaper_web_client::ReaperWebClient, VideoSync},
+    session_manifest::{ChildExitStatus, RecoveredTake, SessionManifestFile, VideoSegments},
+    session_markers::SessionMarkers,
+    video_capture::gstreamer_process::{
+        pipeline_messages::{PipelineLog, PipelineMessageKind},
+        GstreamerInstance,
+    },
+};
+use std::path::Path;
+use tokio_util::sync::CancellationToken;
+
+/// seconds to wait before each restart attempt, the last one keeps repeating
+const BACKOFF_SECONDS: [u64; 6] = [1, 2, 4, 8, 16, 30];
+const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// `<name>---restart-01.<ext>` next to the original take
+pub fn restart_file_path(original: &Path, restart: u32) -> PathBuf {
+    let stem = original
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    original.with_file_name(match original.extension() {
+        Some(extension) => format!(
+            "{stem}---restart-{restart:02}.{}",
+            extension.to_string_lossy()
+        ),
+        None => format!("{stem}---restart-{restart:02}"),
+    })
+}
+
+/// keeps a camera recording for the whole session, a dead pipeline gets restarted
+/// into a new file and the gap ends up in the manifest and as a REAPER marker,
+/// `safe_stop` finalizes the recording for good
+pub struct CaptureSupervisor {
+    current: Arc<RwLock<GstreamerInstance>>,
+    _supervisor: AbortOnDrop<()>,
+}
+
+impl CaptureSupervisor {
+    /// `camera_index` is the position of this camera in the manifest run
+    pub fn new(
+        gstreamer: GstreamerInstance,
+        camera_index: usize,
+        manifest: SessionManifestFile,
+        markers: SessionMarkers,
+        web_client: Arc<ReaperWebClient>,
+        video_sync: VideoSync,
+        safe_stop: CancellationToken,
+        notify: ProcessEventBus,
+    ) -> Self {
+        let original_file = gstreamer.video_file_path.clone();
+        let current = Arc::new(RwLock::new(gstreamer));
+        let supervisor = {
+            to_owned![current];
+            tokio::task::spawn(async move {
+                let mut interval = crate::process::app_interval(CHECK_INTERVAL);
+                let mut restarts = 0;
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = safe_stop.cancelled() => {
+                            let current = current.read();
+                            current.log.push(
+                                PipelineMessageKind::Warning,
+                                "low disk space, finalizing the recording".to_owned(),
+                            );
+                            current.stop();
+                            return;
+                        }
+                    }
+                    if current.read().is_running() {
+                        continue;
+                    }
+                    // the check only runs twice a second, the camera may have stopped well before
+                    let gap_started_at = current.read().last_frame_at();
+                    let log = current.read().log.clone();
+                    let mut attempt = 0;
+                    let next = loop {
+                        let delay = BACKOFF_SECONDS[attempt.min(BACKOFF_SECONDS.len() - 1)];
+                        log.push(
+                            PipelineMessageKind::Warning,
+                            format!("pipeline stopped, restarting in {delay}s"),
+                        );
+                        tokio::time::sleep(std::time::Duration::from_secs(delay)).await;
+                        if safe_stop.is_cancelled() {
+                            return;
+                        }
+                        // a failed attempt leaves nothing worth keeping, the next one reuses its file
+                        let restarted = {
+                            let current = current.read();
+                            current.restart(
+                                restart_file_path(&original_file, restarts + 1),
+                                notify.clone(),
+                            )
+                        }
+                        .await;
+                        match restarted {
+                            Ok(next) => {
+                                restarts += 1;
+                                break next;
+                            }
+                            Err(message) => {
+                                tracing::warn!(?message, attempt, "restarting capture failed");
+                                log.push(
+                                    PipelineMessageKind::Error,
+                                    format!("restart failed: {message:#}"),
+                                );
+                                attempt += 1;
+                            }
+                        }
+                    };
+                    let take = RecoveredTake {
+                        gap_started_at,
+                        gap_seconds: (next.started_at - gap_started_at).num_milliseconds() as f64
+                            / 1000.0,
+                        video_file: next.first_file(),
+                        video_started_at: next.started_at,
+                        item_position_seconds: video_sync.video_position_at(next.started_at),
+                        placed_in_project: false,
+                    };
+                    let note = format!(
+                        "video gap on {} ({:.1}s), continues in {}",
+                        next.video_device,
+                        take.gap_seconds,
+                        take.video_file.display()
+                    );
+                    log.push(PipelineMessageKind::State, note.clone());
+                    if let Err(message) = manifest.update_run(|run| {
+                        if let Some(camera) = run.cameras.get_mut(camera_index) {
+                            camera.recoveries.push(take);
+                        }
+                    }) {
+                        tracing::warn!(?message, "recovered take not in the manifest");
+                    }
+                    *current.write() = next;
+                    notify.send(ProcessEvent::NewInput).ok();
+                    let pending = markers.insert(web_client.clone());
+                    if let Err(message) = markers
+                        .clone()
+                        .commit(pending, note, web_client.clone())
+                        .await
+                    {
+                        tracing::warn!(?message, "video gap marker not placed");
+                    }
+                }
+            })
+            .abort_on_drop()
+        };
+        Self {
+            current,
+            _supervisor: supervisor,
+        }
+    }
+
+    /// shared by every restart of this camera
+    pub fn log(&self) -> PipelineLog {
+        self.current.read().log.clone()
+    }
+
+    pub fn video_device(&self) -> VideoDevice {
+        self.current.read().video_device.clone()
+    }
+
+    /// every restart reports into the same handle
+    pub fn exit_status(&self) -> ChildExitStatus {
+        self.current.read().exit_status.clone()
+    }
+
+    /// every restart adds its segments here, relative to the first pipeline of the camera
+    pub fn segments(&self) -> VideoSegments {
+        self.current.read().segments.clone()
+    }
+}
+
+impl RenderToTerm for CaptureSupervisor {
+    fn render_to_term<B: Backend>(
+        &mut self,
+        f: &mut Frame<B>,
+        rect: tui::layout::Rect,
+    ) -> Result<()> {
+        self.current.write().render_to_term(f, rect)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restarts_are_numbered_next_to_the_original_take() {
+        let original = Path::new("/sessions/song/video-recordings/song---video1---2023.mkv");
+        assert_eq!(
+            restart_file_path(original, 1),
+            Path::new("/sessions/song/video-recordings/song---video1---2023---restart-01.mkv")
+        );
+        assert_eq!(
+            restart_file_path(Path::new("take"), 12),
+            Path::new("take---restart-12")
+        );
+    }
+}
@@ -0,0 +1,955 @@
+use super::*;
+use crate::{
+    directory_shenanigans::{config_dir, home_dir, ExistingDirectoryExt},
+    space_available_watcher::LowDiskThresholds,
+    video_capture::capture_settings::CaptureSettings,
+};
+use std::collections::BTreeMap;
+
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+pub const DEFAULT_SESSIONS_DIRECTORY: &str = "/mnt/md0/manual-backup/reaper-sessions";
+
+/// per-user studio config, by default read from ~/.config/studio-barlog-ctl/config.toml
+///
+/// ```toml
+/// default-profile = "big-room"
+///
+/// [profiles.big-room]
+/// sessions-directory = "/mnt/md0/manual-backup/reaper-sessions"
+/// template = "~/reaper-templates/big-room.RPP"
+/// reaper-web-base-url = "http://localhost:8080/"
+/// # a single device or a list, one capture pipeline per camera, `card:"..."`,
+/// # `by-id:...` and `serial:...` find the camera even after it got renumbered
+/// video-device = ['card:"Magewell Pro Capture"', "serial:1234ABCD"]
+/// # free gigabytes left when the header starts flashing and when everything gets stopped
+/// low-disk-warning = 50
+/// low-disk-critical = 10
+/// # optional, finished video segments and REAPER's audio get copied there during the session
+/// mirror-directory = "/mnt/backup/reaper-sessions"
+///
+/// [profiles.big-room.capture]
+/// resolution = "1920x1080"
+/// format = "YUY2"
+/// framerate = "25/1"
+/// encoder = "x264"
+/// bitrate = 20000
+/// container = "mkv"
+/// # optional, a new file every 10 minutes or 4GB, whichever comes first
+/// segment-duration = 600
+/// segment-size = 4096
+/// ```
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    /// used when no profile is passed explicitly
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, StudioProfile>,
+}
+
+/// fields are kept as raw strings so that every bad one gets reported, not only the first
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct StudioProfile {
+    pub sessions_directory: Option<String>,
+    pub template: Option<String>,
+    pub reaper_web_base_url: Option<String>,
+    pub video_device: Option<VideoDevices>,
+    pub low_disk_warning: Option<u32>,
+    pub low_disk_critical: Option<u32>,
+    pub mirror_directory: Option<String>,
+    #[serde(default)]
+    pub capture: CaptureProfile,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(untagged)]
+pub enum VideoDevices {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl VideoDevices {
+    /// same shape as the command line value
+    fn joined(self) -> String {
+        match self {
+            Self::One(device) => device,
+            Self::Many(devices) => devices.join(","),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CaptureProfile {
+    pub resolution: Option<String>,
+    pub format: Option<String>,
+    pub framerate: Option<String>,
+    pub encoder: Option<String>,
+    pub bitrate: Option<u32>,
+    pub container: Option<String>,
+    pub segment_duration: Option<u32>,
+    pub segment_size: Option<u32>,
+}
+
+impl ConfigFile {
+    pub fn default_path() -> Result<PathBuf> {
+        config_dir().map(|dir| dir.join(CONFIG_FILE_NAME))
+    }
+
+    /// missing file is fine unless it was requested explicitly
+    #[instrument(ret, err, level = "debug")]
+    pub fn load(path: Option<PathBuf>) -> Result<(PathBuf, Self)> {
+        let explicit = path.is_some();
+        path.map(Ok)
+            .unwrap_or_else(Self::default_path)
+            .and_then(|path| match (path.exists(), explicit) {
+                (false, false) => Ok((path, Self::default())),
+                _ => std::fs::read_to_string(&path)
+                    .wrap_err("reading config file")
+                    .and_then(|content| toml::from_str(&content).wrap_err("parsing config file"))
+                    .map(|config| (path.clone(), config))
+                    .wrap_err_with(|| format!("loading {}", path.display())),
+            })
+    }
+
+    /// no profile at all is fine as long as everything gets passed on the command line
+    pub fn profile(&self, name: Option<&str>) -> Result<Option<(String, StudioProfile)>> {
+        match name.or(self.default_profile.as_deref()) {
+            Some(name) => self
+                .profiles
+                .get(name)
+                .cloned()
+                .map(|profile| Some((name.to_owned(), profile)))
+                .ok_or_else(|| {
+                    eyre!(
+                        "no profile named '{name}', available: [{}]",
+                        self.profiles.keys().join(", ")
+                    )
+                }),
+            None => Ok(None),
+        }
+    }
+}
+
+/// profile selection and the sessions directory, shared by everything that touches sessions
+#[derive(Args, Debug)]
+pub struct SessionsDirectoryArgs {
+    /// Studio profile from the config file, `default-profile` is used when not specified
+    #[arg(long, env = "STUDIO_BARLOG_PROFILE")]
+    profile: Option<String>,
+    /// specify base directory for all sessions [default: /mnt/md0/manual-backup/reaper-sessions]
+    #[arg(long, env = "STUDIO_BARLOG_SESSIONS_DIRECTORY")]
+    sessions_directory: Option<String>,
+}
+
+/// anything not given falls back to the profile and then to [CaptureSettings::default]
+#[derive(Args, Debug, Default)]
+pub struct CaptureSettingsArgs {
+    /// Capture resolution [default: 1920x1080]
+    #[arg(long, env = "STUDIO_BARLOG_RESOLUTION")]
+    resolution: Option<String>,
+    /// Pixel format requested from the camera, one of YUY2, NV12, I420, MJPG [default: YUY2]
+    #[arg(long, env = "STUDIO_BARLOG_PIXEL_FORMAT")]
+    pixel_format: Option<String>,
+    /// Capture framerate, eg. 25 or 30000/1001 [default: 25/1]
+    #[arg(long, env = "STUDIO_BARLOG_FRAMERATE")]
+    framerate: Option<String>,
+    /// One of x264, x265, ffv1, passthrough (MJPEG cameras only) [default: x264]
+    #[arg(long, env = "STUDIO_BARLOG_ENCODER")]
+    encoder: Option<String>,
+    /// Encoder bitrate in kbit/s, x264 and x265 only [default: 20000]
+    #[arg(long, env = "STUDIO_BARLOG_BITRATE")]
+    bitrate: Option<String>,
+    /// One of mkv, mp4 (fragmented), mov [default: mkv]
+    #[arg(long, env = "STUDIO_BARLOG_CONTAINER")]
+    container: Option<String>,
+    /// Record in segments of this many seconds, so that a crash loses at most one segment
+    #[arg(long, env = "STUDIO_BARLOG_SEGMENT_DURATION")]
+    segment_duration: Option<String>,
+    /// Record in segments of at most this many megabytes
+    #[arg(long, env = "STUDIO_BARLOG_SEGMENT_SIZE")]
+    segment_size: Option<String>,
+}
+
+/// command line flags and env vars take precedence over the selected profile
+#[derive(Args, Debug)]
+pub struct MainConfigArgs {
+    #[command(flatten)]
+    sessions: SessionsDirectoryArgs,
+    #[command(flatten)]
+    capture: CaptureSettingsArgs,
+    /// Project name to create
+    #[arg(long)]
+    project_name: ProjectName,
+    /// Template to be used
+    #[arg(long, env = "STUDIO_BARLOG_TEMPLATE")]
+    template: Option<String>,
+    #[arg(long, env = "STUDIO_BARLOG_REAPER_WEB_BASE_URL")]
+    reaper_web_base_url: Option<String>,
+    /// Camera to record, repeat it (or separate with commas) for more cameras,
+    /// `card:"<name>"`, `by-id:<id>` or `serial:<serial>` select it regardless of its number,
+    /// `test:<pattern>` records a videotestsrc pattern instead
+    #[arg(long, env = "STUDIO_BARLOG_VIDEO_DEVICE", value_delimiter = ',')]
+    video_device: Vec<String>,
+    /// Free gigabytes on the sessions disk below which the header flashes [default: 50]
+    #[arg(long, env = "STUDIO_BARLOG_LOW_DISK_WARNING")]
+    low_disk_warning: Option<String>,
+    /// Free gigabytes below which REAPER and the cameras are stopped and saved [default: 10]
+    #[arg(long, env = "STUDIO_BARLOG_LOW_DISK_CRITICAL")]
+    low_disk_critical: Option<String>,
+    /// Second disk the recordings get copied to during the session, checksummed
+    #[arg(long, env = "STUDIO_BARLOG_MIRROR_DIRECTORY")]
+    mirror_directory: Option<String>,
+}
+
+#[derive(Debug)]
+struct Sourced {
+    value: String,
+    source: String,
+}
+
+fn field<T>(
+    name: &str,
+    value: Option<Sourced>,
+    parse: impl FnOnce(&str) -> Result<T>,
+) -> Result<T> {
+    value
+        .ok_or_else(|| eyre!("missing - pass --{name} or set it in a profile"))
+        .and_then(|Sourced { value, source }| {
+            parse(&value).wrap_err_with(|| format!("invalid value '{value}' (from {source})"))
+        })
+        .wrap_err_with(|| format!("bad {name}"))
+}
+
+fn pick(cli: Option<String>, from_profile: Option<String>, profile: &str) -> Option<Sourced> {
+    cli.map(|value| Sourced {
+        value,
+        source: "command line or environment".to_owned(),
+    })
+    .or_else(|| {
+        from_profile.map(|value| Sourced {
+            value,
+            source: profile.to_owned(),
+        })
+    })
+}
+
+fn sessions_directory_field(value: Option<Sourced>) -> Result<SessionsDirectory> {
+    field(
+        "sessions-directory",
+        value.or_else(|| {
+            Some(Sourced {
+                value: DEFAULT_SESSIONS_DIRECTORY.to_owned(),
+                source: "built-in default".to_owned(),
+            })
+        }),
+        |value| {
+            expand_home(value)
+                .and_then(|path| path.directory_exists())
+                .map(SessionsDirectory)
+        },
+    )
+}
+
+/// config file and the selected profile, along with a description of where it came from
+fn load_profile(
+    config_path: Option<PathBuf>,
+    profile: Option<&str>,
+) -> Result<(PathBuf, Option<String>, StudioProfile, String)> {
+    let (config_path, config_file) = ConfigFile::load(config_path)?;
+    let (profile_name, profile) = config_file
+        .profile(profile)
+        .wrap_err_with(|| format!("selecting profile from {}", config_path.display()))?
+        .map(|(name, profile)| (Some(name), profile))
+        .unwrap_or_default();
+    let profile_source = format!(
+        "profile '{}' in {}",
+        profile_name.clone().unwrap_or_default(),
+        config_path.display()
+    );
+    Ok((config_path, profile_name, profile, profile_source))
+}
+
+fn field_or_default<T: ToString>(
+    name: &str,
+    value: Option<Sourced>,
+    default: T,
+    parse: impl FnOnce(&str) -> Result<T>,
+) -> Result<T> {
+    field(
+        name,
+        value.or_else(|| {
+            Some(Sourced {
+                value: default.to_string(),
+                source: "built-in default".to_owned(),
+            })
+        }),
+        parse,
+    )
+}
+
+/// not given at all is fine
+fn optional_field<T>(
+    name: &str,
+    value: Option<Sourced>,
+    parse: impl FnOnce(&str) -> Result<T>,
+) -> Result<Option<T>> {
+    value
+        .map(|value| field(name, Some(value), parse))
+        .transpose()
+}
+
+fn expand_home(value: &str) -> Result<PathBuf> {
+    match value.strip_prefix("~/") {
+        Some(relative) => home_dir().map(|home| home.join(relative)),
+        None => value.parse().wrap_err("invalid path"),
+    }
+}
+
+pub fn parse_template(value: &str) -> Result<PathBuf> {
+    expand_home(value)
+        .and_then(|path| {
+            path.is_file()
+                .then(|| path.clone())
+                .ok_or_else(|| eyre!("file {} does not exist", path.display()))
+        })
+        .and_then(|path| {
+            path.extension()
+                .and_then(|extension| extension.to_str())
+                .map(|extension| extension.eq_ignore_ascii_case("rpp"))
+                .unwrap_or_default()
+                .then_some(path)
+                .ok_or_else(|| eyre!("not a reaper project (.rpp) file"))
+        })
+}
+
+/// comma separated, every device gets its own capture pipeline
+pub fn parse_video_devices(value: &str) -> Result<Vec<VideoDevice>> {
+    let devices = value
+        .split(',')
+        .map(str::trim)
+        .filter(|device| !device.is_empty())
+        .map(|device| VideoDevice::new_checked(device).wrap_err_with(|| format!("bad '{device}'")))
+        .collect::<Result<Vec<_>>>()?;
+    match (devices.is_empty(), devices.iter().duplicates().next()) {
+        (true, _) => bail!("no video device given"),
+        (false, Some(duplicate)) => bail!("{duplicate} is listed more than once"),
+        (false, None) => Ok(devices),
+    }
+}
+
+pub fn parse_reaper_web_base_url(value: &str) -> Result<reqwest::Url> {
+    reqwest::Url::parse(value)
+        .wrap_err_with(|| format!("not a valid url - did you mean 'http://{value}/'?"))
+        .and_then(|url| match url.scheme() {
+            "http" | "https" => Ok(url),
+            other => Err(eyre!(
+                "scheme must be http or https, not '{other}' - did you mean 'http://{value}/'?"
+            )),
+        })
+        .and_then(|url| {
+            url.host_str()
+                .is_some()
+                .then(|| url.clone())
+                .ok_or_else(|| eyre!("no host in url"))
+        })
+        .and_then(|url| {
+            url.path()
+                .ends_with('/')
+                .then(|| url.clone())
+                .ok_or_else(|| eyre!("url must end with '/' - did you mean '{url}/'?"))
+        })
+}
+
+impl SessionsDirectoryArgs {
+    #[instrument(ret, err)]
+    pub fn resolve(self, config_path: Option<PathBuf>) -> Result<SessionsDirectory> {
+        let Self {
+            profile,
+            sessions_directory,
+        } = self;
+        load_profile(config_path, profile.as_deref()).and_then(|(_, _, profile, profile_source)| {
+            sessions_directory_field(pick(
+                sessions_directory,
+                profile.sessions_directory,
+                &profile_source,
+            ))
+        })
+    }
+}
+
+impl CaptureSettingsArgs {
+    fn resolve_with(
+        self,
+        profile: CaptureProfile,
+        profile_source: &str,
+    ) -> Result<CaptureSettings> {
+        let Self {
+            resolution,
+            pixel_format,
+            framerate,
+            encoder,
+            bitrate,
+            container,
+            segment_duration,
+            segment_size,
+        } = self;
+        let defaults = CaptureSettings::default();
+        let pick = |cli: Option<String>, from_profile: Option<String>| {
+            pick(cli, from_profile, profile_source)
+        };
+        let resolution = field_or_default(
+            "resolution",
+            pick(resolution, profile.resolution),
+            defaults.resolution,
+            str::parse,
+        );
+        let format = field_or_default(
+            "pixel-format",
+            pick(pixel_format, profile.format),
+            defaults.format,
+            |value| value.parse().wrap_err("unknown pixel format"),
+        );
+        let framerate = field_or_default(
+            "framerate",
+            pick(framerate, profile.framerate),
+            defaults.framerate,
+            str::parse,
+        );
+        let encoder = field_or_default(
+            "encoder",
+            pick(encoder, profile.encoder),
+            defaults.encoder,
+            |value| value.parse().wrap_err("unknown encoder"),
+        );
+        let bitrate = field_or_default(
+            "bitrate",
+            pick(bitrate, profile.bitrate.map(|bitrate| bitrate.to_string())),
+            defaults.bitrate,
+            |value| value.parse().wrap_err("not a number"),
+        );
+        let container = field_or_default(
+            "container",
+            pick(container, profile.container),
+            defaults.container,
+            |value| value.parse().wrap_err("unknown container"),
+        );
+        let segment_seconds = optional_field(
+            "segment-duration",
+            pick(
+                segment_duration,
+                profile.segment_duration.map(|seconds| seconds.to_string()),
+            ),
+            |value| value.parse().wrap_err("not a number of seconds"),
+        );
+        let segment_megabytes = optional_field(
+            "segment-size",
+            pick(
+                segment_size,
+                profile.segment_size.map(|megabytes| megabytes.to_string()),
+            ),
+            |value| value.parse().wrap_err("not a number of megabytes"),
+        );
+        match (
+            resolution,
+            format,
+            framerate,
+            encoder,
+            bitrate,
+            container,
+            segment_seconds,
+            segment_megabytes,
+        ) {
+            (
+                Ok(resolution),
+                Ok(format),
+                Ok(framerate),
+                Ok(encoder),
+                Ok(bitrate),
+                Ok(container),
+                Ok(segment_seconds),
+                Ok(segment_megabytes),
+            ) => CaptureSettings {
+                resolution,
+                format,
+                framerate,
+                encoder,
+                bitrate,
+                container,
+                segment_seconds,
+                segment_megabytes,
+                preview: Default::default(),
+            }
+            .validate(),
+            (
+                resolution,
+                format,
+                framerate,
+                encoder,
+                bitrate,
+                container,
+                segment_seconds,
+                segment_megabytes,
+            ) => bail!(
+                "{}",
+                [
+                    resolution.err(),
+                    format.err(),
+                    framerate.err(),
+                    encoder.err(),
+                    bitrate.err(),
+                    container.err(),
+                    segment_seconds.err(),
+                    segment_megabytes.err(),
+                ]
+                .into_iter()
+                .flatten()
+                .map(|report| format!("{report:#}"))
+                .join("\n")
+            ),
+        }
+    }
+
+    /// for tools that don't read the config file
+    pub fn resolve_without_profile(self) -> Result<CaptureSettings> {
+        self.resolve_with(Default::default(), "no profile")
+    }
+}
+
+impl MainConfigArgs {
+    #[instrument(ret, err)]
+    pub fn resolve(self, config_path: Option<PathBuf>) -> Result<MainConfig> {
+        let Self {
+            sessions:
+                SessionsDirectoryArgs {
+                    profile,
+                    sessions_directory,
+                },
+            capture,
+            project_name,
+            template,
+            reaper_web_base_url,
+            video_device,
+            low_disk_warning,
+            low_disk_critical,
+            mirror_directory,
+        } = self;
+        let (config_path, profile_name, profile, profile_source) =
+            load_profile(config_path, profile.as_deref())?;
+        let pick = |cli: Option<String>, from_profile: Option<String>| {
+            pick(cli, from_profile, &profile_source)
+        };
+
+        let sessions_directory =
+            sessions_directory_field(pick(sessions_directory, profile.sessions_directory));
+        let template = field("template", pick(template, profile.template), parse_template);
+        let capture_settings = capture.resolve_with(profile.capture, &profile_source);
+        let reaper_web_base_url = field(
+            "reaper-web-base-url",
+            pick(reaper_web_base_url, profile.reaper_web_base_url),
+            parse_reaper_web_base_url,
+        );
+        let video_devices = field(
+            "video-device",
+            pick(
+                (!video_device.is_empty()).then(|| video_device.join(",")),
+                profile.video_device.map(VideoDevices::joined),
+            ),
+            parse_video_devices,
+        );
+        let gigabytes = |value: &str| value.parse::<u32>().wrap_err("not a number of gigabytes");
+        let low_disk_warning = field_or_default(
+            "low-disk-warning",
+            pick(
+                low_disk_warning,
+                profile
+                    .low_disk_warning
+                    .map(|gigabytes| gigabytes.to_string()),
+            ),
+            LowDiskThresholds::DEFAULT_WARNING_GIGABYTES,
+            gigabytes,
+        );
+        let low_disk_critical = field_or_default(
+            "low-disk-critical",
+            pick(
+                low_disk_critical,
+                profile
+                    .low_disk_critical
+                    .map(|gigabytes| gigabytes.to_string()),
+            ),
+            LowDiskThresholds::DEFAULT_CRITICAL_GIGABYTES,
+            gigabytes,
+        );
+        let low_disk = match (low_disk_warning, low_disk_critical) {
+            (Ok(warning), Ok(critical)) => LowDiskThresholds::from_gigabytes(warning, critical)
+                .wrap_err("bad low-disk-critical"),
+            (warning, critical) => Err(eyre!(
+                "{}",
+                [warning.err(), critical.err()]
+                    .into_iter()
+                    .flatten()
+                    .map(|report| format!("{report:#}"))
+                    .join("\n")
+            )),
+        };
+        let mirror_directory = optional_field(
+            "mirror-directory",
+            pick(mirror_directory, profile.mirror_directory),
+            |value| {
+                expand_home(value)
+                    .and_then(|path| path.directory_exists())
+                    .map(|directory| directory.as_ref().to_owned())
+            },
+        );
+
+        match (
+            sessions_directory,
+            template,
+            reaper_web_base_url,
+            video_devices,
+            capture_settings,
+            low_disk,
+            mirror_directory,
+        ) {
+            (
+                Ok(sessions_directory),
+                Ok(template),
+                Ok(reaper_web_base_url),
+                Ok(video_devices),
+                Ok(capture_settings),
+                Ok(low_disk),
+                Ok(mirror_directory),
+            ) => Ok(MainConfig {
+                sessions_directory,
+                project_name,
+                template,
+                reaper_web_base_url,
+                video_devices,
+                capture_settings,
+                low_disk,
+                mirror_directory,
+            }),
+            (
+                sessions_directory,
+                template,
+                reaper_web_base_url,
+                video_devices,
+                capture_settings,
+                low_disk,
+                mirror_directory,
+            ) => {
+                bail!(
+                    "invalid studio config ({}):\n{}",
+                    profile_name
+                        .map(|name| format!("profile '{name}' in {}", config_path.display()))
+                        .unwrap_or_else(|| "no profile selected".to_owned()),
+                    [
+                        sessions_directory.err(),
+                        template.err(),
+                        reaper_web_base_url.err(),
+                        video_devices.err(),
+                        capture_settings.err(),
+                        low_disk.err(),
+                        mirror_directory.err(),
+                    ]
+                    .into_iter()
+                    .flatten()
+                    .flat_map(|report| {
+                        format!("{report:#}")
+                            .lines()
+                            .map(|line| format!("  - {line}"))
+                            .collect_vec()
+                    })
+                    .join("\n")
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the built-in sessions directory would get created otherwise
+    fn args(sessions_directory: &std::path::Path) -> MainConfigArgs {
+        MainConfigArgs {
+            sessions: SessionsDirectoryArgs {
+                profile: None,
+                sessions_directory: Some(sessions_directory.display().to_string()),
+            },
+            capture: CaptureSettingsArgs::default(),
+            project_name: "test-project".parse().expect("project name"),
+            template: None,
+            reaper_web_base_url: None,
+            video_device: vec![],
+            low_disk_warning: None,
+            low_disk_critical: None,
+            mirror_directory: None,
+        }
+    }
+
+    fn config_file(content: &str) -> tempfile::NamedTempFile {
+        let config_file = tempfile::NamedTempFile::new().expect("temp config file");
+        std::fs::write(config_file.path(), content).expect("writing config file");
+        config_file
+    }
+
+    #[test]
+    fn documented_example_parses() {
+        let config: ConfigFile = toml::from_str(
+            r#"
+            default-profile = "big-room"
+
+            [profiles.big-room]
+            sessions-directory = "/mnt/md0/manual-backup/reaper-sessions"
+            template = "~/reaper-templates/big-room.RPP"
+            reaper-web-base-url = "http://localhost:8080/"
+            video-device = ["/dev/video1", "/dev/video3"]
+
+            [profiles.big-room.capture]
+            resolution = "1920x1080"
+            format = "YUY2"
+            framerate = "25/1"
+            encoder = "x264"
+            bitrate = 20000
+            container = "mkv"
+            segment-duration = 600
+            segment-size = 4096
+            "#,
+        )
+        .expect("parsing");
+        let (name, profile) = config.profile(None).expect("profile").expect("default");
+        assert_eq!(name, "big-room");
+        assert_eq!(
+            profile
+                .video_device
+                .clone()
+                .map(VideoDevices::joined)
+                .as_deref(),
+            Some("/dev/video1,/dev/video3")
+        );
+        assert_eq!(
+            CaptureSettingsArgs::default()
+                .resolve_with(profile.capture.clone(), "documented example")
+                .expect("capture settings"),
+            CaptureSettings {
+                segment_seconds: Some(600),
+                segment_megabytes: Some(4096),
+                ..Default::default()
+            }
+        );
+        assert!(config.profile(Some("small-room")).is_err());
+        assert!(ConfigFile::default()
+            .profile(None)
+            .expect("no profile")
+            .is_none());
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(toml::from_str::<ConfigFile>("[profiles.a]\nvideo-devices = \"test\"").is_err());
+        assert!(toml::from_str::<ConfigFile>("default-profiles = \"a\"").is_err());
+    }
+
+    #[test]
+    fn only_an_explicit_config_file_has_to_exist() {
+        let directory = tempfile::tempdir().expect("temp directory");
+        let missing = directory.path().join(CONFIG_FILE_NAME);
+        assert!(ConfigFile::load(Some(missing)).is_err());
+        let (_, config) =
+            ConfigFile::load(Some(config_file("").path().to_owned())).expect("empty config");
+        assert!(config.profiles.is_empty());
+    }
+
+    #[test]
+    fn command_line_wins_over_the_profile() {
+        let config_file = config_file(
+            r#"
+            default-profile = "big-room"
+
+            [profiles.big-room]
+            template = "/from/profile.RPP"
+            reaper-web-base-url = "localhost:8080"
+            "#,
+        );
+        let sessions_directory = tempfile::tempdir().expect("sessions directory");
+        let error = MainConfigArgs {
+            template: Some("/from/command-line.RPP".to_owned()),
+            ..args(sessions_directory.path())
+        }
+        .resolve(Some(config_file.path().to_owned()))
+        .expect_err("nothing in this config exists");
+        let message = format!("{error:#}");
+        assert!(message.contains("profile 'big-room'"), "{message}");
+        assert!(message.contains("/from/command-line.RPP"), "{message}");
+        assert!(!message.contains("/from/profile.RPP"), "{message}");
+        assert!(
+            message.contains("did you mean 'http://localhost:8080/'"),
+            "{message}"
+        );
+        assert!(message.contains("bad video-device"), "{message}");
+    }
+
+    #[test]
+    fn sessions_directory_comes_from_the_profile() {
+        let sessions_directory = tempfile::tempdir().expect("sessions directory");
+        let config_file = config_file(&format!(
+            "[profiles.big-room]\nsessions-directory = \"{}\"",
+            sessions_directory.path().display()
+        ));
+        let resolved = SessionsDirectoryArgs {
+            profile: Some("big-room".to_owned()),
+            sessions_directory: None,
+        }
+        .resolve(Some(config_file.path().to_owned()))
+        .expect("resolving");
+        assert_eq!(resolved.as_ref().as_ref(), sessions_directory.path());
+        let error = SessionsDirectoryArgs {
+            profile: Some("big-room".to_owned()),
+            // can't be created below a file
+            sessions_directory: Some(format!("{}/sessions", config_file.path().display())),
+        }
+        .resolve(Some(config_file.path().to_owned()))
+        .expect_err("command line wins");
+        assert!(format!("{error:#}").contains("bad sessions-directory"));
+    }
+
+    #[test]
+    fn capture_settings_are_merged_field_by_field() {
+        let profile = CaptureProfile {
+            resolution: Some("1280x720".to_owned()),
+            framerate: Some("30".to_owned()),
+            ..Default::default()
+        };
+        let resolved = CaptureSettingsArgs {
+            framerate: Some("30000/1001".to_owned()),
+            ..CaptureSettingsArgs::default()
+        }
+        .resolve_with(profile.clone(), "test profile")
+        .expect("resolving");
+        assert_eq!(resolved.resolution.to_string(), "1280x720");
+        assert_eq!(resolved.framerate.to_string(), "30000/1001");
+        assert_eq!(resolved.encoder, CaptureSettings::default().encoder);
+
+        let message = format!(
+            "{:#}",
+            CaptureSettingsArgs {
+                encoder: Some("h264".to_owned()),
+                container: Some("avi".to_owned()),
+                ..CaptureSettingsArgs::default()
+            }
+            .resolve_with(profile, "test profile")
+            .expect_err("unknown encoder and container")
+        );
+        assert!(message.contains("bad encoder"), "{message}");
+        assert!(message.contains("bad container"), "{message}");
+    }
+
+    #[test]
+    fn segments_are_optional() {
+        let profile = CaptureProfile {
+            segment_duration: Some(600),
+            ..Default::default()
+        };
+        let resolved = CaptureSettingsArgs::default()
+            .resolve_with(profile.clone(), "test profile")
+            .expect("resolving");
+        assert_eq!(resolved.segment_seconds, Some(600));
+        assert_eq!(resolved.segment_megabytes, None);
+        assert!(!CaptureSettingsArgs::default()
+            .resolve_without_profile()
+            .expect("defaults")
+            .is_segmented());
+        let message = format!(
+            "{:#}",
+            CaptureSettingsArgs {
+                segment_size: Some("4GB".to_owned()),
+                ..CaptureSettingsArgs::default()
+            }
+            .resolve_with(profile, "test profile")
+            .expect_err("not a number")
+        );
+        assert!(message.contains("bad segment-size"), "{message}");
+    }
+
+    #[test]
+    fn a_single_video_device_is_still_accepted() {
+        let config: ConfigFile =
+            toml::from_str("[profiles.a]\nvideo-device = \"/dev/video1\"").expect("parsing");
+        assert_eq!(
+            config.profiles["a"]
+                .video_device
+                .clone()
+                .map(VideoDevices::joined)
+                .as_deref(),
+            Some("/dev/video1")
+        );
+        assert!(format!(
+            "{:#}",
+            parse_video_devices(" , ").expect_err("nothing listed")
+        )
+        .contains("no video device given"));
+    }
+
+    #[test]
+    fn every_bad_field_is_reported_once() {
+        let sessions_directory = tempfile::tempdir().expect("sessions directory");
+        let config_file = config_file("");
+        let error = MainConfigArgs {
+            capture: CaptureSettingsArgs {
+                resolution: Some("big".to_owned()),
+                framerate: Some("fast".to_owned()),
+                ..Default::default()
+            },
+            low_disk_warning: Some("lots".to_owned()),
+            low_disk_critical: Some("little".to_owned()),
+            ..args(sessions_directory.path())
+        }
+        .resolve(Some(config_file.path().to_owned()))
+        .expect_err("bad config");
+        let message = format!("{error:#}");
+        for field in [
+            "template",
+            "resolution",
+            "framerate",
+            "low-disk-warning",
+            "low-disk-critical",
+        ] {
+            assert!(
+                message.contains(&format!("bad {field}")),
+                "{field}: {message}"
+            );
+        }
+        assert!(!message.contains("-   -"), "{message}");
+        assert!(
+            message.lines().skip(1).all(|line| line.starts_with("  - ")),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn critical_threshold_must_be_below_warning() {
+        let sessions_directory = tempfile::tempdir().expect("sessions directory");
+        let config_file = config_file("");
+        let error = MainConfigArgs {
+            low_disk_warning: Some("10".to_owned()),
+            low_disk_critical: Some("20".to_owned()),
+            ..args(sessions_directory.path())
+        }
+        .resolve(Some(config_file.path().to_owned()))
+        .expect_err("thresholds swapped");
+        assert!(
+            format!("{error:#}").contains("must be below the warning one"),
+            "{error:#}"
+        );
+    }
+
+    #[test]
+    fn reaper_url_needs_a_trailing_slash() {
+        assert!(parse_reaper_web_base_url("http://localhost:8080/").is_ok());
+        assert!(parse_reaper_web_base_url("http://localhost:8080/reaper").is_err());
+        assert!(parse_reaper_web_base_url("ftp://localhost/").is_err());
+    }
+}
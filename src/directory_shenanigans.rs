@@ -7,6 +7,13 @@ pub fn home_dir() -> Result<PathBuf> {
         .map(|user| user.home_dir().to_owned())
 }
 
+/// per-user config directory, usually ~/.config/studio-barlog-ctl
+pub fn config_dir() -> Result<PathBuf> {
+    directories::ProjectDirs::from("", "", clap::crate_name!())
+        .ok_or_else(|| eyre!("no project dirs"))
+        .map(|dirs| dirs.config_dir().to_owned())
+}
+
 /// qpwgraph has a bug so the file must be persistent...
 pub fn temp_home_path(name: &str) -> Result<PathBuf> {
     home_dir().map(|parent| parent.join(name))
@@ -83,3 +90,14 @@ pub fn project_directory(
         .join(project_name.as_ref())
         .directory_exists()
 }
+
+pub fn project_file_path(
+    sessions_directory: SessionsDirectory,
+    project_name: &ProjectName,
+) -> Result<PathBuf> {
+    project_directory(sessions_directory, project_name).map(|project_directory| {
+        project_directory
+            .as_ref()
+            .join(format!("{project_name}.rpp"))
+    })
+}
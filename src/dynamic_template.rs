@@ -0,0 +1,734 @@
+use super::*;
+use crate::session_manifest::{RecordingRun, SessionManifest, TimelineItem};
+use reaper_save_rs::{
+    low_level::{Attribute, Entry, Line, Object, ReaperString},
+    prelude::{ObjectWrapper, ReaperProject, SerializeAndDeserialize, Track},
+};
+use std::{collections::BTreeMap, iter::once, path::Path};
+
+/// video item always starts here, the recording gets started at the matching
+/// position instead - see [crate::reaper::VideoSync]
+pub const VIDEO_ITEM_POSITION: f64 = 0.0;
+/// leaves some room between the previous take and the resumed one
+pub const RESUME_GAP_SECONDS: f64 = 5.0;
+/// well below a sample at 192kHz, smaller differences are just float formatting
+const POSITION_EPSILON: f64 = 1e-6;
+/// the video is still being recorded when its item gets added, the real length is
+/// written by [finish_project] once REAPER is closed
+const RECORDING_ITEM_LENGTH_SECONDS: f64 = 4.0 * 60.0 * 60.0;
+
+fn template_video_track() -> Result<Track> {
+    let template_video_track_code = r#"
+<TRACK {E2DDC8BD-1B29-165D-D141-7267E8F39ECD}
+  NAME "VIDEO 1"
+  PEAKCOL 16576
+  BEAT -1
+  AUTOMODE 0
+  VOLPAN 1 0 -1 -1 1
+  MUTESOLO 0 0 0
+  IPHASE 0
+  PLAYOFFS 0 1
+  ISBUS 0 0
+  BUSCOMP 0 0 0 0 0
+  SHOWINMIX 1 0.558065 0.5 1 0.5 0 0 0
+  SEL 0
+  REC 0 0 0 0 0 0 0 0
+  VU 2
+  TRACKHEIGHT 0 0 0 0 0 0
+  INQ 0 0 0 0.5 100 0 0 100
+  NCHAN 2
+  FX 1
+  TRACKID {E2DDC8BD-1B29-165D-D141-7267E8F39ECD}
+  PERF 0
+  MIDIOUT -1
+  MAINSEND 1 0
+  <ITEM
+    POSITION 0
+    SNAPOFFS 0
+    LENGTH 0
+    LOOP 0
+    ALLTAKES 0
+    FADEIN 1 0.01 0 1 0 0 0
+    FADEOUT 1 0.01 0 1 0 0 0
+    MUTE 0 0
+    SEL 1
+    IGUID {2F6AD700-840B-EFB6-D384-7F8316E1C1E7}
+    IID 21
+    NAME ""
+    VOLPAN 1 0 1 -1
+    SOFFS 0
+    PLAYRATE 1 0 0 -1 0 0.0025
+    CHANMODE 0
+    GUID {A365E92F-3BF8-24E8-1FF4-8FDF30208BCB}
+    <SOURCE VIDEO
+      FILE ""
+    >
+  >
+>
+    "#
+    .trim();
+    Object::deserialize(template_video_track_code, 0)
+        .wrap_err("deserializing template track")
+        .and_then(|(_, o)| Track::from_object(o).wrap_err("validating input"))
+}
+
+fn double_quote(val: &str) -> Attribute {
+    Attribute::String(ReaperString::DoubleQuote(val.to_owned()))
+}
+
+fn float(val: f64) -> Attribute {
+    Attribute::Float(val.into())
+}
+
+/// `<name ...>` blocks right inside `object`, like the ITEMs of a TRACK
+fn children<'a>(object: &'a Object, name: &'a str) -> impl Iterator<Item = &'a Object> + 'a {
+    object.values.iter().filter_map(move |entry| match entry {
+        Entry::Object(child) if child.header.attribute.as_ref() == name => Some(child),
+        _ => None,
+    })
+}
+
+fn children_mut<'a>(
+    object: &'a mut Object,
+    name: &'a str,
+) -> impl Iterator<Item = &'a mut Object> + 'a {
+    object
+        .values
+        .iter_mut()
+        .filter_map(move |entry| match entry {
+            Entry::Object(child) if child.header.attribute.as_ref() == name => Some(child),
+            _ => None,
+        })
+}
+
+/// `name ...` lines right inside `object`, like the MARKERs of a project
+fn lines_mut<'a>(object: &'a mut Object, name: &'a str) -> impl Iterator<Item = &'a mut Line> + 'a {
+    object
+        .values
+        .iter_mut()
+        .filter_map(move |entry| match entry {
+            Entry::Line(line) if line.attribute.as_ref() == name => Some(line),
+            _ => None,
+        })
+}
+
+/// value as written in the project without its quotes, numbers keep whatever type they were
+/// parsed as - `POSITION 0` is an integer
+fn attribute_text(attribute: &Attribute) -> Option<String> {
+    attribute
+        .serialize_inline()
+        .ok()
+        .map(|text| text.trim_matches(&['"', '\'', '`'][..]).to_owned())
+}
+
+fn number(object: &Object, name: &str) -> Option<f64> {
+    object
+        .single_attribute(name)
+        .ok()
+        .and_then(attribute_text)
+        .and_then(|text| text.parse().ok())
+}
+
+/// replaced only when it differs by more than float formatting, so that untouched projects
+/// serialize the way they were read
+fn set_number(object: &mut Object, name: &str, value: f64) -> Result<()> {
+    match number(object, name) {
+        Some(current) if (current - value).abs() <= POSITION_EPSILON => Ok(()),
+        _ => {
+            *object.single_attribute_mut(name)? = float(value);
+            Ok(())
+        }
+    }
+}
+
+pub fn append_video_to(
+    mut reaper_project: ReaperProject,
+    video_path: PathBuf,
+    offset: f64,
+) -> Result<ReaperProject> {
+    template_video_track()
+        .and_then(|mut track| -> Result<_> {
+            let file_path = double_quote(video_path.clone().display().to_string().as_str());
+            *track.as_mut().single_attribute_mut("NAME")? = file_path.clone();
+            let item = track
+                .as_mut()
+                .child_object_mut("ITEM")
+                .ok_or_else(|| eyre!("no ITEM in template"))?;
+            *item.single_attribute_mut("NAME")? = file_path.clone();
+            *item.single_attribute_mut("POSITION")? = float(offset);
+            *item.single_attribute_mut("LENGTH")? = float(RECORDING_ITEM_LENGTH_SECONDS);
+            let source = item
+                .child_object_mut("SOURCE")
+                .ok_or_else(|| eyre!("no SOURCE in ITEM"))?;
+            *source.single_attribute_mut("FILE")? = file_path;
+            Ok(track)
+        })
+        .wrap_err("creating video track")
+        .and_then(|video_track| {
+            reaper_project
+                .modify_tracks(|tracks| {
+                    tracks
+                        .into_iter()
+                        .chain(once(video_track.clone()))
+                        .collect()
+                })
+                .wrap_err("modifying tracks")
+        })
+        .map(move |_| reaper_project)
+}
+
+/// one track per camera, `videos` are paired with their item positions
+fn append_videos_to(
+    reaper_project: ReaperProject,
+    videos: Vec<(PathBuf, f64)>,
+) -> Result<ReaperProject> {
+    videos
+        .into_iter()
+        .try_fold(reaper_project, |project, (video_path, offset)| {
+            append_video_to(project, video_path, offset)
+        })
+}
+
+/// every item of every track
+fn items(project: &ReaperProject) -> impl Iterator<Item = &Object> {
+    children(project.as_ref(), "TRACK").flat_map(|track| children(track, "ITEM"))
+}
+
+/// end of the last item
+pub fn project_end_seconds(project: &ReaperProject) -> f64 {
+    items(project)
+        .filter_map(|item| Some(number(item, "POSITION")? + number(item, "LENGTH")?))
+        .fold(0.0, f64::max)
+}
+
+/// copied next to the project, an existing backup from the same second is the older one
+fn backup_project(project_path: &Path) -> Result<PathBuf> {
+    let backup_path = project_path.with_extension(format!(
+        "rpp.{}.bak",
+        crate::now().format("%Y-%m-%d--%H-%M-%S")
+    ));
+    match backup_path.exists() {
+        true => Ok(backup_path),
+        false => std::fs::copy(project_path, &backup_path)
+            .wrap_err_with(|| format!("backing up to {}", backup_path.display()))
+            .map(|_| backup_path),
+    }
+}
+
+/// original is kept next to the project, REAPER must not be running,
+/// `videos` gets the original and returns the video items to add
+fn rewrite_project<T>(
+    project_path: PathBuf,
+    videos: impl FnOnce(&ReaperProject) -> (T, Vec<(PathBuf, f64)>),
+) -> Result<(T, PathBuf)> {
+    backup_project(&project_path)
+        .and_then(|backup_path| {
+            std::fs::read_to_string(&project_path)
+                .wrap_err("reading original")
+                .and_then(|original| {
+                    ReaperProject::parse_from_str(&original).wrap_err("parsing original")
+                })
+                .map(|parsed| (backup_path, parsed))
+        })
+        .and_then(|(backup_path, parsed)| {
+            let (value, videos) = videos(&parsed);
+            append_videos_to(parsed, videos)
+                .and_then(|modified| modified.serialize_to_string().wrap_err("serializing"))
+                .and_then(|serialized| {
+                    std::fs::write(&project_path, serialized)
+                        .wrap_err_with(|| format!("writing to {}", project_path.display()))
+                })
+                .map(|_| (value, backup_path))
+        })
+}
+
+/// REAPER has no escaping and the name gets double quotes, so the ones in the note
+/// become single ones
+fn marker_name(note: &str) -> Attribute {
+    double_quote(&note.replace(&['\r', '\n'][..], " ").replace('"', "'"))
+}
+
+/// REAPER's web interface can't rename markers, so the notes go straight into the .rpp,
+/// `MARKER <id> <position> <name> <flags> ...` where regions have the lowest flag bit set
+pub fn name_markers(project: &mut ReaperProject, names: &BTreeMap<u32, String>) {
+    lines_mut(project.as_mut(), "MARKER").for_each(|marker| {
+        let value = |index: usize| {
+            marker
+                .values
+                .get(index)
+                .and_then(attribute_text)
+                .and_then(|text| text.parse::<u32>().ok())
+        };
+        let is_region = value(3).map(|flags| flags & 1 == 1).unwrap_or_default();
+        let name = value(0)
+            .and_then(|id| names.get(&id))
+            .filter(|_| !is_region);
+        if let (Some(name), Some(current)) = (name, marker.values.get_mut(2)) {
+            *current = marker_name(name);
+        }
+    })
+}
+
+/// `FILE` of the item's `<SOURCE`, relative to the project or absolute
+fn item_source(item: &Object) -> Option<PathBuf> {
+    children(item, "SOURCE")
+        .next()
+        .and_then(|source| source.single_attribute("FILE").ok())
+        .and_then(attribute_text)
+        .map(PathBuf::from)
+}
+
+/// the manifest knows absolute paths, REAPER might have made them relative to the project
+fn is_source(path: &Path, source: &Path) -> bool {
+    path == source || path.ends_with(source)
+}
+
+/// moves the video items to the positions the manifest ended up with (see
+/// [crate::reaper::VideoSync::corrected]) and trims them to what got recorded
+pub fn place_items(project: &mut ReaperProject, items: &[TimelineItem]) -> Result<()> {
+    children_mut(project.as_mut(), "TRACK")
+        .flat_map(|track| children_mut(track, "ITEM"))
+        .filter_map(|item| {
+            let source = item_source(item)?;
+            items
+                .iter()
+                .find(|timeline_item| is_source(&timeline_item.path, &source))
+                .map(|placement| (item, placement))
+        })
+        .try_for_each(|(item, placement)| {
+            set_number(item, "POSITION", placement.position_seconds)?;
+            match placement.length_seconds {
+                Some(length) => set_number(item, "LENGTH", length),
+                None => Ok(()),
+            }
+        })
+}
+
+/// sources of every item in the project
+fn item_sources(project: &ReaperProject) -> Vec<PathBuf> {
+    items(project).filter_map(item_source).collect()
+}
+
+/// copy of the previous segment's item pointed at `next`, REAPER hands out new ids and GUIDs
+/// for the ones left out, the position and length are up to [place_items]
+fn continued_item(item: &Object, next: &TimelineItem) -> Result<Object> {
+    let mut continued = item.clone();
+    continued.values.retain(|entry| match entry {
+        Entry::Line(line) => !["IGUID", "GUID", "IID"].contains(&line.attribute.as_ref()),
+        Entry::Object(_) => true,
+    });
+    let file = double_quote(&next.path.display().to_string());
+    *continued.single_attribute_mut("NAME")? = file.clone();
+    *continued
+        .child_object_mut("SOURCE")
+        .ok_or_else(|| eyre!("no SOURCE in ITEM"))?
+        .single_attribute_mut("FILE")? = file;
+    Ok(continued)
+}
+
+/// segments after the first one go on the same track, right after the one they follow
+pub fn continue_segments(project: &mut ReaperProject, items: &[TimelineItem]) -> Result<()> {
+    let mut present = item_sources(project);
+    children_mut(project.as_mut(), "TRACK").try_for_each(|track| {
+        track.values = std::mem::take(&mut track.values)
+            .into_iter()
+            .map(|entry| -> Result<Vec<Entry>> {
+                let mut previous = match &entry {
+                    Entry::Object(item) if item.header.attribute.as_ref() == "ITEM" => {
+                        item_source(item)
+                    }
+                    _ => None,
+                };
+                let mut entries = vec![];
+                while let Some(next) = previous.as_ref().and_then(|previous| {
+                    items.iter().find(|next| {
+                        !present.iter().any(|source| is_source(&next.path, source))
+                            && next
+                                .follows
+                                .as_ref()
+                                .map(|follows| is_source(follows, previous))
+                                .unwrap_or_default()
+                    })
+                }) {
+                    if let Entry::Object(item) = &entry {
+                        entries.push(Entry::Object(continued_item(item, next)?));
+                    }
+                    present.push(next.path.clone());
+                    previous = Some(next.path.clone());
+                }
+                Ok(once(entry).chain(entries).collect_vec())
+            })
+            .flatten_ok()
+            .collect::<Result<_>>()?;
+        Ok(())
+    })
+}
+
+/// everything that can only be done with REAPER closed: segments and recovered takes
+/// get their items, markers their names and every item its corrected position and real length,
+/// runs already finished are left alone so that later edits made in REAPER stick,
+/// returns the backup when the project had to be changed
+#[instrument(skip(manifest), ret, err)]
+pub fn finish_project(project_path: &Path, manifest: &SessionManifest) -> Result<Option<PathBuf>> {
+    let notes = crate::session_markers::project_notes(manifest)?;
+    let items = manifest
+        .runs
+        .iter()
+        .filter(|run| !run.project_finished)
+        .flat_map(RecordingRun::timeline_items)
+        .collect_vec();
+    let original = std::fs::read_to_string(project_path)
+        .wrap_err_with(|| format!("reading {}", project_path.display()))?;
+    let parse = || ReaperProject::parse_from_str(&original).wrap_err("parsing original");
+    let unchanged = parse()?.serialize_to_string().wrap_err("serializing")?;
+    let mut project = parse()?;
+    continue_segments(&mut project, &items)?;
+    let sources = item_sources(&project);
+    let missing = items
+        .iter()
+        .filter(|item| !sources.iter().any(|source| is_source(&item.path, source)))
+        .map(|item| (item.path.clone(), item.position_seconds))
+        .collect_vec();
+    let mut project = append_videos_to(project, missing)?;
+    name_markers(&mut project, &notes);
+    place_items(&mut project, &items)?;
+    let finished = project.serialize_to_string().wrap_err("serializing")?;
+    match finished == unchanged {
+        true => Ok(None),
+        false => backup_project(project_path)
+            .and_then(|backup_path| {
+                std::fs::write(project_path, finished)
+                    .wrap_err_with(|| format!("writing to {}", project_path.display()))
+                    .map(|_| Some(backup_path))
+            })
+            .wrap_err_with(|| format!("finishing {}", project_path.display())),
+    }
+}
+
+/// `videos` positions are relative to the start of the resumed take
+#[instrument(ret, err)]
+pub fn resume_with_video_tracks(
+    project_path: PathBuf,
+    videos: Vec<(PathBuf, f64)>,
+) -> Result<(f64, PathBuf)> {
+    rewrite_project(project_path.clone(), |original| {
+        let position = project_end_seconds(original) + RESUME_GAP_SECONDS;
+        (
+            position,
+            videos
+                .into_iter()
+                .map(|(video_path, offset)| (video_path, position + offset))
+                .collect(),
+        )
+    })
+    .wrap_err_with(|| format!("appending new take to {}", project_path.display()))
+}
+
+pub fn with_video_tracks(template_path: PathBuf, videos: Vec<(PathBuf, f64)>) -> Result<PathBuf> {
+    std::fs::read_to_string(template_path)
+        .wrap_err("reading original")
+        .and_then(|original| ReaperProject::parse_from_str(&original).wrap_err("parsing original"))
+        .and_then(|parsed| append_videos_to(parsed, videos))
+        .and_then(|modified| modified.serialize_to_string().wrap_err("serializing"))
+        .and_then(|serialized| {
+            directory_shenanigans::temp_home_path("generated-template.rpp").and_then(|path| {
+                std::fs::write(&path, serialized)
+                    .wrap_err_with(|| format!("writing to {}", path.display()))
+                    .map(|_| path)
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(position: f64, length: f64, file: &str) -> String {
+        [
+            "    <ITEM".to_owned(),
+            format!("      POSITION {position}"),
+            "      SNAPOFFS 0".to_owned(),
+            format!("      LENGTH {length}"),
+            "      IGUID {2F6AD700-840B-EFB6-D384-7F8316E1C1E7}".to_owned(),
+            format!("      NAME \"{file}\""),
+            "      <SOURCE VIDEO".to_owned(),
+            format!("        FILE \"{file}\""),
+            "      >".to_owned(),
+            "    >".to_owned(),
+        ]
+        .join("\n")
+    }
+
+    /// one track with `items`, the project's own POSITION is the edit cursor
+    fn project_text(markers: &[&str], items: &[String]) -> String {
+        once("<REAPER_PROJECT 0.1 \"6.80/linux-x86_64\" 1690000000".to_owned())
+            .chain(once("  POSITION 3".to_owned()))
+            .chain(markers.iter().map(|marker| format!("  {marker}")))
+            .chain(once(
+                "  <TRACK {11111111-2222-3333-4444-555555555555}".to_owned(),
+            ))
+            .chain(once("    NAME \"VIDEO 1\"".to_owned()))
+            .chain(items.iter().cloned())
+            .chain(["  >".to_owned(), ">".to_owned(), String::new()])
+            .join("\n")
+    }
+
+    fn project(markers: &[&str], items: &[String]) -> ReaperProject {
+        ReaperProject::parse_from_str(&project_text(markers, items)).expect("parsing project")
+    }
+
+    /// source, position and length of every item, track by track
+    fn placed(project: &ReaperProject) -> Vec<Vec<(PathBuf, f64, f64)>> {
+        children(project.as_ref(), "TRACK")
+            .map(|track| {
+                children(track, "ITEM")
+                    .map(|item| {
+                        (
+                            item_source(item).expect("item source"),
+                            number(item, "POSITION").expect("item position"),
+                            number(item, "LENGTH").expect("item length"),
+                        )
+                    })
+                    .collect_vec()
+            })
+            .filter(|items| !items.is_empty())
+            .collect()
+    }
+
+    fn marker_names(project: &mut ReaperProject) -> Vec<String> {
+        lines_mut(project.as_mut(), "MARKER")
+            .map(|marker| {
+                marker
+                    .values
+                    .get(2)
+                    .and_then(attribute_text)
+                    .expect("marker name")
+            })
+            .collect()
+    }
+
+    fn serialized(project: &ReaperProject) -> String {
+        project.serialize_to_string().expect("serializing")
+    }
+
+    #[test]
+    fn marker_names_lose_their_double_quotes_and_line_breaks() {
+        assert_eq!(
+            attribute_text(&marker_name("the \"good\"\ntake")).as_deref(),
+            Some("the 'good' take")
+        );
+    }
+
+    #[test]
+    fn markers_get_named_and_regions_are_left_alone() {
+        let mut project = project(
+            &[
+                "MARKER 1 12.5 \"\" 0 0 1 B {AAAAAAAA-0000-0000-0000-000000000000} 0",
+                "MARKER 2 20 old 0 0 1 B {BBBBBBBB-0000-0000-0000-000000000000} 0",
+                "MARKER 1 30 \"\" 1 0 1 B {CCCCCCCC-0000-0000-0000-000000000000} 0",
+                "MARKER 3 40 \"\" 0 0 1 B {DDDDDDDD-0000-0000-0000-000000000000} 0",
+            ],
+            &[],
+        );
+        let names = BTreeMap::from([
+            (1, "take it from here".to_owned()),
+            (2, "\"loud\"".to_owned()),
+        ]);
+        name_markers(&mut project, &names);
+        assert_eq!(
+            marker_names(&mut project),
+            ["take it from here", "'loud'", "", ""]
+        );
+        let named = serialized(&project);
+        name_markers(&mut project, &names);
+        assert_eq!(serialized(&project), named);
+    }
+
+    #[test]
+    fn project_ends_with_its_last_item() {
+        let two_items = project(&[], &[item(10.0, 5.0, "a.mkv"), item(2.0, 30.0, "b.mkv")]);
+        assert_eq!(project_end_seconds(&two_items), 32.0);
+        assert_eq!(project_end_seconds(&project(&[], &[])), 0.0);
+    }
+
+    #[test]
+    fn items_are_placed_by_their_source_file() {
+        let mut project = project(
+            &[],
+            &[
+                item(0.0, 14400.0, "/sessions/song/video-recordings/a.mkv"),
+                item(5.0, 60.0, "video-recordings/b.mkv"),
+                item(7.0, 3.0, "unrelated.wav"),
+            ],
+        );
+        let items = [
+            TimelineItem {
+                path: "/sessions/song/video-recordings/a.mkv".into(),
+                position_seconds: 0.25,
+                length_seconds: Some(42.5),
+                follows: None,
+            },
+            TimelineItem {
+                path: "/sessions/song/video-recordings/b.mkv".into(),
+                position_seconds: 5.0,
+                length_seconds: None,
+                follows: None,
+            },
+        ];
+        place_items(&mut project, &items).expect("placing items");
+        assert_eq!(
+            placed(&project),
+            [[
+                ("/sessions/song/video-recordings/a.mkv".into(), 0.25, 42.5),
+                ("video-recordings/b.mkv".into(), 5.0, 60.0),
+                ("unrelated.wav".into(), 7.0, 3.0),
+            ]]
+        );
+        let once_placed = serialized(&project);
+        place_items(&mut project, &items).expect("placing items again");
+        assert_eq!(serialized(&project), once_placed);
+    }
+
+    #[test]
+    fn resumed_takes_go_after_the_end_of_the_project() {
+        let directory = tempfile::tempdir().expect("temp dir");
+        let project_path = directory.path().join("song.rpp");
+        let original = project_text(&[], &[item(10.0, 5.0, "video-recordings/a.mkv")]);
+        std::fs::write(&project_path, &original).expect("writing project");
+        let (b, c) = (
+            directory.path().join("video-recordings/b.mkv"),
+            directory.path().join("video-recordings/c.mkv"),
+        );
+        let (position, backup_path) = resume_with_video_tracks(
+            project_path.clone(),
+            vec![(b.clone(), 0.0), (c.clone(), 0.5)],
+        )
+        .expect("resuming");
+        assert_eq!(position, 15.0 + RESUME_GAP_SECONDS);
+        assert_eq!(
+            std::fs::read_to_string(&backup_path).expect("backup"),
+            original
+        );
+        let resumed = ReaperProject::parse_from_str(
+            &std::fs::read_to_string(&project_path).expect("resumed project"),
+        )
+        .expect("parsing resumed project");
+        assert_eq!(
+            placed(&resumed),
+            [
+                vec![("video-recordings/a.mkv".into(), 10.0, 5.0)],
+                vec![(b, position, RECORDING_ITEM_LENGTH_SECONDS)],
+                vec![(c, position + 0.5, RECORDING_ITEM_LENGTH_SECONDS)],
+            ],
+            "one new track per camera"
+        );
+    }
+
+    #[test]
+    fn segments_continue_on_the_track_of_the_first_one() {
+        let mut project = project(&[], &[item(10.0, 14400.0, "/v/take-00000.mkv")]);
+        let segment = |index: usize, position_seconds: f64, length_seconds: f64| TimelineItem {
+            path: format!("/v/take-{index:05}.mkv").into(),
+            position_seconds,
+            length_seconds: Some(length_seconds),
+            follows: index
+                .checked_sub(1)
+                .map(|previous| format!("/v/take-{previous:05}.mkv").into()),
+        };
+        let items = [
+            segment(0, 10.0, 60.0),
+            segment(1, 70.0, 60.0),
+            segment(2, 130.0, 5.0),
+        ];
+        continue_segments(&mut project, &items).expect("continuing segments");
+        let continued = serialized(&project);
+        place_items(&mut project, &items).expect("placing items");
+        assert_eq!(
+            placed(&project),
+            [[
+                ("/v/take-00000.mkv".into(), 10.0, 60.0),
+                ("/v/take-00001.mkv".into(), 70.0, 60.0),
+                ("/v/take-00002.mkv".into(), 130.0, 5.0),
+            ]]
+        );
+        let track = children(project.as_ref(), "TRACK")
+            .next()
+            .expect("video track");
+        assert_eq!(
+            children(track, "ITEM")
+                .map(|item| item.single_attribute("IGUID").is_ok())
+                .collect_vec(),
+            [true, false, false],
+            "REAPER hands out new ids to the copies"
+        );
+        let mut project = ReaperProject::parse_from_str(&continued).expect("parsing continued");
+        continue_segments(&mut project, &items).expect("continuing again");
+        assert_eq!(serialized(&project), continued);
+    }
+
+    #[test]
+    fn finishing_adds_recovered_takes_names_markers_and_trims_items() {
+        use crate::session_manifest::{tests::*, CameraRun, RecoveredTake};
+        let directory = tempfile::tempdir().expect("temp dir");
+        let project_path = directory.path().join("song.rpp");
+        let markers_file = directory.path().join("take.markers.jsonl");
+        std::fs::write(
+            &markers_file,
+            format!(
+                "{}\n",
+                serde_json::json!({
+                    "time": after(30),
+                    "note": "chorus",
+                    "reaper_marker_id": 1,
+                    "reaper_position_seconds": 30.0,
+                    "video_file": "take.mkv",
+                    "video_position_seconds": 30.0,
+                })
+            ),
+        )
+        .expect("markers");
+        let original = project_text(
+            &["MARKER 1 30 \"\" 0 0 1 B {AAAAAAAA-0000-0000-0000-000000000000} 0"],
+            &[item(0.0, 14400.0, "take.mkv")],
+        );
+        std::fs::write(&project_path, &original).expect("project");
+        let manifest = SessionManifest {
+            project_name: "song".to_owned(),
+            runs: vec![RecordingRun {
+                markers_file: Some(markers_file),
+                cameras: vec![CameraRun {
+                    recoveries: vec![RecoveredTake {
+                        gap_started_at: after(60),
+                        gap_seconds: 2.0,
+                        video_file: "take---restart-01.mkv".into(),
+                        video_started_at: after(62),
+                        item_position_seconds: 62.0,
+                        placed_in_project: false,
+                    }],
+                    ..camera("take.mkv", started_at())
+                }],
+                ..run(Some(after(90)))
+            }],
+        };
+        let backup = finish_project(&project_path, &manifest)
+            .expect("finished")
+            .expect("project changed");
+        assert_eq!(std::fs::read_to_string(backup).expect("backup"), original);
+        let mut finished = ReaperProject::parse_from_str(
+            &std::fs::read_to_string(&project_path).expect("finished project"),
+        )
+        .expect("parsing finished project");
+        assert_eq!(marker_names(&mut finished), ["chorus"]);
+        assert_eq!(
+            placed(&finished),
+            [
+                vec![("take.mkv".into(), 0.0, 60.0)],
+                vec![("take---restart-01.mkv".into(), 62.0, 28.0)],
+            ]
+        );
+        assert_eq!(
+            finish_project(&project_path, &manifest).expect("finished"),
+            None
+        );
+    }
+}
@@ -8,35 +8,67 @@ pub struct Args {
     video_device: VideoDevice,
     #[arg(long, short)]
     output_path: PathBuf,
+    #[command(flatten)]
+    capture: config::CaptureSettingsArgs,
 }
 #[derive(Debug)]
 pub struct GStreamerReaderDumper {
     pub process: AbortOnDrop<()>,
     pub cancel: CancellationToken,
+    _messages_logger: AbortOnDrop<()>,
 }
 
 impl GStreamerReaderDumper {
     #[tracing::instrument]
-    pub fn new(
+    pub async fn new(
         Args {
             video_device,
             output_path,
+            capture,
         }: Args,
+        preview: PreviewSink,
     ) -> Result<Self> {
+        let settings = capture.resolve_without_profile()?.with_preview(preview);
+        video_capture::device_capabilities::check_supported(video_device.clone(), settings).await?;
         tracing::info!("spawning gstreamer process");
         let cancel = CancellationToken::new();
+        let (messages, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let messages_logger = tokio::task::spawn(async move {
+            while let Some(gstreamer_process::pipeline_messages::PipelineMessage {
+                kind,
+                text,
+                ..
+            }) = received.recv().await
+            {
+                info!(%kind, %text, "pipeline message");
+            }
+        })
+        .abort_on_drop();
         let process = {
             to_owned![cancel];
             tokio::task::spawn_blocking(move || {
-                match gstreamer_process::low_level::start_stream(video_device, output_path, cancel)
-                {
+                match gstreamer_process::low_level::start_stream(
+                    video_device,
+                    settings,
+                    output_path,
+                    cancel,
+                    None,
+                    None,
+                    Default::default(),
+                    Default::default(),
+                    messages,
+                ) {
                     Ok(_) => info!("process has finished"),
                     Err(message) => error!(?message, "bye bye"),
                 }
             })
             .abort_on_drop()
         };
-        Ok(Self { process, cancel })
+        Ok(Self {
+            process,
+            cancel,
+            _messages_logger: messages_logger,
+        })
     }
 
     #[tracing::instrument(ret, err, level = "INFO")]
@@ -56,3 +88,31 @@ impl GStreamerReaderDumper {
             .map_err(|v| v.wrap_err("waiting for gstreamer process to finish"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sources_get_dumped_until_cancelled() {
+        let directory = tempfile::tempdir().expect("temp dir");
+        let output_path = directory.path().join("dump.mkv");
+        let dumper = GStreamerReaderDumper::new(
+            Args {
+                video_device: VideoDevice::Test("ball".to_owned()),
+                output_path: output_path.clone(),
+                capture: Default::default(),
+            },
+            PreviewSink::Headless,
+        )
+        .await
+        .expect("starting the dumper");
+        tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+        dumper.cancel.cancel();
+        tokio::time::timeout(std::time::Duration::from_secs(10), dumper.wait_for_finish())
+            .await
+            .expect("finished in time")
+            .expect("finished cleanly");
+        assert!(output_path.metadata().expect("dump").len() > 0);
+    }
+}
@@ -33,14 +33,22 @@ use tui::{
 };
 use utils::*;
 
+pub mod capture_supervisor;
+pub mod config;
 pub mod directory_shenanigans;
+pub mod dynamic_template;
 pub mod gst_viewer_dumper;
 mod process;
 pub mod qpwgraph;
 pub mod reaper;
 pub mod rendering;
+pub mod session_manifest;
+pub mod session_markers;
+pub mod session_mirror;
+pub mod sessions;
 pub mod space_available_watcher;
 mod state;
+pub mod transport_controls;
 pub mod utils;
 pub mod video_capture;
 
@@ -48,6 +56,8 @@ pub mod video_capture;
 pub enum ProcessEvent {
     NewInput,
     ProcessExtied,
+    /// the capture pipeline reported an error and stopped recording
+    PipelineFailed,
 }
 
 type ProcessEventBus = tokio::sync::mpsc::UnboundedSender<ProcessEvent>;
@@ -99,30 +109,31 @@ fn setup_tracing(kind: TracingKind) -> Option<tracing_appender::non_blocking::Wo
 #[derive(Debug, Clone, derive_more::FromStr, derive_more::AsRef)]
 pub struct SessionsDirectory(ExistingDirectory);
 
-#[derive(Args)]
+/// fully resolved and validated, see [config::MainConfigArgs]
+#[derive(Debug)]
 pub struct MainConfig {
-    /// specify base directory for all sessions
-    #[arg(long, default_value = "/mnt/md0/manual-backup/reaper-sessions")]
     sessions_directory: SessionsDirectory,
-    /// Project name to create
-    #[arg(long)]
     project_name: ProjectName,
-    /// Template to be used
-    #[arg(long)]
     template: PathBuf,
-    #[arg(long)]
     reaper_web_base_url: reqwest::Url,
-    #[arg(long, value_parser = VideoDevice::new_checked)]
-    video_device: VideoDevice,
+    /// one capture pipeline per camera, never empty
+    video_devices: Vec<VideoDevice>,
+    capture_settings: CaptureSettings,
+    low_disk: space_available_watcher::LowDiskThresholds,
+    /// finished recordings get copied there during the session
+    mirror_directory: Option<PathBuf>,
 }
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(next_line_help = true)]
 struct Cli {
-    // /// Sets a custom config file
-    // #[arg(short, long, value_name = "FILE")]
-    // config: Option<PathBuf>,
+    /// Sets a custom config file [default: ~/.config/studio-barlog-ctl/config.toml]
+    #[arg(short, long, value_name = "FILE", env = "STUDIO_BARLOG_CONFIG")]
+    config: Option<PathBuf>,
+    /// previews go to a fakesink instead of a window, for machines without a display
+    #[arg(long, global = true, env = "STUDIO_BARLOG_HEADLESS_PREVIEW")]
+    headless_preview: bool,
 
     // /// Turn debugging information on
     // #[arg(short, long, action = clap::ArgAction::Count)]
@@ -133,10 +144,16 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    StartRecording(MainConfig),
+    StartRecording(config::MainConfigArgs),
+    /// list and inspect recorded sessions
+    Sessions(sessions::Args),
     ShowVideos,
+    /// formats, resolutions and framerates supported by the video devices
+    DeviceCapabilities(video_capture::device_capabilities::Args),
     QpwgraphOnly,
     GstViewerDumper(gst_viewer_dumper::Args),
+    /// fake REAPER web interface for rehearsals without REAPER
+    MockReaper(reaper::mock_reaper::Args),
 }
 
 type AppTerminal = Terminal<CrosstermBackend<Stdout>>;
@@ -178,8 +195,11 @@ async fn run_app_with_ui(
         project_name,
         template,
         reaper_web_base_url,
-        video_device,
+        video_devices,
         sessions_directory,
+        capture_settings,
+        low_disk,
+        mirror_directory,
     }: MainConfig,
 ) -> Result<()> {
     state::StudioState::new(
@@ -187,17 +207,29 @@ async fn run_app_with_ui(
         project_name,
         template,
         reaper_web_base_url,
-        video_device,
+        video_devices,
+        capture_settings,
+        low_disk,
+        mirror_directory,
     )
     .and_then(|state| {
-        enable_terminal_backend().and_then(|mut terminal| async move {
-            ready(run_app(&mut terminal, state).await)
-                .then(|app_result| {
-                    disable_terminal_backend(terminal)
-                        .map(move |term_result| app_result.and(term_result))
-                })
-                .await
-        })
+        let mirror_drained = state.mirror_drained();
+        enable_terminal_backend()
+            .and_then(|mut terminal| async move {
+                ready(run_app(&mut terminal, state).await)
+                    .then(|app_result| {
+                        disable_terminal_backend(terminal)
+                            .map(move |term_result| app_result.and(term_result))
+                    })
+                    .await
+            })
+            .then(|res| async move {
+                if let Some(mirror_drained) = mirror_drained {
+                    eprintln!("waiting for the mirror to copy the last recordings");
+                    mirror_drained.cancelled().await;
+                }
+                res
+            })
     })
     .then(|res| tokio::time::sleep(tokio::time::Duration::from_millis(100)).map(|_| res))
     .await
@@ -217,56 +249,95 @@ async fn wait_for_accept(text: String) -> Result<()> {
     .and_then(|v| v)
 }
 
+/// the preview closes when the returned window is dropped
 #[instrument]
 async fn present_video_device(
     video_device: VideoDevice,
-) -> Result<(GracefullyShutdownChild, VideoDevice)> {
+    settings: Option<CaptureSettings>,
+    sink: PreviewSink,
+) -> Result<PreviewWindow> {
     info!("presenting video device");
-    video_capture::ffplay_preview(video_device.clone())
-        .and_then(|ffplay| ffplay.gracefully_shutdown_on_drop())
-        .await
-        .map(|child| (child, video_device))
+    video_capture::preview_window::open_preview(video_device, settings, sink).await
 }
 
 async fn app_main() -> Result<()> {
-    let cli = Cli::parse();
-    let _guard = setup_tracing(match cli.command {
+    let Cli {
+        config,
+        headless_preview,
+        command,
+    } = Cli::parse();
+    let preview_sink = PreviewSink::new(headless_preview);
+    let _guard = setup_tracing(match command {
         Commands::StartRecording(_) => TracingKind::FileBased,
         _ => TracingKind::TerminalBased,
     });
-    match cli.command {
+    match command {
         Commands::ShowVideos => {
-            let devices = video_capture::list_devices().await?;
-            let _children = ready(devices)
+            let devices = video_capture::list_capture_devices().await?;
+            let _previews = ready(devices)
                 .then(|videos| {
                     futures::future::join_all(videos.iter().cloned().map(
                         |detailed_video_device| async move {
                             tracing::info!(?detailed_video_device);
-                            present_video_device(detailed_video_device.video_device.clone())
-                                .await
-                                .wrap_err_with(move || {
-                                    format!("spawning ffplay for {detailed_video_device:?}")
-                                })
+                            present_video_device(
+                                detailed_video_device.video_device.clone(),
+                                None,
+                                preview_sink,
+                            )
+                            .await
+                            .wrap_err_with(move || format!("previewing {detailed_video_device:?}"))
                         },
                     ))
                     .map(|v| v.into_iter().filter_map(|v| v.ok()).collect_vec())
-                    .then(move |ready| {
-                        let (children, devices): (Vec<_>, Vec<_>) = ready.into_iter().unzip();
+                    .then(move |previews| {
                         wait_for_accept(format!(
                             "available devices: {:?}",
-                            devices.into_iter().collect_vec()
+                            previews
+                                .iter()
+                                .map(|preview| preview.video_device.clone())
+                                .collect_vec()
                         ))
-                        .map_ok(move |_| children)
+                        .map_ok(move |_| previews)
                     })
                 })
                 .await?;
             Ok(())
         }
-        Commands::StartRecording(config) => {
+        Commands::StartRecording(args) => {
+            let config = args.resolve(config).map(|config| MainConfig {
+                capture_settings: config.capture_settings.with_preview(preview_sink),
+                ..config
+            })?;
+            futures::future::join_all(config.video_devices.iter().cloned().map(|video_device| {
+                video_capture::device_capabilities::check_supported(
+                    video_device,
+                    config.capture_settings,
+                )
+            }))
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
             {
-                let video_device = config.video_device.clone();
-                let (_child, video_device) = present_video_device(video_device.clone()).await?;
-                wait_for_accept(format!("config video device {video_device}")).await?;
+                let previews = futures::future::join_all(config.video_devices.iter().cloned().map(
+                    |video_device| {
+                        present_video_device(
+                            video_device,
+                            Some(config.capture_settings),
+                            preview_sink,
+                        )
+                    },
+                ))
+                .await
+                .into_iter()
+                .collect::<Result<Vec<_>>>()?;
+                wait_for_accept(format!(
+                    "config video devices {}",
+                    previews
+                        .iter()
+                        .map(|preview| &preview.video_device)
+                        .join(", ")
+                ))
+                .await?;
             }
             info!("chosen device, starting app");
 
@@ -279,10 +350,13 @@ async fn app_main() -> Result<()> {
             Ok(())
         }
         Commands::GstViewerDumper(args) => {
-            let viewer = gst_viewer_dumper::GStreamerReaderDumper::new(args)?;
+            let viewer = gst_viewer_dumper::GStreamerReaderDumper::new(args, preview_sink).await?;
             viewer.wait_for_finish().await?;
             Ok(())
         }
+        Commands::Sessions(args) => sessions::run(args, config).await,
+        Commands::DeviceCapabilities(args) => video_capture::device_capabilities::run(args).await,
+        Commands::MockReaper(args) => reaper::mock_reaper::MockReaper::run(args).await,
     }
 }
 
@@ -318,6 +392,7 @@ async fn next_event() -> Result<crossterm::event::Event> {
 pub enum AppEvent {
     Terminal(crossterm::event::Event),
     StateUpdated,
+    PipelineFailed,
 }
 
 async fn run_app<B: Backend>(
@@ -331,11 +406,16 @@ async fn run_app<B: Backend>(
         .wake_up
         .take()
         .ok_or_else(|| eyre!("notifier not initialized - programming error"))?
-        .map(|_| Result::<AppEvent, eyre::Report>::Ok(AppEvent::StateUpdated))
+        .map(|event| {
+            Result::<AppEvent, eyre::Report>::Ok(match event {
+                ProcessEvent::PipelineFailed => AppEvent::PipelineFailed,
+                ProcessEvent::NewInput | ProcessEvent::ProcessExtied => AppEvent::StateUpdated,
+            })
+        })
         .boxed();
     let mut app_events = futures::stream::select_all([term_events, wake_up]);
 
-    let mut redraw = || {
+    let redraw = |terminal: &mut Terminal<B>, state: &mut state::StudioState| {
         debug!("redrawing");
         terminal
             .draw(|f| {
@@ -343,7 +423,7 @@ async fn run_app<B: Backend>(
             })
             .ok();
     };
-    redraw();
+    redraw(terminal, &mut state);
     while let Some(ev) = app_events.next().await {
         if let Ok(ev) = ev {
             trace!(?ev, "new event");
@@ -352,22 +432,30 @@ async fn run_app<B: Backend>(
             match ev {
                 AppEvent::Terminal(event) => match event {
                     Event::Key(key) => match key.code {
-                        KeyCode::Char('q')
+                        KeyCode::Char('q' | 'Q')
                             if key
                                 .modifiers
-                                .contains(KeyModifiers::ALT & KeyModifiers::SHIFT) =>
+                                .contains(KeyModifiers::ALT | KeyModifiers::SHIFT) =>
                         {
                             return Ok(())
                         }
-                        _ => {}
+                        _ => {
+                            if state.handle_key(key) {
+                                redraw(terminal, &mut state);
+                            }
+                        }
                     },
-                    Event::FocusGained => redraw(),
-                    Event::Resize(_, _) => redraw(),
+                    Event::FocusGained => redraw(terminal, &mut state),
+                    Event::Resize(_, _) => redraw(terminal, &mut state),
                     Event::FocusLost => {}
                     Event::Mouse(_) => {}
                     Event::Paste(_) => {}
                 },
-                AppEvent::StateUpdated => redraw(),
+                AppEvent::StateUpdated => redraw(terminal, &mut state),
+                AppEvent::PipelineFailed => {
+                    state.pipeline_failed();
+                    redraw(terminal, &mut state)
+                }
             }
         }
     }
@@ -82,6 +82,7 @@ pub struct ProcessWatcher {
     pub stderr: Option<StdioWatcher>,
     pub status: Arc<RwLock<Option<String>>>,
     pub watcher: AbortOnDrop<()>,
+    pid: Option<u32>,
 }
 
 #[derive(Debug)]
@@ -93,6 +94,7 @@ pub enum ProcessStatus {
 impl ProcessWatcher {
     pub fn new(name: String, mut child: GracefullyShutdownChild, notify: ProcessEventBus) -> Self {
         let status = Arc::new(RwLock::new(None));
+        let pid = child.as_mut().id();
         let stdout = child
             .as_mut()
             .stdout
@@ -124,6 +126,17 @@ impl ProcessWatcher {
             stdout,
             stderr,
             watcher,
+            pid,
+        }
+    }
+
+    /// same signal the child gets when dropped, but the exit still ends up in `status`
+    pub fn terminate(&self) {
+        if let Some(pid) = self.pid.and_then(|pid| TryInto::<i32>::try_into(pid).ok()) {
+            let pid = nix::unistd::Pid::from_raw(pid);
+            if let Err(errno) = nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGTERM) {
+                tracing::warn!(?errno, name = %self.name, "terminating the child process failed")
+            }
         }
     }
 
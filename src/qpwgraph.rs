@@ -38,6 +38,12 @@ impl QpwgraphInstance {
     }
 }
 
+impl QpwgraphInstance {
+    pub fn exit_status(&self) -> crate::session_manifest::ChildExitStatus {
+        self.process.read().status.clone()
+    }
+}
+
 impl RenderToTerm for QpwgraphInstance {
     fn render_to_term<B: Backend>(
         &mut self,
@@ -1,7 +1,12 @@
-use self::reaper_web_client::rea_request::{Playstate, TransportResponse};
+use self::reaper_web_client::rea_request::{
+    AllTracks, Playstate, TrackResponse, Transport, TransportResponse,
+};
 
 use super::*;
-use crate::directory_shenanigans::project_directory;
+use crate::{
+    directory_shenanigans::project_file_path,
+    video_capture::gstreamer_process::low_level::PipelineClock,
+};
 pub mod common_types {
     #[derive(Debug, Clone, Copy, PartialEq, Eq, strum::FromRepr, strum::Display)]
     pub enum ReaperBool {
@@ -14,21 +19,101 @@ use futures::TryFutureExt;
 use itertools::Itertools;
 use reqwest::Url;
 use std::{future::ready, sync::Arc};
+use tokio_util::sync::CancellationToken;
 use tui::{
     layout::Rect,
-    style::{Color, Style},
+    style::{Color, Modifier, Style},
     text::{Span, Spans, Text},
-    widgets::{Paragraph, Wrap},
+    widgets::{List, ListItem, Paragraph, Wrap},
 };
 
+/// everything polled from the web interface on each tick
+#[derive(Debug, Clone)]
+pub struct ReaperStatus {
+    pub transport: TransportResponse,
+    pub tracks: Vec<TrackResponse>,
+}
+
+/// where the video item sits in the project and when the video started
+#[derive(Debug, Clone)]
+pub struct VideoSync {
+    pub video_started_at: ProjectTime,
+    pub item_position: f64,
+    /// clock of the reference camera's pipeline, the wall clock stands in when it's missing
+    pub clock: Option<PipelineClock>,
+}
+
+impl VideoSync {
+    /// timeline position of the video frame captured at `time`
+    pub fn video_position_at(&self, time: ProjectTime) -> f64 {
+        self.item_position
+            + (time - self.video_started_at)
+                .num_microseconds()
+                .unwrap_or_default() as f64
+                / 1_000_000.0
+    }
+
+    /// running time of the reference camera, the timestamp of the frame captured right now
+    pub fn running_time_seconds(&self) -> f64 {
+        match &self.clock {
+            Some(clock) => clock.running_time_seconds(),
+            None => self.video_position_at(crate::now()) - self.item_position,
+        }
+    }
+
+    /// REAPER reported `position_seconds` while the reference camera was at `running_time_seconds`
+    pub fn recording_start(
+        &self,
+        position_seconds: f64,
+        running_time_seconds: f64,
+    ) -> RecordingStart {
+        RecordingStart {
+            time: crate::now(),
+            position_seconds,
+            video_running_time_seconds: running_time_seconds,
+            sync_error_seconds: position_seconds - (self.item_position + running_time_seconds),
+        }
+    }
+
+    /// item moved so that the frame captured when recording started sits at the recording position
+    pub fn corrected(self, recording_start: &RecordingStart) -> Self {
+        Self {
+            item_position: recording_start.position_seconds
+                - recording_start.video_running_time_seconds,
+            ..self
+        }
+    }
+}
+
+/// captured right after the record button got pressed
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct RecordingStart {
+    pub time: ProjectTime,
+    pub position_seconds: f64,
+    /// running time of the reference camera's pipeline at `position_seconds`
+    #[serde(default)]
+    pub video_running_time_seconds: f64,
+    /// positive when the audio landed later on the timeline than the video
+    pub sync_error_seconds: f64,
+}
+
+/// anything above a frame at 25fps is worth a warning
+const SYNC_ERROR_TOLERANCE_SECONDS: f64 = 0.04;
+/// has to fit in the cleanup deadline, the project gets finished on the next resume otherwise
+const EXIT_DEADLINE: tokio::time::Duration = tokio::time::Duration::from_secs(3);
+
 #[derive(Debug, Clone)]
 pub struct ReaperInstance {
+    pub recording_start: RecordingStart,
     process: Arc<RwLock<ProcessWatcher>>,
-    state: Arc<RwLock<Result<reaper_web_client::rea_request::TransportResponse>>>,
-    _web_client: Arc<reaper_web_client::ReaperWebClient>,
+    state: Arc<RwLock<Result<ReaperStatus>>>,
+    web_client: Arc<reaper_web_client::ReaperWebClient>,
+    /// cancelled once REAPER saved the project and exited
+    closed: CancellationToken,
     _state_watcher: Arc<AbortOnDrop<()>>,
 }
 
+pub mod mock_reaper;
 pub mod reaper_web_client;
 
 impl ReaperInstance {
@@ -39,12 +124,10 @@ impl ReaperInstance {
         template: PathBuf,
         notify: ProcessEventBus,
         web_client_base_address: reqwest::Url,
+        video_sync: VideoSync,
     ) -> Result<Self> {
         let process_path = "reaper";
-        let project_directory = project_directory(sessions_directory, &project_name)?;
-        let project_file_path = project_directory
-            .as_ref()
-            .join(format!("{project_name}.rpp"));
+        let project_file_path = project_file_path(sessions_directory, &project_name)?;
 
         let (already_exists, command) = {
             let mut base = bounded_command(process_path);
@@ -78,13 +161,17 @@ impl ReaperInstance {
                             to_owned![web_client, state, notify];
                             tokio::task::spawn(async move {
                                 let mut tick = crate::process::app_interval(
-                                    tokio::time::Duration::from_secs(1),
+                                    tokio::time::Duration::from_millis(250),
                                 );
                                 loop {
                                     tick.tick().await;
                                     *state.write() = web_client
                                         .clone()
-                                        .run_single(reaper_web_client::rea_request::Transport)
+                                        .run_batch((Transport, AllTracks))
+                                        .map_ok(|(transport, tracks)| ReaperStatus {
+                                            transport,
+                                            tracks,
+                                        })
                                         .await;
                                     if let Err(message) = notify.send(ProcessEvent::NewInput) {
                                         tracing::warn!(?message, "web client new data");
@@ -94,14 +181,37 @@ impl ReaperInstance {
                         }
                         .abort_on_drop();
 
-                        if !already_exists {
-                            web_client.clone().start_reaper_recording().await?;
+                        // resumed projects get the new take appended at the end, so it's
+                        // safe to record right away as well
+                        let sent_at = video_sync.running_time_seconds();
+                        let transport = web_client
+                            .clone()
+                            .start_reaper_recording_at(video_sync.item_position + sent_at)
+                            .await?;
+                        // REAPER read its position somewhere within the round trip
+                        let recording_start = video_sync.recording_start(
+                            transport.position_seconds,
+                            (sent_at + video_sync.running_time_seconds()) / 2.0,
+                        );
+                        match recording_start.sync_error_seconds.abs()
+                            > SYNC_ERROR_TOLERANCE_SECONDS
+                        {
+                            true => {
+                                tracing::warn!(?recording_start, "audio and video out of sync")
+                            }
+                            false => tracing::info!(
+                                ?recording_start,
+                                already_exists,
+                                "recording started"
+                            ),
                         }
 
                         Ok(Self {
+                            recording_start,
                             process: child,
-                            _web_client: web_client,
+                            web_client,
                             state,
+                            closed: CancellationToken::new(),
                             _state_watcher: Arc::new(state_watcher),
                         })
                     },
@@ -111,6 +221,35 @@ impl ReaperInstance {
     }
 }
 
+impl ReaperInstance {
+    pub fn web_client(&self) -> Arc<reaper_web_client::ReaperWebClient> {
+        self.web_client.clone()
+    }
+
+    pub fn exit_status(&self) -> crate::session_manifest::ChildExitStatus {
+        self.process.read().status.clone()
+    }
+
+    /// the .rpp is safe to edit once this is cancelled
+    pub fn closed(&self) -> CancellationToken {
+        self.closed.clone()
+    }
+
+    /// shared with the poller, refreshed on every tick
+    pub fn status(&self) -> Arc<RwLock<Result<ReaperStatus>>> {
+        self.state.clone()
+    }
+
+    /// last known playstate, None when reaper isn't responding
+    pub fn playstate(&self) -> Option<Playstate> {
+        self.state
+            .read()
+            .as_ref()
+            .ok()
+            .map(|status| status.transport.playstate)
+    }
+}
+
 impl RenderToTerm for TransportResponse {
     fn render_to_term<B: Backend>(
         &mut self,
@@ -124,11 +263,18 @@ impl RenderToTerm for TransportResponse {
             Playstate::Recording => Color::LightRed,
             Playstate::RecordPaused => Color::DarkGray,
         };
-        let lines = format!("{self:#?}",)
-            .lines()
-            .map(ToOwned::to_owned)
-            .map(|line| Spans::from(Span::styled(line, Style::default().fg(color))))
-            .collect_vec();
+        let lines = [
+            format!("{:?}", self.playstate),
+            format!(
+                "{} ({})",
+                self.position_string.trim(),
+                self.position_string_beats.trim()
+            ),
+            format!("repeat: {}", self.is_repeat_on),
+        ]
+        .into_iter()
+        .map(|line| Spans::from(Span::styled(line, Style::default().fg(color))))
+        .collect_vec();
         f.render_widget(
             Paragraph::new(lines)
                 .wrap(Wrap { trim: false })
@@ -139,6 +285,97 @@ impl RenderToTerm for TransportResponse {
     }
 }
 
+/// meters show the range from here up to 0dB
+const METER_FLOOR_DB: f64 = -60.0;
+const TRACK_NAME_WIDTH: usize = 16;
+
+fn track_meter(track: &TrackResponse, width: usize) -> Spans<'static> {
+    let peak = track.last_meter_peak_db();
+    let clipping = peak >= 0.0;
+    let color = match peak {
+        _ if clipping => Color::Red,
+        peak if peak >= -6.0 => Color::Yellow,
+        _ => Color::Green,
+    };
+    let filled = (((peak - METER_FLOOR_DB) / -METER_FLOOR_DB).clamp(0.0, 1.0) * width as f64)
+        .round() as usize;
+    let armed = match (track.is_master(), track.flags.is_record_armed()) {
+        (true, _) => Span::raw("   "),
+        (false, true) => Span::styled(
+            " ● ",
+            Style::default()
+                .fg(Color::LightRed)
+                .add_modifier(Modifier::BOLD),
+        ),
+        (false, false) => Span::styled(" ○ ", Style::default().fg(Color::DarkGray)),
+    };
+    let name_style = match clipping {
+        true => Style::default()
+            .fg(Color::White)
+            .bg(Color::Red)
+            .add_modifier(Modifier::BOLD),
+        false => Style::default(),
+    };
+    Spans::from(vec![
+        armed,
+        Span::styled(
+            format!(
+                "{:<TRACK_NAME_WIDTH$.TRACK_NAME_WIDTH$} ",
+                match track.is_master() {
+                    true => "MASTER",
+                    false => track.name.as_str(),
+                }
+            ),
+            name_style,
+        ),
+        Span::styled("█".repeat(filled), Style::default().fg(color)),
+        Span::styled(
+            "░".repeat(width.saturating_sub(filled)),
+            Style::default().fg(Color::DarkGray),
+        ),
+        Span::styled(format!(" {peak:>6.1} dB"), Style::default().fg(color)),
+    ])
+}
+
+impl RenderToTerm for ReaperStatus {
+    fn render_to_term<B: Backend>(
+        &mut self,
+        f: &mut Frame<B>,
+        rect: tui::layout::Rect,
+    ) -> Result<()> {
+        let [transport, tracks]: [Rect; 2] = layout!(Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(5), Constraint::Min(0)])
+            .split(rect));
+        self.transport.render_to_term(f, transport)?;
+
+        let armed = self
+            .tracks
+            .iter()
+            .filter(|track| track.flags.is_record_armed())
+            .count();
+        let title = match (self.transport.playstate, armed) {
+            (Playstate::Recording, 0) => Span::styled(
+                "Tracks (nothing armed!)",
+                Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+            ),
+            (_, armed) => Span::raw(format!("Tracks ({armed} armed)")),
+        };
+        // borders, arm marker, name and the dB readout
+        let meter_width = (tracks.width as usize).saturating_sub(2 + 3 + TRACK_NAME_WIDTH + 1 + 10);
+        let items = self
+            .tracks
+            .iter()
+            .map(|track| ListItem::new(track_meter(track, meter_width)))
+            .collect_vec();
+        f.render_widget(
+            List::new(items).block(Block::default().borders(Borders::ALL).title(title)),
+            tracks,
+        );
+        Ok(())
+    }
+}
+
 impl<T: RenderToTerm> RenderToTerm for Option<T> {
     fn render_to_term<B: Backend>(
         &mut self,
@@ -193,7 +430,7 @@ impl RenderToTerm for ReaperInstance {
     ) -> Result<()> {
         let [state, logs]: [Rect; 2] = layout!(Layout::default()
             .direction(Direction::Vertical)
-            .constraints([Constraint::Ratio(1, 3), Constraint::Ratio(2, 3)])
+            .constraints([Constraint::Ratio(2, 3), Constraint::Ratio(1, 3)])
             .split(rect));
         self.state.write().render_to_term(f, state)?;
         self.process.write().render_to_term(f, logs)?;
@@ -205,16 +442,116 @@ impl RenderToTerm for ReaperInstance {
 impl Drop for ReaperInstance {
     fn drop(&mut self) {
         use reaper_web_client::rea_request::ActionId;
-        let web_client = self._web_client.clone();
+        let web_client = self.web_client.clone();
         let process = self.process.clone();
+        let closed = self.closed.clone();
         tokio::task::spawn(async move {
-            let _process = process;
-            for action in [ActionId::TransportStop, ActionId::SaveProject] {
-                tracing::info!(?action, "cleaning up");
-                if let Err(message) = web_client.clone().run_single(action).await {
-                    tracing::error!(?message);
+            tracing::info!("cleaning up");
+            if let Err(message) = web_client
+                .run_batch((ActionId::TransportStop, ActionId::SaveProject))
+                .await
+            {
+                tracing::error!(?message);
+            }
+            let status = {
+                let process = process.read();
+                process.terminate();
+                process.status.clone()
+            };
+            let exited = tokio::time::timeout(EXIT_DEADLINE, async {
+                while status.read().is_none() {
+                    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
                 }
+            })
+            .await;
+            match exited {
+                Ok(()) => closed.cancel(),
+                Err(_) => tracing::warn!("REAPER did not exit in time"),
             }
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::reaper_web_client::rea_request::ReaResponse;
+    use super::*;
+
+    fn track(number: usize, flags: u32, peak: i32) -> TrackResponse {
+        TrackResponse::from_response(&format!(
+            "TRACK\t{number}\tGuitar\t{flags}\t1.000000\t0.000000\t{peak}\t{peak}\t1.000000\t3\t0\t0\t1\t0"
+        ))
+        .expect("track line")
+    }
+
+    fn text(spans: &Spans) -> Vec<String> {
+        spans
+            .0
+            .iter()
+            .map(|span| span.content.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn meters_fill_up_towards_zero_db() {
+        let meter = track_meter(&track(1, 64, -300), 10);
+        assert_eq!(
+            text(&meter),
+            [" ● ", "Guitar           ", "█████", "░░░░░", "  -30.0 dB"]
+        );
+        assert_eq!(meter.0[2].style.fg, Some(Color::Green));
+        assert_eq!(text(&track_meter(&track(1, 0, -1500), 10))[2], "");
+        assert_eq!(text(&track_meter(&track(1, 0, -40), 10))[0], " ○ ");
+    }
+
+    #[test]
+    fn clipping_tracks_light_up() {
+        let meter = track_meter(&track(2, 64, 15), 10);
+        assert_eq!(meter.0[1].style.bg, Some(Color::Red));
+        assert_eq!(text(&meter)[2], "█".repeat(10));
+        assert_eq!(text(&meter)[3], "");
+    }
+
+    #[test]
+    fn video_position_follows_the_wall_clock() {
+        let video_started_at = crate::now();
+        let sync = VideoSync {
+            video_started_at,
+            item_position: 2.0,
+            clock: None,
+        };
+        assert_eq!(sync.video_position_at(video_started_at), 2.0);
+        assert!(
+            (sync.video_position_at(video_started_at + chrono::Duration::milliseconds(12_345))
+                - 14.345)
+                .abs()
+                < 1e-9
+        );
+    }
+
+    #[test]
+    fn master_has_no_arm_marker() {
+        let meter = track_meter(&track(0, 64, -1500), 4);
+        assert_eq!(text(&meter)[..2], ["   ", "MASTER           "]);
+    }
+
+    #[test]
+    fn correction_lines_the_first_frame_up_with_the_audio() {
+        let video_sync = VideoSync {
+            video_started_at: crate::now(),
+            item_position: 10.0,
+            clock: None,
+        };
+        // audio landed 150ms later than the video item says
+        let recording_start = video_sync.recording_start(12.15, 2.0);
+        assert!((recording_start.sync_error_seconds - 0.15).abs() < 1e-9);
+        let corrected = video_sync.corrected(&recording_start);
+        assert!((corrected.item_position - 10.15).abs() < 1e-9);
+        assert!(
+            (corrected.item_position + recording_start.video_running_time_seconds
+                - recording_start.position_seconds)
+                .abs()
+                < 1e-9
+        );
+    }
+}
@@ -0,0 +1,541 @@
+use super::reaper_web_client::rea_request::{ActionId, Playstate, TrackFlags};
+use super::*;
+use crate::reaper::common_types::ReaperBool;
+use std::{collections::BTreeMap, net::SocketAddr, str::FromStr, time::Instant};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+};
+
+/// tempo of the fake project, 4/4
+const BPM: f64 = 120.0;
+
+/// pretends to be REAPER's web interface, good enough for rehearsals without a real REAPER
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    bind: SocketAddr,
+}
+
+#[derive(Debug, Clone)]
+pub struct MockTrack {
+    pub name: String,
+    pub flags: TrackFlags,
+}
+
+#[derive(Debug, Clone)]
+pub struct MockReaperState {
+    pub playstate: Playstate,
+    /// edit cursor, playback starts from here
+    pub cursor_seconds: f64,
+    pub is_repeat_on: ReaperBool,
+    pub save_count: usize,
+    /// master track not included
+    pub tracks: Vec<MockTrack>,
+    pub markers: Vec<(String, f64)>,
+    pub regions: Vec<(String, f64, f64)>,
+    /// (section, key) -> value, still url-encoded
+    pub project_ext_state: BTreeMap<(String, String), String>,
+    /// every command received, in order
+    pub history: Vec<String>,
+    rolling_since: Option<Instant>,
+}
+
+impl Default for MockReaperState {
+    fn default() -> Self {
+        Self {
+            playstate: Playstate::Stopped,
+            cursor_seconds: 0.0,
+            is_repeat_on: ReaperBool::False,
+            save_count: 0,
+            tracks: (1..=4)
+                .map(|idx| MockTrack {
+                    name: format!("Track {idx}"),
+                    flags: TrackFlags(TrackFlags::RECORD_ARMED),
+                })
+                .collect(),
+            markers: vec![],
+            regions: vec![],
+            project_ext_state: Default::default(),
+            history: vec![],
+            rolling_since: None,
+        }
+    }
+}
+
+fn position_string(seconds: f64) -> String {
+    let minutes = (seconds / 60.0).floor();
+    format!("{minutes}:{:06.3}", seconds - minutes * 60.0)
+}
+
+impl MockReaperState {
+    pub fn position_seconds(&self) -> f64 {
+        self.cursor_seconds
+            + self
+                .rolling_since
+                .map(|since| since.elapsed().as_secs_f64())
+                .unwrap_or_default()
+    }
+
+    fn roll(&mut self, playstate: Playstate) {
+        self.rolling_since.get_or_insert_with(Instant::now);
+        self.playstate = playstate;
+    }
+
+    fn stop(&mut self) {
+        self.rolling_since = None;
+        self.playstate = Playstate::Stopped;
+    }
+
+    fn transport(&self) -> String {
+        let position_seconds = self.position_seconds();
+        [
+            "TRANSPORT".to_owned(),
+            (self.playstate as isize).to_string(),
+            format!("{position_seconds:.6}"),
+            (self.is_repeat_on as isize).to_string(),
+            position_string(position_seconds),
+            "1.1.00".to_owned(),
+        ]
+        .join("\t")
+    }
+
+    fn beatpos(&self) -> String {
+        let position_seconds = self.position_seconds();
+        let full_beat_position = position_seconds * BPM / 60.0;
+        let measure = (full_beat_position / 4.0).floor();
+        [
+            "BEATPOS".to_owned(),
+            (self.playstate as isize).to_string(),
+            format!("{position_seconds:.6}"),
+            format!("{full_beat_position:.6}"),
+            measure.to_string(),
+            format!("{:.6}", full_beat_position - measure * 4.0),
+            "4".to_owned(),
+            "4".to_owned(),
+        ]
+        .join("\t")
+    }
+
+    /// meters only move while the transport is rolling, master is track 0
+    fn track(&self, track_number: usize) -> Option<String> {
+        let (name, flags) = match track_number {
+            0 => ("MASTER".to_owned(), TrackFlags::default()),
+            idx => self
+                .tracks
+                .get(idx - 1)
+                .map(|track| (track.name.clone(), track.flags))?,
+        };
+        let peak = self
+            .rolling_since
+            .map(|since| {
+                let wobble = (since.elapsed().as_secs_f64() * (track_number + 1) as f64).sin();
+                (-600.0 + 550.0 * wobble.abs()) as i32
+            })
+            .unwrap_or(-1500);
+        Some(
+            [
+                "TRACK".to_owned(),
+                track_number.to_string(),
+                name,
+                flags.0.to_string(),
+                "1.000000".to_owned(),
+                "0.000000".to_owned(),
+                peak.to_string(),
+                peak.to_string(),
+                "1.000000".to_owned(),
+                "3".to_owned(),
+                "0".to_owned(),
+                "0".to_owned(),
+                "1".to_owned(),
+                "0".to_owned(),
+            ]
+            .join("\t"),
+        )
+    }
+
+    fn list(name: &str, items: impl Iterator<Item = String>) -> String {
+        std::iter::once(format!("{name}_LIST"))
+            .chain(items)
+            .chain(std::iter::once(format!("{name}_LIST_END")))
+            .join("\n")
+    }
+
+    /// returns the response line for the command, actions don't respond with anything
+    #[instrument(level = "debug", ret)]
+    pub fn handle_command(&mut self, command: &str) -> Option<String> {
+        self.history.push(command.to_owned());
+        match command.split('/').collect_vec().as_slice() {
+            ["TRANSPORT"] => Some(self.transport()),
+            ["BEATPOS"] => Some(self.beatpos()),
+            ["NTRACK"] => Some(format!("NTRACK\t{}", self.tracks.len())),
+            ["TRACK"] => Some(
+                (0..=self.tracks.len())
+                    .filter_map(|idx| self.track(idx))
+                    .join("\n"),
+            ),
+            ["TRACK", idx] => idx.parse().ok().and_then(|idx| self.track(idx)),
+            ["MARKER"] => Some(Self::list(
+                "MARKER",
+                self.markers
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, (name, position))| {
+                        format!("MARKER\t{name}\t{}\t{position:.6}", idx + 1)
+                    }),
+            )),
+            ["REGION"] => Some(Self::list(
+                "REGION",
+                self.regions
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, (name, start, end))| {
+                        format!("REGION\t{name}\t{}\t{start:.6}\t{end:.6}", idx + 1)
+                    }),
+            )),
+            ["SET", "POS", position] => {
+                match position.parse() {
+                    Ok(position) => {
+                        self.cursor_seconds = position;
+                        if let Some(since) = self.rolling_since.as_mut() {
+                            *since = Instant::now();
+                        }
+                    }
+                    Err(message) => tracing::warn!(?message, %command, "bad position"),
+                }
+                None
+            }
+            ["SET", "PROJEXTSTATE", section, key, value] => {
+                self.project_ext_state
+                    .insert((section.to_string(), key.to_string()), value.to_string());
+                None
+            }
+            ["GET", "REPEAT"] => Some(format!("GET/REPEAT\t{}", self.is_repeat_on as isize)),
+            [action] => match ActionId::from_str(action) {
+                Ok(ActionId::TransportPlay) => {
+                    self.roll(Playstate::Playing);
+                    None
+                }
+                Ok(ActionId::TransportRecord) => {
+                    self.roll(Playstate::Recording);
+                    None
+                }
+                Ok(ActionId::TransportStop) => {
+                    self.stop();
+                    None
+                }
+                Ok(ActionId::SaveProject) => {
+                    self.save_count += 1;
+                    None
+                }
+                Ok(ActionId::TransportToggleRepeat) => {
+                    self.is_repeat_on = match self.is_repeat_on {
+                        ReaperBool::False => ReaperBool::True,
+                        ReaperBool::True => ReaperBool::False,
+                    };
+                    None
+                }
+                Ok(ActionId::InsertMarker) => {
+                    let position = self.position_seconds();
+                    self.markers
+                        .push((format!("{}", self.markers.len() + 1), position));
+                    None
+                }
+                Ok(ActionId::ItemNavigationMoveCursorToEndOfItems) => None,
+                Err(message) => {
+                    tracing::warn!(?message, %command, "unsupported command");
+                    None
+                }
+            },
+            _ => {
+                tracing::warn!(%command, "unsupported command");
+                None
+            }
+        }
+    }
+
+    /// `path` looks like `/_/TRANSPORT;1013;`
+    pub fn handle_path(&mut self, path: &str) -> Option<String> {
+        path.strip_prefix("/_/").map(|commands| {
+            commands
+                .split(';')
+                .filter(|command| !command.is_empty())
+                .filter_map(|command| self.handle_command(command))
+                .map(|line| format!("{line}\n"))
+                .collect()
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct MockReaper {
+    pub address: SocketAddr,
+    pub state: Arc<RwLock<MockReaperState>>,
+    _server: AbortOnDrop<()>,
+}
+
+async fn respond(stream: TcpStream, state: Arc<RwLock<MockReaperState>>) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    let request_line = lines
+        .next_line()
+        .await
+        .wrap_err("reading request line")?
+        .ok_or_else(|| eyre!("empty request"))?;
+    // headers are not interesting
+    while let Some(header) = lines.next_line().await.wrap_err("reading headers")? {
+        if header.is_empty() {
+            break;
+        }
+    }
+    let response = match request_line.split_whitespace().collect_vec().as_slice() {
+        ["GET", path, ..] => state
+            .write()
+            .handle_path(path)
+            .map(|body| ("200 OK", body))
+            .unwrap_or_else(|| ("404 Not Found", String::new())),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    let (status, body) = response;
+    write
+        .write_all(
+            format!(
+                "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .await
+        .wrap_err("writing response")?;
+    write.shutdown().await.wrap_err("closing connection")
+}
+
+impl MockReaper {
+    #[instrument(ret, err)]
+    pub async fn new(bind: SocketAddr) -> Result<Self> {
+        let listener = TcpListener::bind(bind)
+            .await
+            .wrap_err_with(|| format!("binding to {bind}"))?;
+        let address = listener.local_addr().wrap_err("reading local address")?;
+        let state = Arc::new(RwLock::new(MockReaperState::default()));
+        let server = {
+            to_owned![state];
+            tokio::task::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, peer)) => {
+                            to_owned![state];
+                            tokio::task::spawn(async move {
+                                if let Err(message) = respond(stream, state).await {
+                                    tracing::warn!(?message, %peer, "handling request");
+                                }
+                            });
+                        }
+                        Err(message) => tracing::error!(?message, "accepting connection"),
+                    }
+                }
+            })
+            .abort_on_drop()
+        };
+        Ok(Self {
+            address,
+            state,
+            _server: server,
+        })
+    }
+
+    /// what `--reaper-web-base-url` should be set to
+    pub fn base_url(&self) -> Result<Url> {
+        format!("http://{}/", self.address)
+            .parse()
+            .wrap_err("invalid mock server url")
+    }
+
+    #[instrument(ret, err, level = "INFO")]
+    pub async fn run(Args { bind }: Args) -> Result<()> {
+        let mock = Self::new(bind).await?;
+        tracing::info!(base_url = %mock.base_url()?, "mock reaper listening");
+        tokio::signal::ctrl_c()
+            .await
+            .wrap_err("waiting for CTRL+C")?;
+        tracing::warn!(state=?mock.state.read(), "CTRL+C received, shutting down");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::reaper::reaper_web_client::{
+        rea_request::{AllTracks, Transport},
+        ReaperWebClient,
+    };
+
+    pub(crate) async fn mock() -> (MockReaper, Arc<ReaperWebClient>) {
+        let mock = MockReaper::new("127.0.0.1:0".parse().expect("bind address"))
+            .await
+            .expect("starting the mock");
+        let web_client = ReaperWebClient::new(mock.base_url().expect("mock url"))
+            .await
+            .expect("mock is alive");
+        (mock, web_client)
+    }
+
+    /// talks to the mock, a `sleep` stands in for the REAPER process and the status is polled once
+    pub(crate) async fn instance(web_client: Arc<ReaperWebClient>) -> ReaperInstance {
+        let (notify, _wake_up) = tokio::sync::mpsc::unbounded_channel();
+        let child = bounded_command("sleep")
+            .arg("30")
+            .spawn()
+            .expect("spawning sleep")
+            .gracefully_shutdown_on_drop()
+            .await
+            .expect("sleep is running");
+        let status = web_client
+            .clone()
+            .run_batch((Transport, AllTracks))
+            .await
+            .map(|(transport, tracks)| ReaperStatus { transport, tracks });
+        ReaperInstance {
+            recording_start: RecordingStart {
+                time: crate::now(),
+                position_seconds: 0.0,
+                video_running_time_seconds: 0.0,
+                sync_error_seconds: 0.0,
+            },
+            process: Arc::new(RwLock::new(ProcessWatcher::new(
+                "sleep".to_owned(),
+                child,
+                notify,
+            ))),
+            state: Arc::new(RwLock::new(status)),
+            web_client,
+            closed: Default::default(),
+            _state_watcher: Arc::new(tokio::task::spawn(async {}).abort_on_drop()),
+        }
+    }
+
+    /// commands are sent from spawned tasks, gives them 5 seconds to arrive
+    pub(crate) async fn wait_until(mock: &MockReaper, done: impl Fn(&MockReaperState) -> bool) {
+        for _ in 0..50 {
+            if done(&mock.state.read()) {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        panic!("mock never got there: {:?}", mock.state.read());
+    }
+
+    #[test]
+    fn batched_commands_are_handled_in_order() {
+        let mut state = MockReaperState::default();
+        let response = state
+            .handle_path("/_/TRANSPORT;1013;TRANSPORT;")
+            .expect("handled");
+        assert_eq!(state.history, ["TRANSPORT", "1013", "TRANSPORT"]);
+        assert_eq!(state.playstate, Playstate::Recording);
+        assert_eq!(
+            response
+                .lines()
+                .map(|line| line.split('\t').nth(1).expect("playstate"))
+                .collect_vec(),
+            ["0", "5"]
+        );
+        assert!(state.handle_path("/favicon.ico").is_none());
+    }
+
+    #[tokio::test]
+    async fn listings_parse_with_the_client_types() {
+        use crate::reaper::reaper_web_client::rea_request::{
+            AllTracks, BeatPos, GetRepeat, Marker, NTrack, Region,
+        };
+        let (mock, web_client) = mock().await;
+        {
+            let mut state = mock.state.write();
+            state.markers.push(("verse".to_owned(), 12.0));
+            state.regions.push(("take 1".to_owned(), 0.0, 95.25));
+        }
+        let (count, tracks, beatpos, markers, regions, repeat) = web_client
+            .run_batch((NTrack, AllTracks, BeatPos, Marker, Region, GetRepeat))
+            .await
+            .expect("batch");
+        assert_eq!(count.count, 4);
+        assert_eq!(tracks.len(), 5);
+        assert!(tracks.iter().skip(1).all(|t| t.flags.is_record_armed()));
+        assert_eq!(beatpos.playstate, Playstate::Stopped);
+        assert_eq!(markers.0[0].name, "verse");
+        assert_eq!(regions.0[0].region_id, 1);
+        assert_eq!(repeat.value, ReaperBool::False);
+    }
+
+    #[test]
+    fn positions_are_formatted_like_reaper() {
+        assert_eq!(position_string(0.0), "0:00.000");
+        assert_eq!(position_string(75.5), "1:15.500");
+    }
+
+    #[tokio::test]
+    async fn wait_alive_polls_the_transport() {
+        let (mock, _web_client) = mock().await;
+        assert_eq!(mock.state.read().history, vec!["TRANSPORT".to_owned()]);
+    }
+
+    #[tokio::test]
+    async fn wait_alive_gives_up_when_nothing_listens() {
+        let address = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("free port");
+            listener.local_addr().expect("address")
+        };
+        let error = ReaperWebClient::new(format!("http://{address}/").parse().expect("url"))
+            .await
+            .expect_err("nothing is listening");
+        assert!(format!("{error:?}").contains("healthcheck failed"));
+    }
+
+    #[tokio::test]
+    async fn start_reaper_recording_presses_record() {
+        let (mock, web_client) = mock().await;
+        web_client
+            .start_reaper_recording()
+            .await
+            .expect("recording");
+        let state = mock.state.read();
+        assert_eq!(state.playstate, Playstate::Recording);
+        assert_eq!(state.history.last().map(String::as_str), Some("1013"));
+    }
+
+    #[tokio::test]
+    async fn start_reaper_recording_at_moves_the_cursor_first() {
+        let (mock, web_client) = mock().await;
+        let transport = web_client
+            .start_reaper_recording_at(12.5)
+            .await
+            .expect("recording");
+        assert_eq!(transport.playstate, Playstate::Recording);
+        assert!((transport.position_seconds - 12.5).abs() < 0.1);
+        assert_eq!(
+            mock.state.read().history[1..],
+            ["SET/POS/12.500000", "1013", "TRANSPORT"]
+        );
+    }
+
+    #[tokio::test]
+    async fn dropping_the_instance_stops_saves_and_closes() {
+        let (mock, web_client) = mock().await;
+        web_client
+            .clone()
+            .start_reaper_recording()
+            .await
+            .expect("recording");
+        let instance = instance(web_client).await;
+        let closed = instance.closed();
+        drop(instance);
+        tokio::time::timeout(std::time::Duration::from_secs(5), closed.cancelled())
+            .await
+            .expect("process terminated after saving");
+        let state = mock.state.read();
+        assert_eq!(state.playstate, Playstate::Stopped);
+        assert_eq!(state.save_count, 1);
+        assert_eq!(state.history[state.history.len() - 2..], ["1016", "40026"]);
+    }
+}
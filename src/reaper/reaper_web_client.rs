@@ -1,4 +1,4 @@
-use self::rea_request::{ActionId, ReaRequest, ReaResponse};
+use self::rea_request::{ActionId, ReaBatch, ReaRequest};
 
 use super::*;
 /// uses barely-documented web api, it's pretty simple though
@@ -14,8 +14,19 @@ pub mod rea_request {
     use crate::reaper::common_types::ReaperBool;
 
     use super::*;
+
+    /// lines of a single http response, peekable so that lists know where they end
+    pub type ResponseLines<'a> = std::iter::Peekable<std::str::Lines<'a>>;
+
     pub trait ReaResponse: Sized {
         fn from_response(response: &str) -> Result<Self>;
+        /// takes only the lines belonging to this response out of a multi-command reply
+        fn from_lines<'a>(lines: &mut ResponseLines<'a>) -> Result<Self> {
+            lines
+                .next()
+                .ok_or_else(|| eyre!("response line missing"))
+                .and_then(Self::from_response)
+        }
     }
 
     pub trait ReaRequest {
@@ -40,9 +51,14 @@ pub mod rea_request {
 
     #[derive(Debug, Clone, Copy, PartialEq, Eq, strum::FromRepr)]
     pub enum ActionId {
+        TransportPlay = 1007,
         TransportRecord = 1013,
         TransportStop = 1016,
+        /// Transport: Toggle repeat
+        TransportToggleRepeat = 1068,
         SaveProject = 40026,
+        /// Markers: Insert marker at current position
+        InsertMarker = 40157,
         /// Item navigation: Move cursor to end of items
         ItemNavigationMoveCursorToEndOfItems = 41174,
     }
@@ -106,7 +122,60 @@ pub mod rea_request {
         fn from_response(_response: &str) -> Result<Self> {
             Ok(())
         }
+        /// actions don't print anything
+        fn from_lines<'a>(_lines: &mut ResponseLines<'a>) -> Result<Self> {
+            Ok(())
+        }
+    }
+
+    /// multiple requests sent in a single round trip, as `_/TRANSPORT;1016;40026;`
+    pub trait ReaBatch {
+        type Response;
+        fn as_uris(&self) -> Vec<String>;
+        fn parse_lines<'a>(&self, lines: &mut ResponseLines<'a>) -> Result<Self::Response>;
+    }
+
+    impl<R: ReaRequest> ReaBatch for Vec<R> {
+        type Response = Vec<R::Response>;
+        fn as_uris(&self) -> Vec<String> {
+            self.iter().map(|request| request.as_uri()).collect()
+        }
+        fn parse_lines<'a>(&self, lines: &mut ResponseLines<'a>) -> Result<Self::Response> {
+            self.iter()
+                .enumerate()
+                .map(|(idx, _)| {
+                    R::Response::from_lines(lines).wrap_err_with(|| {
+                        format!("parsing response {idx} ({})", std::any::type_name::<R>())
+                    })
+                })
+                .collect()
+        }
+    }
+
+    macro_rules! rea_batch_tuple {
+        ($($request:ident),+) => {
+            impl<$($request: ReaRequest),+> ReaBatch for ($($request,)+) {
+                type Response = ($(<$request as ReaRequest>::Response,)+);
+                #[allow(non_snake_case)]
+                fn as_uris(&self) -> Vec<String> {
+                    let ($($request,)+) = self;
+                    vec![$($request.as_uri()),+]
+                }
+                fn parse_lines<'a>(&self, lines: &mut ResponseLines<'a>) -> Result<Self::Response> {
+                    Ok(($(
+                        <$request as ReaRequest>::Response::from_lines(lines)
+                            .wrap_err_with(|| format!("parsing response of {}", std::any::type_name::<$request>()))?,
+                    )+))
+                }
+            }
+        };
     }
+    rea_batch_tuple!(A);
+    rea_batch_tuple!(A, B);
+    rea_batch_tuple!(A, B, C);
+    rea_batch_tuple!(A, B, C, D);
+    rea_batch_tuple!(A, B, C, D, E);
+    rea_batch_tuple!(A, B, C, D, E, F);
 
     /// * TRANSPORT
     /// Returns a line including (note that the spaces are here for readability, there
@@ -135,6 +204,273 @@ pub mod rea_request {
             pub position_string_beats: String,
         }
     }
+
+    /// * BEATPOS
+    ///
+    /// Returns a line:
+    ///   BEATPOS \t playstate \t position_seconds \t full_beat_position \t measure_cnt \t beats_in_measure \t ts_numerator \t ts_denominator
+    pub struct BeatPos;
+    impl ReaRequest for BeatPos {
+        type Response = BeatPosResponse;
+    }
+    rea_response! {
+        #[derive(Debug, Clone)]
+        pub struct BeatPosResponse {
+            pub playstate: Playstate,
+            pub position_seconds: f64,
+            pub full_beat_position: f64,
+            pub measure_count: i64,
+            pub beats_in_measure: f64,
+            pub ts_numerator: u32,
+            pub ts_denominator: u32,
+        }
+    }
+
+    /// * NTRACK
+    ///
+    /// Returns a line:
+    ///   NTRACK \t value
+    ///
+    /// value is the number of tracks, not counting the master track
+    pub struct NTrack;
+    impl ReaRequest for NTrack {
+        type Response = NTrackResponse;
+    }
+    rea_response! {
+        #[derive(Debug, Clone, Copy)]
+        pub struct NTrackResponse {
+            pub count: usize,
+        }
+    }
+
+    /// &1=folder, &2=selected, &4=has FX, &8=muted, &16=soloed (&32: solo-in-place),
+    /// &64=record armed, &128=record monitoring on, &256=record monitoring auto,
+    /// &512=hide from TCP, &1024=hide from MCP
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default, derive_more::FromStr)]
+    pub struct TrackFlags(pub u32);
+
+    impl TrackFlags {
+        pub const FOLDER: u32 = 1;
+        pub const SELECTED: u32 = 2;
+        pub const HAS_FX: u32 = 4;
+        pub const MUTED: u32 = 8;
+        pub const SOLOED: u32 = 16;
+        pub const RECORD_ARMED: u32 = 64;
+        pub const RECORD_MONITORING_ON: u32 = 128;
+        pub const RECORD_MONITORING_AUTO: u32 = 256;
+
+        pub fn contains(&self, flag: u32) -> bool {
+            self.0 & flag == flag
+        }
+        pub fn is_record_armed(&self) -> bool {
+            self.contains(Self::RECORD_ARMED)
+        }
+        pub fn is_muted(&self) -> bool {
+            self.contains(Self::MUTED)
+        }
+    }
+
+    /// * TRACK/index
+    ///
+    /// Returns a line:
+    ///   TRACK \t tracknumber \t trackname \t trackflags \t volume \t pan \t last_meter_peak \t last_meter_pos \t width/pan2 \t panmode \t sendcnt \t recvcnt \t hwoutcnt \t color
+    ///
+    /// tracknumber is zero for the master track, 1 for the first track, etc.
+    /// volume is a linear gain, 1 is 0dB. last_meter_peak and last_meter_pos are dB*10.
+    pub struct Track(pub usize);
+    impl ReaRequest for Track {
+        type Response = TrackResponse;
+        fn as_uri(&self) -> String {
+            format!("TRACK/{}", self.0)
+        }
+    }
+    rea_response! {
+        #[derive(Debug, Clone)]
+        pub struct TrackResponse {
+            pub track_number: usize,
+            pub name: String,
+            pub flags: TrackFlags,
+            pub volume: f64,
+            pub pan: f64,
+            pub last_meter_peak: i32,
+            pub last_meter_pos: i32,
+            pub width_pan2: f64,
+            pub panmode: i32,
+            pub send_count: usize,
+            pub receive_count: usize,
+            pub hardware_out_count: usize,
+            pub color: i64,
+        }
+    }
+
+    impl TrackResponse {
+        pub fn last_meter_peak_db(&self) -> f64 {
+            self.last_meter_peak as f64 / 10.0
+        }
+        pub fn is_master(&self) -> bool {
+            self.track_number == 0
+        }
+    }
+
+    /// * TRACK
+    ///
+    /// Returns one TRACK line per track, master track included
+    pub struct AllTracks;
+    impl ReaRequest for AllTracks {
+        type Response = Vec<TrackResponse>;
+        fn as_uri(&self) -> String {
+            "TRACK".to_owned()
+        }
+    }
+
+    impl ReaResponse for Vec<TrackResponse> {
+        fn from_response(response: &str) -> Result<Self> {
+            Self::from_lines(&mut response.lines().peekable())
+        }
+        fn from_lines<'a>(lines: &mut ResponseLines<'a>) -> Result<Self> {
+            lines
+                .peeking_take_while(|line| line.starts_with("TRACK\t"))
+                .map(TrackResponse::from_response)
+                .collect()
+        }
+    }
+
+    /// * GET/REPEAT
+    ///
+    /// Returns a line:
+    ///   GET/REPEAT \t value
+    pub struct GetRepeat;
+    impl ReaRequest for GetRepeat {
+        type Response = GetRepeatResponse;
+        fn as_uri(&self) -> String {
+            "GET/REPEAT".to_owned()
+        }
+    }
+    rea_response! {
+        #[derive(Debug, Clone, Copy)]
+        pub struct GetRepeatResponse {
+            pub value: ReaperBool,
+        }
+    }
+
+    /// * SET/POS/value
+    ///
+    /// Moves the edit cursor, doesn't return anything
+    pub struct SetPos(pub f64);
+    impl ReaRequest for SetPos {
+        type Response = ();
+        fn as_uri(&self) -> String {
+            format!("SET/POS/{:.6}", self.0)
+        }
+    }
+
+    /// REAPER decodes every path component, so anything that isn't alphanumeric gets escaped
+    pub fn encode_component(value: &str) -> String {
+        value
+            .bytes()
+            .map(|byte| match byte {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => {
+                    (byte as char).to_string()
+                }
+                other => format!("%{other:02X}"),
+            })
+            .collect()
+    }
+
+    /// * SET/PROJEXTSTATE/section/key/value
+    ///
+    /// Stores a value in the project file, doesn't return anything
+    pub struct SetProjExtState {
+        pub section: String,
+        pub key: String,
+        pub value: String,
+    }
+    impl ReaRequest for SetProjExtState {
+        type Response = ();
+        fn as_uri(&self) -> String {
+            format!(
+                "SET/PROJEXTSTATE/{}/{}/{}",
+                encode_component(&self.section),
+                encode_component(&self.key),
+                encode_component(&self.value)
+            )
+        }
+    }
+
+    /// lists are wrapped in `<LIST>` ... `<LIST>_END` lines
+    pub trait ReaListItem: ReaResponse {
+        const LIST: &'static str;
+    }
+
+    #[derive(Debug, Clone, Default)]
+    pub struct ReaList<T>(pub Vec<T>);
+
+    impl<T: ReaListItem> ReaResponse for ReaList<T> {
+        fn from_response(response: &str) -> Result<Self> {
+            Self::from_lines(&mut response.lines().peekable())
+        }
+        fn from_lines<'a>(lines: &mut ResponseLines<'a>) -> Result<Self> {
+            let end = format!("{}_END", T::LIST);
+            lines
+                .next()
+                .filter(|line| *line == T::LIST)
+                .ok_or_else(|| eyre!("expected {}", T::LIST))?;
+            let items = lines
+                .peeking_take_while(|line| *line != end)
+                .map(T::from_response)
+                .collect::<Result<Vec<_>>>()?;
+            lines
+                .next()
+                .filter(|line| *line == end)
+                .ok_or_else(|| eyre!("list not terminated with {end}"))
+                .map(|_| Self(items))
+        }
+    }
+
+    /// * MARKER
+    ///
+    /// Returns a list of markers:
+    ///   MARKER_LIST
+    ///   MARKER \t name \t markerID \t position_seconds [\t color]
+    ///   MARKER_LIST_END
+    pub struct Marker;
+    impl ReaRequest for Marker {
+        type Response = ReaList<MarkerResponse>;
+    }
+    rea_response! {
+        #[derive(Debug, Clone)]
+        pub struct MarkerResponse {
+            pub name: String,
+            pub marker_id: u32,
+            pub position_seconds: f64,
+        }
+    }
+    impl ReaListItem for MarkerResponse {
+        const LIST: &'static str = "MARKER_LIST";
+    }
+
+    /// * REGION
+    ///
+    /// Returns a list of regions:
+    ///   REGION_LIST
+    ///   REGION \t name \t regionID \t start_position \t end_position [\t color]
+    ///   REGION_LIST_END
+    pub struct Region;
+    impl ReaRequest for Region {
+        type Response = ReaList<RegionResponse>;
+    }
+    rea_response! {
+        #[derive(Debug, Clone)]
+        pub struct RegionResponse {
+            pub name: String,
+            pub region_id: u32,
+            pub start_position: f64,
+            pub end_position: f64,
+        }
+    }
+    impl ReaListItem for RegionResponse {
+        const LIST: &'static str = "REGION_LIST";
+    }
 }
 
 impl ReaperWebClient {
@@ -149,10 +485,10 @@ impl ReaperWebClient {
         .and_then(|client| client.wait_alive())
         .await
     }
-    pub async fn run_single<R: ReaRequest>(self: Arc<Self>, request: R) -> Result<R::Response> {
+    pub async fn run_batch<B: ReaBatch>(self: Arc<Self>, batch: B) -> Result<B::Response> {
         ready(
             self.base_addr
-                .join(&format!("_/{};", request.as_uri()))
+                .join(&format!("_/{};", batch.as_uris().join(";")))
                 .wrap_err("invalid url"),
         )
         .and_then(|url| {
@@ -164,14 +500,21 @@ impl ReaperWebClient {
                 .and_then(|r| r.text().map(|res| res.wrap_err("bad text response")))
                 .and_then(|response| {
                     ready(
-                        ReaResponse::from_response(&response)
+                        batch
+                            .parse_lines(&mut response.lines().peekable())
                             .wrap_err_with(|| format!("bad response: '{response}'")),
                     )
                 })
                 .map(move |v| v.wrap_err_with(|| format!("performing request: {url}")))
         })
         .await
-        .wrap_err_with(|| format!("performing reaper request: {}", std::any::type_name::<R>()))
+        .wrap_err_with(|| format!("performing reaper request: {}", std::any::type_name::<B>()))
+    }
+
+    pub async fn run_single<R: ReaRequest>(self: Arc<Self>, request: R) -> Result<R::Response> {
+        self.run_batch((request,))
+            .map_ok(|(response,)| response)
+            .await
     }
 
     async fn is_alive(self: Arc<Self>) -> Result<Arc<Self>> {
@@ -201,4 +544,199 @@ impl ReaperWebClient {
     pub async fn start_reaper_recording(self: Arc<Self>) -> Result<()> {
         self.run_single(ActionId::TransportRecord).await
     }
+
+    /// cursor is moved and recording started in one go, returns the transport right after
+    pub async fn start_reaper_recording_at(
+        self: Arc<Self>,
+        position_seconds: f64,
+    ) -> Result<rea_request::TransportResponse> {
+        self.run_batch((
+            rea_request::SetPos(position_seconds),
+            ActionId::TransportRecord,
+            rea_request::Transport,
+        ))
+        .map_ok(|((), (), transport)| transport)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::rea_request::*;
+    use super::*;
+    use crate::reaper::common_types::ReaperBool;
+
+    /// captured from REAPER 6.81, fields are tab separated
+    const RECORDING: &str = "TRANSPORT\t5\t12.345000\t0\t0:12.345\t7.2.00";
+    const STOPPED: &str = "TRANSPORT\t0\t0.000000\t1\t0:00.000\t1.1.00";
+
+    fn parse<B: ReaBatch>(batch: B, response: &str) -> Result<B::Response> {
+        batch.parse_lines(&mut response.lines().peekable())
+    }
+
+    #[test]
+    fn transport_line_parses() {
+        let transport = TransportResponse::from_response(RECORDING).expect("transport");
+        assert_eq!(transport.playstate, Playstate::Recording);
+        assert!((transport.position_seconds - 12.345).abs() < f64::EPSILON);
+        assert_eq!(transport.position_string, "0:12.345");
+        assert_eq!(transport.position_string_beats, "7.2.00");
+    }
+
+    #[test]
+    fn batches_are_sent_as_a_single_path() {
+        assert_eq!(
+            (ActionId::TransportStop, ActionId::SaveProject, Transport).as_uris(),
+            ["1016", "40026", "TRANSPORT"]
+        );
+        assert_eq!(
+            vec![Transport, Transport].as_uris(),
+            ["TRANSPORT", "TRANSPORT"]
+        );
+    }
+
+    #[test]
+    fn actions_in_a_batch_take_no_lines() {
+        let ((), transport, ()) = parse(
+            (ActionId::TransportRecord, Transport, ActionId::SaveProject),
+            &format!("{RECORDING}\n"),
+        )
+        .expect("batch");
+        assert_eq!(transport.playstate, Playstate::Recording);
+    }
+
+    #[test]
+    fn batched_responses_come_back_in_order() {
+        let (before, (), after) = parse(
+            (Transport, ActionId::TransportRecord, Transport),
+            &format!("{STOPPED}\n{RECORDING}\n"),
+        )
+        .expect("batch");
+        assert_eq!(before.playstate, Playstate::Stopped);
+        assert_eq!(after.playstate, Playstate::Recording);
+
+        let transports = parse(
+            vec![Transport, Transport],
+            &format!("{RECORDING}\n{STOPPED}\n"),
+        )
+        .expect("batch");
+        assert_eq!(
+            transports.iter().map(|t| t.playstate).collect_vec(),
+            [Playstate::Recording, Playstate::Stopped]
+        );
+    }
+
+    #[test]
+    fn missing_lines_are_reported() {
+        let error = parse((Transport, Transport), RECORDING).expect_err("one line short");
+        assert!(format!("{error:#}").contains("response line missing"));
+        assert!(parse(vec![Transport], "").is_err());
+    }
+
+    #[test]
+    fn malformed_lines_are_reported() {
+        for line in [
+            "TRANSPORT\t5\t12.345000",
+            "TRANSPORT\t4\t12.345000\t0\t0:12.345\t7.2.00",
+            "TRANSPORT\t5\tsoon\t0\t0:12.345\t7.2.00",
+            "TRANSPORT\t5\t12.345000\t2\t0:12.345\t7.2.00",
+            "",
+        ] {
+            assert!(TransportResponse::from_response(line).is_err(), "{line:?}");
+        }
+    }
+
+    const TRACKS: &str =
+        "TRACK\t0\tMASTER\t0\t1.000000\t0.000000\t-1500\t-1500\t1.000000\t3\t0\t0\t1\t0
+TRACK\t1\tGuitar\t66\t0.501187\t-0.250000\t-182\t-240\t1.000000\t3\t0\t0\t1\t16576
+TRACK\t2\tVocals\t8\t1.000000\t0.000000\t-1500\t-1500\t1.000000\t3\t1\t0\t1\t0";
+
+    #[test]
+    fn tracks_parse_with_their_flags() {
+        let (count, tracks, track) = parse(
+            (NTrack, AllTracks, Track(1)),
+            &format!(
+                "NTRACK\t2\n{TRACKS}\n{}\n",
+                TRACKS.lines().nth(1).expect("guitar")
+            ),
+        )
+        .expect("batch");
+        assert_eq!(count.count, 2);
+        assert_eq!(
+            tracks.iter().map(|t| t.name.as_str()).collect_vec(),
+            ["MASTER", "Guitar", "Vocals"]
+        );
+        assert!(tracks[0].is_master());
+        assert!(tracks[1].flags.is_record_armed());
+        assert!(tracks[1].flags.contains(TrackFlags::SELECTED));
+        assert!(tracks[2].flags.is_muted());
+        assert!(!tracks[2].flags.is_record_armed());
+        assert_eq!(track.name, "Guitar");
+        assert!((track.last_meter_peak_db() + 18.2).abs() < 1e-9);
+        assert_eq!(track.color, 16576);
+    }
+
+    #[test]
+    fn beatpos_and_repeat_parse() {
+        let (beatpos, repeat) = parse(
+            (BeatPos, GetRepeat),
+            "BEATPOS\t5\t12.345000\t24.690000\t6\t0.690000\t4\t4\nGET/REPEAT\t1\n",
+        )
+        .expect("batch");
+        assert_eq!(beatpos.playstate, Playstate::Recording);
+        assert_eq!(beatpos.measure_count, 6);
+        assert_eq!((beatpos.ts_numerator, beatpos.ts_denominator), (4, 4));
+        assert_eq!(repeat.value, ReaperBool::True);
+        assert_eq!(GetRepeat.as_uri(), "GET/REPEAT");
+        assert_eq!(Track(3).as_uri(), "TRACK/3");
+        assert_eq!(BeatPos.as_uri(), "BEATPOS");
+    }
+
+    #[test]
+    fn lists_take_everything_up_to_their_end() {
+        let (markers, regions, transport) = parse(
+            (Marker, Region, Transport),
+            &format!(
+                "MARKER_LIST
+MARKER\tverse\t1\t12.000000\t16777471
+MARKER\tchorus\t2\t47.500000
+MARKER_LIST_END
+REGION_LIST
+REGION\ttake 1\t1\t0.000000\t95.250000
+REGION_LIST_END
+{STOPPED}
+"
+            ),
+        )
+        .expect("batch");
+        assert_eq!(
+            markers
+                .0
+                .iter()
+                .map(|m| (m.name.as_str(), m.marker_id))
+                .collect_vec(),
+            [("verse", 1), ("chorus", 2)]
+        );
+        assert!((markers.0[1].position_seconds - 47.5).abs() < f64::EPSILON);
+        assert_eq!(regions.0.len(), 1);
+        assert!((regions.0[0].end_position - 95.25).abs() < f64::EPSILON);
+        assert_eq!(transport.playstate, Playstate::Stopped);
+
+        let (empty,) = parse((Marker,), "MARKER_LIST\nMARKER_LIST_END\n").expect("empty list");
+        assert!(empty.0.is_empty());
+    }
+
+    #[test]
+    fn broken_lists_are_reported() {
+        for response in [
+            "MARKER_LIST\nMARKER\tverse\t1\t12.000000\n",
+            "REGION_LIST\nMARKER_LIST_END\n",
+            "MARKER\tverse\t1\t12.000000\nMARKER_LIST_END\n",
+            "MARKER_LIST\nMARKER\tverse\tfirst\t12.000000\nMARKER_LIST_END\n",
+        ] {
+            assert!(parse((Marker,), response).is_err(), "{response:?}");
+        }
+        assert!(parse((NTrack,), "NTRACK\tmany").is_err());
+        assert!(parse((GetRepeat,), "GET/REPEAT").is_err());
+    }
 }
@@ -0,0 +1,787 @@
+use super::*;
+use crate::reaper::{reaper_web_client::rea_request::Playstate, ReaperStatus, RecordingStart};
+use std::{collections::BTreeMap, iter::once, path::Path};
+
+pub const SESSION_MANIFEST_FILE_NAME: &str = "session.json";
+
+/// exit status of a child as reported by the os, `None` while it's still running
+pub type ChildExitStatus = Arc<RwLock<Option<String>>>;
+
+/// filled in by the capture pipeline as splitmuxsink opens and closes files
+pub type VideoSegments = Arc<RwLock<Vec<VideoSegment>>>;
+
+/// one file of a segmented recording, times are relative to the camera's `video-started-at`
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct VideoSegment {
+    pub path: PathBuf,
+    pub start_seconds: f64,
+    /// `None` while it's still being written, or when the recording crashed
+    pub end_seconds: Option<f64>,
+}
+
+/// a single capture pipeline of the run
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CameraRun {
+    pub video_device: VideoDevice,
+    pub video_device_details: Option<DetailedVideoDevice>,
+    pub pipeline: String,
+    pub video_file: PathBuf,
+    pub video_started_at: ProjectTime,
+    /// where the take starts on the REAPER timeline, moved by the sync error measured
+    /// when REAPER started recording
+    pub item_position_seconds: f64,
+    /// empty unless recording in segments, each one goes at
+    /// `item-position-seconds + start-seconds` on the timeline
+    #[serde(default)]
+    pub segments: Vec<VideoSegment>,
+    /// takes started after the pipeline died mid-session
+    #[serde(default)]
+    pub recoveries: Vec<RecoveredTake>,
+}
+
+impl CameraRun {
+    /// the file that was being written at `time` and how far into it `time` is,
+    /// segments and recovered takes included
+    pub fn video_at(&self, time: ProjectTime) -> (PathBuf, f64) {
+        let files = match self.segments.is_empty() {
+            true => vec![(self.video_file.clone(), self.video_started_at)],
+            false => self
+                .segments
+                .iter()
+                .map(|segment| {
+                    (
+                        segment.path.clone(),
+                        self.video_started_at
+                            + chrono::Duration::microseconds(
+                                (segment.start_seconds * 1_000_000.0) as i64,
+                            ),
+                    )
+                })
+                .collect(),
+        }
+        .into_iter()
+        .chain(
+            self.recoveries
+                .iter()
+                .map(|take| (take.video_file.clone(), take.video_started_at)),
+        )
+        .collect_vec();
+        let (video_file, started_at) = files
+            .iter()
+            .filter(|(_, started_at)| *started_at <= time)
+            .max_by_key(|(_, started_at)| *started_at)
+            .or_else(|| files.first())
+            .cloned()
+            .unwrap_or_else(|| (self.video_file.clone(), self.video_started_at));
+        (video_file, seconds_between(started_at, time))
+    }
+}
+
+/// a gap in the video and the take that picked up after it
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RecoveredTake {
+    /// when the dead pipeline was noticed
+    pub gap_started_at: ProjectTime,
+    pub gap_seconds: f64,
+    pub video_file: PathBuf,
+    pub video_started_at: ProjectTime,
+    pub item_position_seconds: f64,
+    /// REAPER can't take new media items while it's running, they get added to
+    /// the project once it's closed, on the next resume or by `sessions finish-project`
+    pub placed_in_project: bool,
+}
+
+/// a file of the run and where it belongs on the REAPER timeline
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimelineItem {
+    pub path: PathBuf,
+    pub position_seconds: f64,
+    /// `None` when neither the run nor REAPER said when it stopped
+    pub length_seconds: Option<f64>,
+    /// previous segment of the camera, the item goes on the same track
+    pub follows: Option<PathBuf>,
+}
+
+fn seconds_between(from: ProjectTime, to: ProjectTime) -> f64 {
+    (to - from).num_microseconds().unwrap_or_default() as f64 / 1_000_000.0
+}
+
+impl RecordingRun {
+    /// every take of every camera, a take ends where the next one picked up
+    /// and the last one when the run stopped, segmented recordings get an item per segment
+    pub fn timeline_items(&self) -> Vec<TimelineItem> {
+        self.cameras
+            .iter()
+            .flat_map(|camera| match camera.segments.is_empty() {
+                true => self.take_items(camera),
+                false => self.segment_items(camera),
+            })
+            .collect()
+    }
+
+    /// restarted pipelines share the segment list, so recovered takes are in there as well
+    fn segment_items(&self, camera: &CameraRun) -> Vec<TimelineItem> {
+        let run_end_seconds = self
+            .stopped_at
+            .map(|stopped_at| seconds_between(camera.video_started_at, stopped_at))
+            .or_else(|| {
+                self.reaper_stop_position_seconds
+                    .map(|stop_position| stop_position - camera.item_position_seconds)
+            });
+        camera
+            .segments
+            .iter()
+            .enumerate()
+            .map(|(index, segment)| {
+                // a segment left open by a crash ends where the next one starts
+                let end_seconds = segment
+                    .end_seconds
+                    .or_else(|| {
+                        camera
+                            .segments
+                            .get(index + 1)
+                            .map(|next| next.start_seconds)
+                    })
+                    .or(run_end_seconds);
+                TimelineItem {
+                    path: segment.path.clone(),
+                    position_seconds: camera.item_position_seconds + segment.start_seconds,
+                    length_seconds: end_seconds
+                        .map(|end_seconds| end_seconds - segment.start_seconds),
+                    follows: index
+                        .checked_sub(1)
+                        .map(|previous| camera.segments[previous].path.clone()),
+                }
+            })
+            .collect()
+    }
+
+    fn take_items(&self, camera: &CameraRun) -> Vec<TimelineItem> {
+        let takes = once((
+            camera.video_file.clone(),
+            camera.video_started_at,
+            camera.item_position_seconds,
+        ))
+        .chain(camera.recoveries.iter().map(|take| {
+            (
+                take.video_file.clone(),
+                take.video_started_at,
+                take.item_position_seconds,
+            )
+        }));
+        let ended_at = camera
+            .recoveries
+            .iter()
+            .map(|take| Some(take.gap_started_at))
+            .chain(once(self.stopped_at));
+        takes
+            .zip(ended_at)
+            .map(|((path, started_at, position_seconds), ended_at)| {
+                let length_seconds = match ended_at {
+                    Some(ended_at) => Some(seconds_between(started_at, ended_at)),
+                    // crashed before finalizing, the last recording position is
+                    // the best there is
+                    None => self
+                        .reaper_stop_position_seconds
+                        .map(|stop_position| stop_position - position_seconds),
+                };
+                TimelineItem {
+                    path,
+                    position_seconds,
+                    length_seconds,
+                    follows: None,
+                }
+            })
+            .collect_vec()
+    }
+}
+
+impl SessionManifest {
+    /// recovered takes count as placed from now on
+    pub fn mark_project_finished(&mut self) {
+        self.runs.iter_mut().for_each(|run| {
+            run.project_finished = true;
+            run.cameras
+                .iter_mut()
+                .flat_map(|camera| camera.recoveries.iter_mut())
+                .for_each(|take| take.placed_in_project = true);
+        });
+    }
+}
+
+/// a recording copied to the mirror directory and read back with the same checksum
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MirroredFile {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub size_bytes: u64,
+    pub crc32: u32,
+    pub mirrored_at: ProjectTime,
+}
+
+/// one `StartRecording` run
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RecordingRun {
+    pub template: PathBuf,
+    /// copy of the project taken before a resumed run touched it
+    #[serde(default)]
+    pub project_backup: Option<PathBuf>,
+    /// empty in manifests written before multi-camera recording
+    #[serde(default)]
+    pub cameras: Vec<CameraRun>,
+    pub capture_settings: CaptureSettings,
+    pub video_files: Vec<PathBuf>,
+    pub started_at: ProjectTime,
+    /// earliest of the cameras, REAPER recording is synced to it
+    pub video_started_at: ProjectTime,
+    pub stopped_at: Option<ProjectTime>,
+    pub reaper_start: Option<RecordingStart>,
+    /// last position seen while recording, the stop position once finalized
+    pub reaper_stop_position_seconds: Option<f64>,
+    pub children: BTreeMap<String, Option<String>>,
+    pub finalized: bool,
+    /// secondary destination the recordings get copied to while the session runs
+    #[serde(default)]
+    pub mirror_directory: Option<PathBuf>,
+    #[serde(default)]
+    pub mirrored: Vec<MirroredFile>,
+    /// marker notes of the run, their names get written into the .rpp once REAPER is closed
+    #[serde(default)]
+    pub markers_file: Option<PathBuf>,
+    /// marker names and item positions were written into the .rpp after REAPER closed
+    #[serde(default)]
+    pub project_finished: bool,
+}
+
+/// `session.json` in the project directory, every run of the project gets appended
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SessionManifest {
+    pub project_name: String,
+    pub runs: Vec<RecordingRun>,
+}
+
+impl SessionManifest {
+    pub fn path(project_directory: &Path) -> PathBuf {
+        project_directory.join(SESSION_MANIFEST_FILE_NAME)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        std::fs::read_to_string(path)
+            .wrap_err("reading manifest")
+            .and_then(|content| serde_json::from_str(&content).wrap_err("parsing manifest"))
+            .wrap_err_with(|| format!("loading {}", path.display()))
+    }
+
+    /// `None` when there is none yet, a manifest that doesn't parse is renamed to
+    /// `session.json.corrupt-<time>` so that the session can go on with a fresh one
+    pub fn load_or_move_aside(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("reading {}", path.display()))?;
+        match serde_json::from_str(&content) {
+            Ok(manifest) => Ok(Some(manifest)),
+            Err(parsing) => {
+                let corrupt_path = path.with_extension(format!(
+                    "json.corrupt-{}",
+                    crate::now().format("%Y-%m-%d--%H-%M-%S")
+                ));
+                std::fs::rename(path, &corrupt_path)
+                    .wrap_err_with(|| format!("moving aside to {}", corrupt_path.display()))?;
+                tracing::warn!(
+                    ?parsing,
+                    ?corrupt_path,
+                    "session manifest is corrupt, starting a fresh one"
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// written next to it and renamed so that a crash never leaves half a file behind
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let temp_path = path.with_extension("json.part");
+        serde_json::to_string_pretty(self)
+            .wrap_err("serializing manifest")
+            .and_then(|content| {
+                std::fs::write(&temp_path, content)
+                    .wrap_err_with(|| format!("writing {}", temp_path.display()))
+            })
+            .and_then(|_| {
+                std::fs::rename(&temp_path, path)
+                    .wrap_err_with(|| format!("moving manifest to {}", path.display()))
+            })
+    }
+
+    pub fn current_run(&mut self) -> Option<&mut RecordingRun> {
+        self.runs.last_mut()
+    }
+}
+
+/// the manifest of the ongoing run, every update hits the disk right away
+#[derive(Debug, Clone)]
+pub struct SessionManifestFile {
+    pub path: PathBuf,
+    manifest: Arc<RwLock<SessionManifest>>,
+}
+
+impl SessionManifestFile {
+    #[instrument(skip(run), ret, err)]
+    pub fn create(
+        project_directory: &Path,
+        project_name: &ProjectName,
+        run: RecordingRun,
+    ) -> Result<Self> {
+        let path = SessionManifest::path(project_directory);
+        let mut manifest =
+            SessionManifest::load_or_move_aside(&path)?.unwrap_or_else(|| SessionManifest {
+                project_name: project_name.to_string(),
+                runs: vec![],
+            });
+        manifest.runs.push(run);
+        manifest.save(&path).map(|_| Self {
+            path,
+            manifest: Arc::new(RwLock::new(manifest)),
+        })
+    }
+
+    pub fn snapshot(&self) -> SessionManifest {
+        self.manifest.read().clone()
+    }
+
+    pub fn update(&self, update: impl FnOnce(&mut SessionManifest)) -> Result<()> {
+        let mut manifest = self.manifest.write();
+        update(&mut manifest);
+        manifest
+            .save(&self.path)
+            .wrap_err("updating session manifest")
+    }
+
+    pub fn update_run(&self, update: impl FnOnce(&mut RecordingRun)) -> Result<()> {
+        self.update(|manifest| {
+            if let Some(run) = manifest.current_run() {
+                update(run);
+            }
+        })
+    }
+
+    /// keeps child statuses, video segments and the recording position up to date,
+    /// `segments` are in the same order as the cameras of the run
+    pub fn watch(
+        &self,
+        children: Vec<(String, ChildExitStatus)>,
+        segments: Vec<VideoSegments>,
+        reaper_status: Arc<RwLock<Result<ReaperStatus>>>,
+    ) -> AbortOnDrop<()> {
+        let manifest = self.clone();
+        tokio::task::spawn(async move {
+            let mut interval = crate::process::app_interval(std::time::Duration::from_secs(1));
+            let mut last_written = None;
+            loop {
+                interval.tick().await;
+                let statuses = children
+                    .iter()
+                    .map(|(name, status)| (name.clone(), status.read().clone()))
+                    .collect::<BTreeMap<_, _>>();
+                let recording_position = reaper_status.read().as_ref().ok().and_then(|status| {
+                    matches!(status.transport.playstate, Playstate::Recording)
+                        .then_some(status.transport.position_seconds)
+                });
+                let segments = segments
+                    .iter()
+                    .map(|segments| segments.read().clone())
+                    .collect_vec();
+                let snapshot = (statuses, segments, recording_position);
+                if last_written.as_ref() == Some(&snapshot) {
+                    continue;
+                }
+                let (statuses, segments, recording_position) = snapshot.clone();
+                match manifest.update_run(|run| {
+                    run.children.extend(statuses);
+                    run.cameras
+                        .iter_mut()
+                        .zip(segments)
+                        .filter(|(_, segments)| !segments.is_empty())
+                        .for_each(|(camera, segments)| camera.segments = segments);
+                    run.video_files = run
+                        .cameras
+                        .iter()
+                        .flat_map(|camera| match camera.segments.is_empty() {
+                            true => std::iter::once(camera.video_file.clone())
+                                .chain(camera.recoveries.iter().map(|take| take.video_file.clone()))
+                                .collect_vec(),
+                            false => camera
+                                .segments
+                                .iter()
+                                .map(|segment| segment.path.clone())
+                                .collect(),
+                        })
+                        .collect();
+                    if let Some(position) = recording_position {
+                        run.reaper_stop_position_seconds = Some(position);
+                    }
+                }) {
+                    Ok(()) => last_written = Some(snapshot),
+                    Err(message) => tracing::warn!(?message, "session manifest not updated"),
+                }
+            }
+        })
+        .abort_on_drop()
+    }
+
+    pub fn finalize(&self) -> Result<()> {
+        self.update_run(|run| {
+            run.stopped_at = Some(crate::now());
+            run.finalized = true;
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::reaper::reaper_web_client::rea_request::{ReaResponse, TransportResponse};
+
+    pub(crate) fn run(stopped_at: Option<ProjectTime>) -> RecordingRun {
+        RecordingRun {
+            template: "template.RPP".into(),
+            project_backup: None,
+            cameras: vec![camera("take.mkv", started_at())],
+            capture_settings: Default::default(),
+            video_files: vec!["take.mkv".into()],
+            started_at: started_at(),
+            video_started_at: started_at(),
+            stopped_at,
+            reaper_start: None,
+            reaper_stop_position_seconds: None,
+            children: Default::default(),
+            finalized: stopped_at.is_some(),
+            mirror_directory: None,
+            mirrored: vec![],
+            markers_file: None,
+            project_finished: false,
+        }
+    }
+
+    pub(crate) fn camera(video_file: &str, video_started_at: ProjectTime) -> CameraRun {
+        CameraRun {
+            video_device: VideoDevice::new("/dev/video0").expect("video device"),
+            video_device_details: None,
+            pipeline: String::new(),
+            video_file: video_file.into(),
+            video_started_at,
+            item_position_seconds: 0.0,
+            segments: vec![],
+            recoveries: vec![],
+        }
+    }
+
+    pub(crate) fn started_at() -> ProjectTime {
+        chrono::DateTime::parse_from_rfc3339("2023-07-31T20:00:00+00:00")
+            .expect("valid date")
+            .with_timezone(&chrono::Local)
+    }
+
+    pub(crate) fn after(seconds: i64) -> ProjectTime {
+        started_at() + chrono::Duration::seconds(seconds)
+    }
+
+    fn project_name() -> ProjectName {
+        "song".parse().expect("project name")
+    }
+
+    #[test]
+    fn every_run_of_the_project_gets_appended() {
+        let directory = tempfile::tempdir().expect("temp dir");
+        let path = SessionManifest::path(directory.path());
+        SessionManifestFile::create(directory.path(), &project_name(), run(Some(after(60))))
+            .expect("first run");
+        SessionManifestFile::create(directory.path(), &project_name(), run(None))
+            .expect("second run");
+        let manifest = SessionManifest::load(&path).expect("readable");
+        assert_eq!(manifest.project_name, "song");
+        assert_eq!(
+            manifest.runs.iter().map(|run| run.finalized).collect_vec(),
+            [true, false]
+        );
+        assert!(!path.with_extension("json.part").exists());
+    }
+
+    #[test]
+    fn updates_hit_the_disk_right_away() {
+        let directory = tempfile::tempdir().expect("temp dir");
+        let manifest = SessionManifestFile::create(directory.path(), &project_name(), run(None))
+            .expect("manifest");
+        manifest
+            .update_run(|run| run.reaper_stop_position_seconds = Some(12.5))
+            .expect("updating");
+        let on_disk = SessionManifest::load(&manifest.path).expect("readable");
+        assert_eq!(on_disk.runs[0].reaper_stop_position_seconds, Some(12.5));
+        assert!(on_disk.runs[0].stopped_at.is_none());
+
+        manifest.finalize().expect("finalizing");
+        let on_disk = SessionManifest::load(&manifest.path).expect("readable");
+        assert!(on_disk.runs[0].finalized);
+        assert!(on_disk.runs[0].stopped_at.is_some());
+    }
+
+    #[test]
+    fn corrupt_manifests_are_moved_aside() {
+        let directory = tempfile::tempdir().expect("temp dir");
+        let path = SessionManifest::path(directory.path());
+        std::fs::write(&path, "{ \"project-name\": \"song\", \"runs\": [").expect("half a file");
+        let manifest = SessionManifestFile::create(directory.path(), &project_name(), run(None))
+            .expect("fresh manifest");
+        assert_eq!(manifest.snapshot().runs.len(), 1);
+        assert_eq!(
+            SessionManifest::load(&path).expect("readable").runs.len(),
+            1
+        );
+        let moved_aside = std::fs::read_dir(directory.path())
+            .expect("listing")
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with("session.json.corrupt-"))
+            .collect_vec();
+        assert_eq!(moved_aside.len(), 1);
+        assert!(
+            std::fs::read_to_string(directory.path().join(&moved_aside[0]))
+                .expect("kept")
+                .ends_with("\"runs\": [")
+        );
+    }
+
+    #[test]
+    fn finished_projects_have_their_recovered_takes_placed() {
+        let mut manifest = SessionManifest {
+            project_name: "song".to_owned(),
+            runs: vec![RecordingRun {
+                cameras: vec![CameraRun {
+                    recoveries: vec![recovered_take()],
+                    ..camera("take.mkv", started_at())
+                }],
+                ..run(Some(after(300)))
+            }],
+        };
+        manifest.mark_project_finished();
+        assert!(manifest.runs[0].project_finished);
+        assert!(manifest.runs[0].cameras[0].recoveries[0].placed_in_project);
+    }
+
+    #[tokio::test]
+    async fn children_and_the_recording_position_are_watched() {
+        let directory = tempfile::tempdir().expect("temp dir");
+        let manifest = SessionManifestFile::create(directory.path(), &project_name(), run(None))
+            .expect("manifest");
+        let reaper_exit_status: ChildExitStatus = Default::default();
+        let reaper_status = Arc::new(RwLock::new(
+            TransportResponse::from_response("TRANSPORT\t5\t42.000000\t0\t0:42.000\t22.1.00").map(
+                |transport| ReaperStatus {
+                    transport,
+                    tracks: vec![],
+                },
+            ),
+        ));
+        let segments = VideoSegments::default();
+        let _watcher = manifest.watch(
+            vec![("reaper".to_owned(), reaper_exit_status.clone())],
+            vec![segments.clone()],
+            reaper_status,
+        );
+        segments.write().extend([
+            VideoSegment {
+                path: "take---00000.mkv".into(),
+                start_seconds: 0.0,
+                end_seconds: Some(600.0),
+            },
+            VideoSegment {
+                path: "take---00001.mkv".into(),
+                start_seconds: 600.0,
+                end_seconds: None,
+            },
+        ]);
+        *reaper_exit_status.write() = Some("exit status: 0".to_owned());
+        for _ in 0..30 {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            let run = SessionManifest::load(&manifest.path)
+                .expect("readable")
+                .runs[0]
+                .clone();
+            if run.children.get("reaper") == Some(&Some("exit status: 0".to_owned())) {
+                assert_eq!(run.reaper_stop_position_seconds, Some(42.0));
+                assert_eq!(run.cameras[0].segments.len(), 2);
+                assert_eq!(
+                    run.video_files,
+                    [
+                        PathBuf::from("take---00000.mkv"),
+                        PathBuf::from("take---00001.mkv")
+                    ]
+                );
+                return;
+            }
+        }
+        panic!("reaper exit never made it into the manifest");
+    }
+
+    pub(crate) fn segment(path: &str, start_seconds: f64) -> VideoSegment {
+        VideoSegment {
+            path: path.into(),
+            start_seconds,
+            end_seconds: None,
+        }
+    }
+
+    #[test]
+    fn single_file_is_always_the_one() {
+        assert_eq!(
+            camera("take.mkv", started_at()).video_at(after(90)),
+            (PathBuf::from("take.mkv"), 90.0)
+        );
+    }
+
+    #[test]
+    fn markers_land_in_the_segment_being_written() {
+        let camera = CameraRun {
+            segments: vec![
+                segment("take-00000.mkv", 0.0),
+                segment("take-00001.mkv", 60.0),
+            ],
+            ..camera("take.mkv", started_at())
+        };
+        assert_eq!(
+            camera.video_at(after(30)),
+            (PathBuf::from("take-00000.mkv"), 30.0)
+        );
+        assert_eq!(
+            camera.video_at(after(75)),
+            (PathBuf::from("take-00001.mkv"), 15.0)
+        );
+    }
+
+    pub(crate) fn recovered_take() -> RecoveredTake {
+        RecoveredTake {
+            gap_started_at: after(100),
+            gap_seconds: 5.0,
+            video_file: "recovered.mkv".into(),
+            video_started_at: after(105),
+            item_position_seconds: 105.0,
+            placed_in_project: false,
+        }
+    }
+
+    #[test]
+    fn markers_after_a_restart_land_in_the_recovered_take() {
+        let camera = CameraRun {
+            recoveries: vec![recovered_take()],
+            ..camera("take.mkv", started_at())
+        };
+        assert_eq!(
+            camera.video_at(after(50)),
+            (PathBuf::from("take.mkv"), 50.0)
+        );
+        assert_eq!(
+            camera.video_at(after(110)),
+            (PathBuf::from("recovered.mkv"), 5.0)
+        );
+    }
+
+    #[test]
+    fn takes_end_where_the_next_one_picked_up() {
+        let run = RecordingRun {
+            cameras: vec![CameraRun {
+                recoveries: vec![recovered_take()],
+                ..camera("take.mkv", started_at())
+            }],
+            ..run(Some(after(300)))
+        };
+        assert_eq!(
+            run.timeline_items(),
+            [
+                TimelineItem {
+                    path: "take.mkv".into(),
+                    position_seconds: 0.0,
+                    length_seconds: Some(100.0),
+                    follows: None,
+                },
+                TimelineItem {
+                    path: "recovered.mkv".into(),
+                    position_seconds: 105.0,
+                    length_seconds: Some(195.0),
+                    follows: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn crashed_runs_end_at_the_last_recording_position() {
+        let run = RecordingRun {
+            cameras: vec![CameraRun {
+                item_position_seconds: 10.0,
+                ..camera("take.mkv", started_at())
+            }],
+            reaper_stop_position_seconds: Some(250.0),
+            ..run(None)
+        };
+        assert_eq!(run.timeline_items()[0].length_seconds, Some(240.0));
+        assert_eq!(
+            RecordingRun {
+                reaper_stop_position_seconds: None,
+                ..run
+            }
+            .timeline_items()[0]
+                .length_seconds,
+            None
+        );
+    }
+
+    #[test]
+    fn every_segment_gets_an_item_after_the_previous_one() {
+        let run = RecordingRun {
+            cameras: vec![CameraRun {
+                segments: vec![
+                    VideoSegment {
+                        end_seconds: Some(60.0),
+                        ..segment("take-00000.mkv", 0.0)
+                    },
+                    // left open by a crash
+                    segment("take-00001.mkv", 60.0),
+                    segment("take-00002.mkv", 125.0),
+                ],
+                item_position_seconds: 10.0,
+                ..camera("take.mkv", started_at())
+            }],
+            ..run(Some(after(200)))
+        };
+        assert_eq!(
+            run.timeline_items(),
+            [
+                TimelineItem {
+                    path: "take-00000.mkv".into(),
+                    position_seconds: 10.0,
+                    length_seconds: Some(60.0),
+                    follows: None,
+                },
+                TimelineItem {
+                    path: "take-00001.mkv".into(),
+                    position_seconds: 70.0,
+                    length_seconds: Some(65.0),
+                    follows: Some("take-00000.mkv".into()),
+                },
+                TimelineItem {
+                    path: "take-00002.mkv".into(),
+                    position_seconds: 135.0,
+                    length_seconds: Some(75.0),
+                    follows: Some("take-00001.mkv".into()),
+                },
+            ]
+        );
+    }
+}
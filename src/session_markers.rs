@@ -0,0 +1,306 @@
+use super::*;
+use crate::{
+    reaper::reaper_web_client::{
+        rea_request::{ActionId, Marker, MarkerResponse, SetProjExtState},
+        ReaperWebClient,
+    },
+    session_manifest::{SessionManifest, SessionManifestFile},
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+use tokio::io::AsyncWriteExt;
+
+/// notes end up in the .rpp as `<EXTSTATE>` under this section
+pub const PROJECT_EXT_STATE_SECTION: &str = clap::crate_name!();
+
+/// one line of the `<video>.markers.jsonl` sidecar
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SessionMarker {
+    pub time: ProjectTime,
+    pub note: String,
+    pub reaper_marker_id: Option<u32>,
+    pub reaper_position_seconds: Option<f64>,
+    pub video_file: PathBuf,
+    pub video_position_seconds: f64,
+}
+
+/// placed in reaper right away, written down once the note is typed in
+#[derive(Debug)]
+pub struct PendingMarker {
+    pub time: ProjectTime,
+    inserted: AbortOnDrop<Result<MarkerResponse>>,
+}
+
+pub fn format_position(seconds: f64) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// next to the first file of the reference camera, one per run
+pub fn sidecar_path(video_file: &Path) -> PathBuf {
+    video_file.with_extension("markers.jsonl")
+}
+
+/// marker notes of a run by REAPER marker id, markers without a note are left out
+pub fn load_notes(sidecar_path: &Path) -> Result<BTreeMap<u32, String>> {
+    match sidecar_path.exists() {
+        false => Ok(Default::default()),
+        true => std::fs::read_to_string(sidecar_path)
+            .wrap_err_with(|| format!("reading {}", sidecar_path.display()))?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str::<SessionMarker>(line).wrap_err("parsing marker"))
+            .filter_map_ok(|marker| {
+                marker
+                    .reaper_marker_id
+                    .filter(|_| !marker.note.is_empty())
+                    .map(|marker_id| (marker_id, marker.note))
+            })
+            .collect(),
+    }
+}
+
+/// notes of the runs not finished yet, REAPER keeps marker ids unique within a project
+pub fn project_notes(manifest: &SessionManifest) -> Result<BTreeMap<u32, String>> {
+    manifest
+        .runs
+        .iter()
+        .filter(|run| !run.project_finished)
+        .filter_map(|run| run.markers_file.as_deref())
+        .map(load_notes)
+        .fold_ok(BTreeMap::new(), |mut notes, run_notes| {
+            notes.extend(run_notes);
+            notes
+        })
+}
+
+/// markers are tied to whichever file of the reference camera was being written at the time
+#[derive(Debug, Clone)]
+pub struct SessionMarkers {
+    pub sidecar_path: PathBuf,
+    manifest: SessionManifestFile,
+    /// position of the reference camera in the manifest run
+    camera_index: usize,
+}
+
+impl SessionMarkers {
+    pub fn new(sidecar_path: PathBuf, manifest: SessionManifestFile, camera_index: usize) -> Self {
+        Self {
+            sidecar_path,
+            manifest,
+            camera_index,
+        }
+    }
+
+    /// the segment or recovered take being recorded at `time` and the position within it
+    pub fn video_at(&self, time: ProjectTime) -> Result<(PathBuf, f64)> {
+        self.manifest
+            .snapshot()
+            .runs
+            .last()
+            .and_then(|run| run.cameras.get(self.camera_index))
+            .map(|camera| camera.video_at(time))
+            .ok_or_else(|| eyre!("reference camera missing from the manifest"))
+    }
+
+    /// markers are listed before and after placing one in a single round trip, the new marker
+    /// is the one missing from the first list
+    pub fn insert(&self, web_client: Arc<ReaperWebClient>) -> PendingMarker {
+        let time = crate::now();
+        let inserted = tokio::task::spawn(async move {
+            web_client
+                .run_batch((Marker, ActionId::InsertMarker, Marker))
+                .await
+                .and_then(|(before, (), after)| {
+                    let existing = before
+                        .0
+                        .iter()
+                        .map(|marker| marker.marker_id)
+                        .collect::<BTreeSet<_>>();
+                    after
+                        .0
+                        .into_iter()
+                        .find(|marker| !existing.contains(&marker.marker_id))
+                        .ok_or_else(|| eyre!("marker was not created"))
+                })
+        })
+        .abort_on_drop();
+        PendingMarker { time, inserted }
+    }
+
+    #[instrument(skip(web_client), ret, err)]
+    pub async fn commit(
+        self,
+        PendingMarker { time, inserted }: PendingMarker,
+        note: String,
+        web_client: Arc<ReaperWebClient>,
+    ) -> Result<SessionMarker> {
+        let reaper_marker = inserted
+            .await
+            .map_err(|e| eyre!("{e:?}"))
+            .and_then(|v| v)
+            .map_err(|message| tracing::warn!(?message, "marker not placed in reaper"))
+            .ok();
+        let (video_file, video_position_seconds) = self.video_at(time)?;
+        let marker = SessionMarker {
+            time,
+            note,
+            reaper_marker_id: reaper_marker.as_ref().map(|marker| marker.marker_id),
+            reaper_position_seconds: reaper_marker.as_ref().map(|marker| marker.position_seconds),
+            video_position_seconds,
+            video_file,
+        };
+        let line = serde_json::to_string(&marker).wrap_err("serializing marker")?;
+        let sidecar_path = self.sidecar_path.clone();
+        tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&sidecar_path)
+            .and_then(
+                |mut file| async move { file.write_all(format!("{line}\n").as_bytes()).await },
+            )
+            .await
+            .wrap_err_with(|| format!("appending to {}", sidecar_path.display()))?;
+        if let (Some(marker_id), false) = (marker.reaper_marker_id, marker.note.is_empty()) {
+            web_client
+                .run_single(SetProjExtState {
+                    section: PROJECT_EXT_STATE_SECTION.to_owned(),
+                    key: format!("marker-{marker_id}"),
+                    value: marker.note.clone(),
+                })
+                .await
+                .wrap_err("storing note in the reaper project")?;
+        }
+        Ok(marker)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        reaper::mock_reaper::tests::mock,
+        session_manifest::tests::{run, started_at},
+    };
+
+    fn markers(directory: &Path) -> SessionMarkers {
+        let manifest = SessionManifestFile::create(
+            directory,
+            &"song".parse().expect("project name"),
+            run(None),
+        )
+        .expect("creating manifest");
+        SessionMarkers::new(sidecar_path(&directory.join("take.mkv")), manifest, 0)
+    }
+
+    fn read_sidecar(markers: &SessionMarkers) -> Vec<SessionMarker> {
+        std::fs::read_to_string(&markers.sidecar_path)
+            .expect("reading sidecar")
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("sidecar line"))
+            .collect()
+    }
+
+    #[test]
+    fn positions_are_hours_minutes_seconds_and_millis() {
+        assert_eq!(format_position(3725.5), "1:02:05.500");
+        assert_eq!(format_position(0.0004), "0:00:00.000");
+        assert_eq!(format_position(-3.0), "0:00:00.000");
+    }
+
+    #[test]
+    fn sidecar_sits_next_to_the_video() {
+        assert_eq!(
+            sidecar_path(Path::new("/videos/take.mov")),
+            PathBuf::from("/videos/take.markers.jsonl")
+        );
+    }
+
+    #[tokio::test]
+    async fn markers_are_appended_to_the_sidecar_and_noted_in_reaper() {
+        let (mock, web_client) = mock().await;
+        let directory = tempfile::tempdir().expect("temp directory");
+        let markers = markers(directory.path());
+        for (note, cursor_seconds) in [("verse", 5.0), ("", 10.0)] {
+            mock.state.write().cursor_seconds = cursor_seconds;
+            let pending = markers.insert(web_client.clone());
+            markers
+                .clone()
+                .commit(pending, note.to_owned(), web_client.clone())
+                .await
+                .expect("committing marker");
+        }
+        let written = read_sidecar(&markers);
+        assert_eq!(
+            written
+                .iter()
+                .map(|marker| (marker.note.as_str(), marker.reaper_marker_id))
+                .collect_vec(),
+            [("verse", Some(1)), ("", Some(2))]
+        );
+        // the camera of `run` started well before the test
+        assert!(written.iter().all(|marker| {
+            marker.video_file == Path::new("take.mkv")
+                && marker.video_position_seconds
+                    >= (marker.time - started_at()).num_seconds() as f64
+        }));
+        // empty notes are not worth storing in the project
+        assert_eq!(
+            mock.state.read().project_ext_state.iter().collect_vec(),
+            [(
+                &(PROJECT_EXT_STATE_SECTION.to_owned(), "marker-1".to_owned()),
+                &"verse".to_owned()
+            )]
+        );
+        assert_eq!(
+            load_notes(&markers.sidecar_path).expect("loading notes"),
+            BTreeMap::from([(1, "verse".to_owned())])
+        );
+    }
+
+    #[tokio::test]
+    async fn the_new_marker_is_found_next_to_an_existing_one() {
+        let (mock, web_client) = mock().await;
+        {
+            let mut state = mock.state.write();
+            state.markers.push(("chorus".to_owned(), 5.0));
+            state.cursor_seconds = 5.0;
+        }
+        let directory = tempfile::tempdir().expect("temp directory");
+        let markers = markers(directory.path());
+        let pending = markers.insert(web_client.clone());
+        let marker = markers
+            .commit(pending, "verse".to_owned(), web_client)
+            .await
+            .expect("committing marker");
+        assert_eq!(marker.reaper_marker_id, Some(2));
+        assert_eq!(marker.reaper_position_seconds, Some(5.0));
+    }
+
+    #[tokio::test]
+    async fn markers_are_written_down_even_without_reaper() {
+        let (mock, web_client) = mock().await;
+        drop(mock);
+        let directory = tempfile::tempdir().expect("temp directory");
+        let markers = markers(directory.path());
+        let pending = markers.insert(web_client.clone());
+        let marker = markers
+            .clone()
+            .commit(pending, "lost".to_owned(), web_client)
+            .await
+            .expect("committing marker");
+        assert_eq!(marker.reaper_marker_id, None);
+        assert_eq!(read_sidecar(&markers)[0].note, "lost");
+        assert!(load_notes(&markers.sidecar_path)
+            .expect("loading notes")
+            .is_empty());
+    }
+}
@@ -0,0 +1,576 @@
+use super::*;
+use crate::{
+    reaper::{reaper_web_client::rea_request::Playstate, ReaperStatus},
+    session_manifest::{
+        CameraRun, ChildExitStatus, MirroredFile, SessionManifest, SessionManifestFile,
+    },
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{Read, Write},
+    path::Path,
+    time::SystemTime,
+};
+use tokio_util::sync::CancellationToken;
+use tui::{
+    style::{Color, Modifier, Style},
+    text::Span,
+    widgets::{Paragraph, Wrap},
+};
+
+const SCAN_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+/// how long the cameras and REAPER get to finalize their files before the last pass
+const FINALIZE_DEADLINE: std::time::Duration = std::time::Duration::from_secs(30);
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+/// whatever REAPER can be set to record
+const AUDIO_EXTENSIONS: [&str; 8] = ["wav", "w64", "rf64", "flac", "aif", "aiff", "ogg", "mp3"];
+
+#[derive(Debug, Clone, Default)]
+pub struct MirrorProgress {
+    pub copied_files: usize,
+    pub copied_bytes: u64,
+    pub pending_files: usize,
+    /// file being copied, bytes done and its size
+    pub current: Option<(PathBuf, u64, u64)>,
+    pub last_error: Option<String>,
+}
+
+impl MirrorProgress {
+    fn summary(&self) -> String {
+        [
+            Some(format!(
+                "mirrored: {} files ({}), pending: {}",
+                self.copied_files,
+                crate::sessions::human_size(self.copied_bytes),
+                self.pending_files
+            )),
+            self.current.as_ref().map(|(path, done, total)| {
+                format!(
+                    "copying {} ({:.0}%)",
+                    path.file_name()
+                        .map(|name| name.to_string_lossy().to_string())
+                        .unwrap_or_default(),
+                    *done as f64 * 100.0 / (*total).max(1) as f64
+                )
+            }),
+            self.last_error.clone(),
+        ]
+        .into_iter()
+        .flatten()
+        .join("  |  ")
+    }
+}
+
+fn is_video(project_directory: &Path, path: &Path) -> bool {
+    path.starts_with(project_directory.join(crate::sessions::VIDEO_RECORDINGS_DIRECTORY))
+}
+
+fn is_audio(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| {
+            AUDIO_EXTENSIONS
+                .iter()
+                .any(|audio| extension.eq_ignore_ascii_case(audio))
+        })
+        .unwrap_or_default()
+}
+
+fn is_recording(project_directory: &Path, path: &Path) -> bool {
+    is_video(project_directory, path) || is_audio(path)
+}
+
+/// closed segments, or every take but the one still being recorded
+fn finished_videos(camera: &CameraRun) -> Vec<PathBuf> {
+    match camera.segments.is_empty() {
+        true => std::iter::once(camera.video_file.clone())
+            .chain(camera.recoveries.iter().map(|take| take.video_file.clone()))
+            .collect_vec()
+            .split_last()
+            .map(|(_, finished)| finished.to_vec())
+            .unwrap_or_default(),
+        false => camera
+            .segments
+            .iter()
+            .filter(|segment| segment.end_seconds.is_some())
+            .map(|segment| segment.path.clone())
+            .collect(),
+    }
+}
+
+/// recordings nobody writes to anymore: videos the manifest knows to be finished, all of REAPER's
+/// audio while it isn't recording and everything once the session has stopped,
+/// with the size and modification time they were seen with
+fn completed_recordings(
+    project_directory: &Path,
+    manifest: &SessionManifest,
+    reaper_recording: bool,
+    stopped: bool,
+) -> Result<Vec<(PathBuf, u64, SystemTime)>> {
+    let finished: BTreeSet<PathBuf> = match manifest.runs.split_last() {
+        Some((current, earlier)) => earlier
+            .iter()
+            .flat_map(|run| run.video_files.iter().cloned())
+            .chain(current.cameras.iter().flat_map(finished_videos))
+            .collect(),
+        None => Default::default(),
+    };
+    crate::sessions::files_in(project_directory).map(|files| {
+        files
+            .into_iter()
+            .filter(|(path, _)| is_recording(project_directory, path))
+            .filter(|(path, _)| {
+                stopped
+                    || match is_video(project_directory, path) {
+                        true => finished.contains(path),
+                        false => !reaper_recording,
+                    }
+            })
+            .filter_map(|(path, metadata)| {
+                let modified = metadata.modified().ok()?;
+                Some((path, metadata.len(), modified))
+            })
+            .collect()
+    })
+}
+
+fn file_checksum(path: &Path) -> Result<u32> {
+    let mut file = std::fs::File::open(path).wrap_err("opening for verification")?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        match file
+            .read(&mut buffer)
+            .wrap_err("reading for verification")?
+        {
+            0 => return Ok(hasher.finalize()),
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+}
+
+/// written next to the destination first and renamed once it reads back with the same checksum
+#[instrument(skip(on_progress), ret, err)]
+fn copy_verified(
+    source: &Path,
+    destination: &Path,
+    mut on_progress: impl FnMut(u64),
+) -> Result<(u64, u32)> {
+    if let Some(parent) = destination.parent() {
+        std::fs::create_dir_all(parent)
+            .wrap_err_with(|| format!("creating {}", parent.display()))?;
+    }
+    let partial = destination.with_file_name(format!(
+        "{}.partial",
+        destination
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    ));
+    let mut input = std::fs::File::open(source).wrap_err("opening source")?;
+    let mut output = std::fs::File::create(&partial).wrap_err("creating destination")?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut copied = 0;
+    loop {
+        let read = input.read(&mut buffer).wrap_err("reading source")?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        output
+            .write_all(&buffer[..read])
+            .wrap_err("writing destination")?;
+        copied += read as u64;
+        on_progress(copied);
+    }
+    output.sync_all().wrap_err("flushing destination")?;
+    let checksum = hasher.finalize();
+    match file_checksum(&partial)? {
+        verified if verified == checksum => std::fs::rename(&partial, destination)
+            .wrap_err("renaming verified copy")
+            .map(|_| (copied, checksum)),
+        verified => {
+            std::fs::remove_file(&partial).ok();
+            bail!("checksum mismatch, {checksum:08x} copied but {verified:08x} read back")
+        }
+    }
+}
+
+/// a destination left over from an earlier run only counts when it reads back with the
+/// checksum of the source, anything else gets copied again
+#[instrument(skip(on_progress), ret, err)]
+fn mirror_file(
+    source: &Path,
+    destination: &Path,
+    on_progress: impl FnMut(u64),
+) -> Result<(u64, u32)> {
+    let size = source.metadata().wrap_err("reading source size")?.len();
+    if destination.metadata().map(|metadata| metadata.len()).ok() == Some(size) {
+        let checksum = file_checksum(source)?;
+        match file_checksum(destination)? {
+            existing if existing == checksum => return Ok((size, checksum)),
+            existing => tracing::warn!(
+                ?destination,
+                "existing copy reads {existing:08x} instead of {checksum:08x}, copying again"
+            ),
+        }
+    }
+    copy_verified(source, destination, on_progress)
+}
+
+/// REAPER is unreachable or writing, either way its audio files aren't done
+fn reaper_recording(reaper_status: &RwLock<Result<ReaperStatus>>) -> bool {
+    reaper_status
+        .read()
+        .as_ref()
+        .map(|status| {
+            matches!(
+                status.transport.playstate,
+                Playstate::Recording | Playstate::RecordPaused
+            )
+        })
+        .unwrap_or(true)
+}
+
+/// copies finished recordings of the project to a second disk while the session runs,
+/// dropping it stops the session for the mirror: once REAPER has closed and the cameras have
+/// finalized their files everything left gets copied and `drained` is cancelled
+#[derive(Debug)]
+pub struct SessionMirror {
+    destination: PathBuf,
+    progress: Arc<RwLock<MirrorProgress>>,
+    stop: CancellationToken,
+    drained: CancellationToken,
+}
+
+impl SessionMirror {
+    /// recordings end up in `<mirror_directory>/<project name>/`, same layout as the project,
+    /// `cameras` are the exit statuses of the capture pipelines
+    pub fn new(
+        project_directory: PathBuf,
+        mirror_directory: PathBuf,
+        project_name: &ProjectName,
+        manifest: SessionManifestFile,
+        reaper_status: Arc<RwLock<Result<ReaperStatus>>>,
+        reaper_closed: CancellationToken,
+        cameras: Vec<ChildExitStatus>,
+        notify: ProcessEventBus,
+    ) -> Self {
+        let destination = mirror_directory.join(project_name.as_ref());
+        let progress = Arc::new(RwLock::new(MirrorProgress::default()));
+        let stop = CancellationToken::new();
+        let drained = CancellationToken::new();
+        // not aborted on drop, the last recordings are copied after the session stops
+        tokio::task::spawn({
+            to_owned![destination, progress, stop, drained];
+            async move {
+                let mut interval = crate::process::app_interval(SCAN_INTERVAL);
+                // path to the size and modification time it was mirrored with
+                let mut mirrored = BTreeMap::<PathBuf, (u64, SystemTime)>::new();
+                loop {
+                    let stopped = tokio::select! {
+                        _ = interval.tick() => false,
+                        _ = stop.cancelled() => true,
+                    };
+                    if stopped {
+                        let finalized = async {
+                            reaper_closed.cancelled().await;
+                            while cameras.iter().any(|camera| camera.read().is_none()) {
+                                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                            }
+                        };
+                        if tokio::time::timeout(FINALIZE_DEADLINE, finalized)
+                            .await
+                            .is_err()
+                        {
+                            tracing::warn!(
+                                "recordings not finalized in time, mirroring them as they are"
+                            );
+                        }
+                    }
+                    let completed = {
+                        let manifest = manifest.snapshot();
+                        let reaper_recording = reaper_recording(&reaper_status);
+                        to_owned![project_directory];
+                        tokio::task::spawn_blocking(move || {
+                            completed_recordings(
+                                &project_directory,
+                                &manifest,
+                                reaper_recording,
+                                stopped,
+                            )
+                        })
+                    }
+                    .await
+                    .wrap_err("scanning thread crashed")
+                    .and_then(|completed| completed);
+                    let pending = match completed {
+                        Ok(completed) => completed
+                            .into_iter()
+                            .filter(|(path, size, modified)| {
+                                mirrored.get(path) != Some(&(*size, *modified))
+                            })
+                            .filter_map(|(source, size, modified)| {
+                                let target =
+                                    destination.join(source.strip_prefix(&project_directory).ok()?);
+                                Some((source, target, size, modified))
+                            })
+                            .collect_vec(),
+                        Err(message) => {
+                            tracing::warn!(?message, "scanning recordings failed");
+                            progress.write().last_error = Some(format!("{message:#}"));
+                            match stopped {
+                                true => break,
+                                false => continue,
+                            }
+                        }
+                    };
+                    progress.write().pending_files = pending.len();
+                    for (source, target, size, modified) in pending {
+                        progress.write().current = Some((source.clone(), 0, size));
+                        notify.send(ProcessEvent::NewInput).ok();
+                        let copied = {
+                            to_owned![source, target, progress];
+                            tokio::task::spawn_blocking(move || {
+                                mirror_file(&source, &target, |done| {
+                                    if let Some((_, copied, _)) = progress.write().current.as_mut()
+                                    {
+                                        *copied = done;
+                                    }
+                                })
+                            })
+                        }
+                        .await
+                        .wrap_err("copying thread crashed")
+                        .and_then(|copied| copied);
+                        {
+                            let mut progress = progress.write();
+                            progress.current = None;
+                            progress.pending_files -= 1;
+                        }
+                        match copied {
+                            Ok((size_bytes, crc32)) => {
+                                {
+                                    let mut progress = progress.write();
+                                    progress.copied_files += 1;
+                                    progress.copied_bytes += size_bytes;
+                                    progress.last_error = None;
+                                }
+                                if let Err(message) = manifest.update_run(|run| {
+                                    run.mirrored.push(MirroredFile {
+                                        source: source.clone(),
+                                        destination: target.clone(),
+                                        size_bytes,
+                                        crc32,
+                                        mirrored_at: crate::now(),
+                                    })
+                                }) {
+                                    tracing::warn!(?message, "mirrored file not in the manifest");
+                                }
+                                mirrored.insert(source, (size, modified));
+                            }
+                            Err(message) => {
+                                tracing::error!(?message, ?source, "mirroring failed");
+                                progress.write().last_error =
+                                    Some(format!("{}: {message:#}", source.display()));
+                            }
+                        }
+                        notify.send(ProcessEvent::NewInput).ok();
+                    }
+                    if stopped {
+                        break;
+                    }
+                }
+                drained.cancel();
+            }
+        });
+        Self {
+            destination,
+            progress,
+            stop,
+            drained,
+        }
+    }
+
+    /// cancelled once the mirror is done after the session has stopped
+    pub fn drained(&self) -> CancellationToken {
+        self.drained.clone()
+    }
+}
+
+impl Drop for SessionMirror {
+    fn drop(&mut self) {
+        self.stop.cancel();
+    }
+}
+
+impl RenderToTerm for SessionMirror {
+    fn render_to_term<B: Backend>(
+        &mut self,
+        f: &mut Frame<B>,
+        rect: tui::layout::Rect,
+    ) -> Result<()> {
+        let progress = self.progress.read().clone();
+        let color = match progress.last_error {
+            Some(_) => Color::Red,
+            None => Color::Magenta,
+        };
+        let block = Block::default().borders(Borders::ALL).title(Span::styled(
+            format!("mirror: [{}]", self.destination.display()),
+            Style::default().fg(color).add_modifier(Modifier::BOLD),
+        ));
+        f.render_widget(
+            Paragraph::new(progress.summary())
+                .block(block)
+                .wrap(Wrap { trim: false }),
+            rect,
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::session_manifest::{
+        tests::{camera, recovered_take, run, segment, started_at},
+        RecordingRun, VideoSegment,
+    };
+
+    fn write(path: &Path, content: &[u8]) {
+        std::fs::create_dir_all(path.parent().expect("file in a directory"))
+            .expect("creating directory");
+        std::fs::write(path, content).expect("writing file");
+    }
+
+    #[test]
+    fn recordings_are_videos_and_audio() {
+        let project = Path::new("/sessions/project");
+        let videos = project.join(crate::sessions::VIDEO_RECORDINGS_DIRECTORY);
+        assert!(is_recording(project, &videos.join("take---00000.mkv")));
+        assert!(is_recording(project, &project.join("audio/vocals.WAV")));
+        assert!(is_recording(project, &project.join("bass.flac")));
+        assert!(!is_recording(project, &project.join("project.RPP")));
+        assert!(!is_recording(project, &project.join("session.json")));
+    }
+
+    #[test]
+    fn copies_read_back_with_the_same_checksum() {
+        let directory = tempfile::tempdir().expect("creating tempdir");
+        let source = directory.path().join("take.mkv");
+        let destination = directory
+            .path()
+            .join("mirror")
+            .join("nested")
+            .join("take.mkv");
+        let content = vec![7u8; CHUNK_SIZE + 1];
+        write(&source, &content);
+        let mut progress = vec![];
+        assert_eq!(
+            copy_verified(&source, &destination, |done| progress.push(done)).expect("copying"),
+            (content.len() as u64, crc32fast::hash(&content))
+        );
+        assert_eq!(
+            std::fs::read(&destination).expect("reading the copy"),
+            content
+        );
+        assert_eq!(progress, vec![CHUNK_SIZE as u64, content.len() as u64]);
+        assert!(!destination.with_file_name("take.mkv.partial").exists());
+    }
+
+    #[test]
+    fn existing_destinations_are_verified_before_they_count() {
+        let directory = tempfile::tempdir().expect("creating tempdir");
+        let source = directory.path().join("take.mkv");
+        let destination = directory.path().join("mirror").join("take.mkv");
+        write(&source, b"the real take");
+        write(&destination, b"the real take");
+        let mut copied = false;
+        assert_eq!(
+            mirror_file(&source, &destination, |_| copied = true).expect("verifying"),
+            (13, crc32fast::hash(b"the real take"))
+        );
+        assert!(!copied, "an identical copy is left alone");
+
+        write(&destination, b"a broken copy");
+        assert_eq!(
+            mirror_file(&source, &destination, |_| copied = true).expect("copying again"),
+            (13, crc32fast::hash(b"the real take"))
+        );
+        assert!(copied);
+        assert_eq!(
+            std::fs::read(&destination).expect("reading the copy"),
+            b"the real take"
+        );
+    }
+
+    #[test]
+    fn takes_are_finished_once_a_restart_picked_up() {
+        assert_eq!(
+            finished_videos(&camera("take.mkv", started_at())),
+            Vec::<PathBuf>::new()
+        );
+        assert_eq!(
+            finished_videos(&CameraRun {
+                recoveries: vec![recovered_take()],
+                ..camera("take.mkv", started_at())
+            }),
+            vec![PathBuf::from("take.mkv")]
+        );
+    }
+
+    #[test]
+    fn recordings_are_completed_by_the_manifest_reaper_and_the_stop() {
+        let directory = tempfile::tempdir().expect("creating tempdir");
+        let project = directory.path();
+        let videos = project.join(crate::sessions::VIDEO_RECORDINGS_DIRECTORY);
+        let (closed, open) = (
+            videos.join("take---00000.mkv"),
+            videos.join("take---00001.mkv"),
+        );
+        let audio = project.join("audio").join("vocals.wav");
+        [&closed, &open, &audio, &project.join("project.RPP")]
+            .into_iter()
+            .for_each(|path| write(path, b"recorded"));
+        let manifest = SessionManifest {
+            project_name: "project".to_owned(),
+            runs: vec![RecordingRun {
+                cameras: vec![CameraRun {
+                    segments: vec![
+                        VideoSegment {
+                            end_seconds: Some(60.0),
+                            ..segment(&closed.display().to_string(), 0.0)
+                        },
+                        segment(&open.display().to_string(), 60.0),
+                    ],
+                    ..camera("take.mkv", started_at())
+                }],
+                ..run(None)
+            }],
+        };
+        let completed = |reaper_recording, stopped| {
+            completed_recordings(project, &manifest, reaper_recording, stopped)
+                .expect("scanning the project")
+                .into_iter()
+                .map(|(path, size, _)| {
+                    assert_eq!(size, 8);
+                    path
+                })
+                .sorted()
+                .collect_vec()
+        };
+        assert_eq!(completed(true, false), vec![closed.clone()]);
+        assert_eq!(
+            completed(false, false),
+            [audio.clone(), closed.clone()]
+                .into_iter()
+                .sorted()
+                .collect_vec()
+        );
+        assert_eq!(
+            completed(true, true),
+            [audio, closed, open].into_iter().sorted().collect_vec()
+        );
+    }
+}
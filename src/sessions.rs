@@ -0,0 +1,613 @@
+use super::*;
+use crate::{
+    session_manifest::{RecordingRun, SessionManifest},
+    video_capture::capture_settings::Container,
+};
+use std::path::Path;
+use strum::IntoEnumIterator;
+use tokio::process::Command;
+
+pub const VIDEO_RECORDINGS_DIRECTORY: &str = "video-recordings";
+
+/// inspect what's already under the sessions directory
+#[derive(Args, Debug)]
+pub struct Args {
+    #[command(flatten)]
+    sessions_directory: config::SessionsDirectoryArgs,
+    #[command(subcommand)]
+    mode: Option<Mode>,
+}
+
+#[derive(Subcommand, Debug, Default)]
+pub enum Mode {
+    /// one line per project, most recently modified first
+    #[default]
+    List,
+    /// every take of a single project
+    Show { project_name: ProjectName },
+    /// everything (or a single project) as json, for the archive tooling
+    Json { project_name: Option<ProjectName> },
+    /// what couldn't be done while REAPER was running: add takes recorded after a capture
+    /// restart, name the markers and trim the video items, REAPER must be closed
+    #[command(alias = "place-recovered-takes")]
+    FinishProject { project_name: ProjectName },
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct VideoTake {
+    pub path: PathBuf,
+    pub size_bytes: u64,
+    pub duration_seconds: Option<f64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SessionSummary {
+    pub project_name: String,
+    pub directory: PathBuf,
+    pub project_file: Option<PathBuf>,
+    pub takes: Vec<VideoTake>,
+    pub disk_usage_bytes: u64,
+    pub modified_at: Option<ProjectTime>,
+    pub manifest: Option<SessionManifest>,
+}
+
+pub(crate) fn human_size(bytes: u64) -> String {
+    byte_unit::Byte::from_bytes(bytes as _)
+        .get_appropriate_unit(true)
+        .to_string()
+}
+
+pub(crate) fn files_in(directory: &Path) -> Result<Vec<(PathBuf, std::fs::Metadata)>> {
+    std::fs::read_dir(directory)
+        .wrap_err_with(|| format!("reading {}", directory.display()))?
+        .map(|entry| {
+            entry
+                .and_then(|entry| entry.metadata().map(|metadata| (entry.path(), metadata)))
+                .wrap_err("reading directory entry")
+        })
+        .map_ok(|(path, metadata)| match metadata.is_dir() {
+            true => files_in(&path),
+            false => Ok(vec![(path, metadata)]),
+        })
+        .flatten_ok()
+        .flatten_ok()
+        .collect()
+}
+
+/// checked once, takes missing from the manifest get no duration without it
+fn ffprobe_available() -> bool {
+    static AVAILABLE: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+    *AVAILABLE.get_or_init(|| {
+        let available = std::process::Command::new("ffprobe")
+            .arg("-version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map(|status| status.success())
+            .unwrap_or_default();
+        if !available {
+            eprintln!("ffprobe not found, durations not in the manifest are shown as '?'");
+        }
+        available
+    })
+}
+
+/// falls back to ffprobe when the manifest doesn't know
+async fn probe_duration(path: &Path) -> Result<f64> {
+    if !ffprobe_available() {
+        bail!("ffprobe is not installed");
+    }
+    Command::new("ffprobe")
+        .args(["-v", "error", "-show_entries", "format=duration", "-of"])
+        .arg("default=noprint_wrappers=1:nokey=1")
+        .arg(path)
+        .output()
+        .await
+        .wrap_err("running ffprobe")
+        .and_then(|output| {
+            output
+                .status
+                .success()
+                .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+                .ok_or_else(|| {
+                    eyre!(
+                        "ffprobe failed: {}",
+                        String::from_utf8_lossy(&output.stderr)
+                    )
+                })
+        })
+        .and_then(|duration| {
+            duration
+                .parse()
+                .wrap_err_with(|| format!("bad duration '{duration}'"))
+        })
+}
+
+/// only finalized runs know when the video stopped
+fn manifest_duration(manifest: Option<&SessionManifest>, path: &Path) -> Option<f64> {
+    manifest?
+        .runs
+        .iter()
+        .find(|run| run.video_files.iter().any(|file| file == path))
+        .and_then(|run| {
+            run.cameras
+                .iter()
+                .flat_map(|camera| camera.segments.iter())
+                .find(|segment| segment.path == path)
+                .map(|segment| {
+                    segment
+                        .end_seconds
+                        .map(|end_seconds| end_seconds - segment.start_seconds)
+                })
+                .unwrap_or_else(|| run_duration(run, path))
+        })
+}
+
+fn run_duration(
+    RecordingRun {
+        cameras,
+        video_started_at,
+        stopped_at,
+        ..
+    }: &RecordingRun,
+    path: &Path,
+) -> Option<f64> {
+    let video_started_at = cameras
+        .iter()
+        .find(|camera| camera.video_file == path)
+        .map(|camera| camera.video_started_at)
+        .unwrap_or(*video_started_at);
+    stopped_at.map(|stopped_at| (stopped_at - video_started_at).num_milliseconds() as f64 / 1000.0)
+}
+
+impl SessionSummary {
+    #[instrument(ret, err, level = "debug")]
+    pub async fn scan(directory: PathBuf) -> Result<Self> {
+        let project_name = directory
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .ok_or_else(|| eyre!("{} has no name", directory.display()))?;
+        let files = {
+            to_owned![directory];
+            tokio::task::spawn_blocking(move || files_in(&directory))
+                .await
+                .wrap_err("thread crashed")
+                .and_then(|v| v)?
+        };
+        let manifest_path = SessionManifest::path(&directory);
+        let manifest = manifest_path
+            .exists()
+            .then(|| SessionManifest::load(&manifest_path))
+            .transpose()
+            .map_err(|message| tracing::warn!(?message, "ignoring broken manifest"))
+            .ok()
+            .flatten();
+        let project_file = Some(directory.join(format!("{project_name}.rpp")))
+            .filter(|path| path.is_file())
+            .or_else(|| {
+                files
+                    .iter()
+                    .map(|(path, _)| path)
+                    .filter(|path| path.parent() == Some(directory.as_path()))
+                    .find(|path| {
+                        path.extension()
+                            .map(|extension| extension.eq_ignore_ascii_case("rpp"))
+                            .unwrap_or_default()
+                    })
+                    .cloned()
+            });
+        let recordings = directory.join(VIDEO_RECORDINGS_DIRECTORY);
+        let takes = futures::future::join_all(
+            files
+                .iter()
+                .filter(|(path, _)| {
+                    path.parent() == Some(recordings.as_path())
+                        && path
+                            .extension()
+                            .and_then(|extension| extension.to_str())
+                            .map(|extension| {
+                                Container::iter()
+                                    .any(|container| container.extension() == extension)
+                            })
+                            .unwrap_or_default()
+                })
+                .sorted_by_key(|(path, _)| path.clone())
+                .map(|(path, metadata)| {
+                    let known = manifest_duration(manifest.as_ref(), path);
+                    async move {
+                        let duration_seconds = match known {
+                            Some(known) => Some(known),
+                            None => probe_duration(path)
+                                .await
+                                .map_err(|message| {
+                                    tracing::debug!(?message, ?path, "unknown duration")
+                                })
+                                .ok(),
+                        };
+                        VideoTake {
+                            path: path.clone(),
+                            size_bytes: metadata.len(),
+                            duration_seconds,
+                        }
+                    }
+                }),
+        )
+        .await;
+        Ok(Self {
+            disk_usage_bytes: files.iter().map(|(_, metadata)| metadata.len()).sum(),
+            modified_at: files
+                .iter()
+                .filter_map(|(_, metadata)| metadata.modified().ok())
+                .max()
+                .map(ProjectTime::from),
+            project_name,
+            directory,
+            project_file,
+            takes,
+            manifest,
+        })
+    }
+
+    pub fn total_duration_seconds(&self) -> f64 {
+        self.takes
+            .iter()
+            .filter_map(|take| take.duration_seconds)
+            .sum()
+    }
+
+    fn modified(&self) -> String {
+        self.modified_at
+            .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_else(|| "-".to_owned())
+    }
+
+    fn list_line(&self) -> String {
+        format!(
+            "{:<32} {:>3} takes {:>14} {:>12}  {}{}",
+            self.project_name,
+            self.takes.len(),
+            session_markers::format_position(self.total_duration_seconds()),
+            human_size(self.disk_usage_bytes),
+            self.modified(),
+            match self.project_file {
+                Some(_) => "",
+                None => "  (no .rpp)",
+            },
+        )
+    }
+
+    fn details(&self) -> String {
+        [
+            format!("project:    {}", self.project_name),
+            format!("directory:  {}", self.directory.display()),
+            format!(
+                "reaper:     {}",
+                self.project_file
+                    .as_ref()
+                    .map(|path| path.display().to_string())
+                    .unwrap_or_else(|| "missing".to_owned())
+            ),
+            format!("disk usage: {}", human_size(self.disk_usage_bytes)),
+            format!("modified:   {}", self.modified()),
+            format!(
+                "manifest:   {}",
+                self.manifest
+                    .as_ref()
+                    .map(|manifest| format!("{} run(s)", manifest.runs.len()))
+                    .unwrap_or_else(|| "none".to_owned())
+            ),
+            format!("takes:      {}", self.takes.len()),
+        ]
+        .into_iter()
+        .chain(self.takes.iter().map(|take| {
+            format!(
+                "  {}  {:>14}  {:>12}",
+                take.path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+                take.duration_seconds
+                    .map(session_markers::format_position)
+                    .unwrap_or_else(|| "?".to_owned()),
+                human_size(take.size_bytes),
+            )
+        }))
+        .join("\n")
+    }
+}
+
+/// projects that couldn't be read are returned next to the rest instead of failing the listing
+#[instrument(ret, err)]
+pub async fn scan_all(
+    sessions_directory: &SessionsDirectory,
+) -> Result<(Vec<SessionSummary>, Vec<eyre::Report>)> {
+    let directory = sessions_directory.as_ref().as_ref();
+    let projects = std::fs::read_dir(directory)
+        .wrap_err_with(|| format!("reading {}", directory.display()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_dir())
+        .collect_vec();
+    let (sessions, unreadable): (Vec<_>, Vec<_>) =
+        futures::future::join_all(projects.into_iter().map(|project| {
+            let display = project.display().to_string();
+            SessionSummary::scan(project)
+                .map(move |session| session.wrap_err_with(|| format!("skipping {display}")))
+        }))
+        .await
+        .into_iter()
+        .partition_result();
+    Ok((
+        sessions
+            .into_iter()
+            .sorted_by(|one, other| other.modified_at.cmp(&one.modified_at))
+            .collect(),
+        unreadable,
+    ))
+}
+
+/// on stderr so that the json output stays parseable
+fn report_unreadable(unreadable: &[eyre::Report]) {
+    unreadable
+        .iter()
+        .for_each(|message| eprintln!("{message:#}"));
+}
+
+/// unlike [directory_shenanigans::project_directory] a mistyped name doesn't create anything
+fn existing_project_directory(
+    sessions_directory: &SessionsDirectory,
+    project_name: &ProjectName,
+) -> Result<PathBuf> {
+    let directory = sessions_directory
+        .as_ref()
+        .as_ref()
+        .join(project_name.as_ref());
+    match directory.is_dir() {
+        true => Ok(directory),
+        false => bail!(
+            "no project named '{project_name}' in {}",
+            sessions_directory.as_ref().as_ref().display()
+        ),
+    }
+}
+
+async fn scan_one(
+    sessions_directory: &SessionsDirectory,
+    project_name: &ProjectName,
+) -> Result<SessionSummary> {
+    SessionSummary::scan(existing_project_directory(
+        sessions_directory,
+        project_name,
+    )?)
+    .await
+}
+
+pub async fn run(
+    Args {
+        sessions_directory,
+        mode,
+    }: Args,
+    config: Option<PathBuf>,
+) -> Result<()> {
+    let sessions_directory = sessions_directory.resolve(config)?;
+    match mode.unwrap_or_default() {
+        Mode::List => scan_all(&sessions_directory)
+            .await
+            .map(|(sessions, unreadable)| {
+                sessions
+                    .iter()
+                    .for_each(|session| println!("{}", session.list_line()));
+                report_unreadable(&unreadable);
+            }),
+        Mode::Show { project_name } => scan_one(&sessions_directory, &project_name)
+            .await
+            .map(|session| println!("{}", session.details())),
+        Mode::Json { project_name } => match project_name {
+            Some(project_name) => {
+                scan_one(&sessions_directory, &project_name)
+                    .await
+                    .and_then(|session| {
+                        serde_json::to_string_pretty(&session).wrap_err("serializing session")
+                    })
+            }
+            None => scan_all(&sessions_directory)
+                .await
+                .and_then(|(sessions, unreadable)| {
+                    report_unreadable(&unreadable);
+                    serde_json::to_string_pretty(&sessions).wrap_err("serializing sessions")
+                }),
+        }
+        .map(|json| println!("{json}")),
+        Mode::FinishProject { project_name } => finish_project(sessions_directory, &project_name),
+    }
+}
+
+fn finish_project(sessions_directory: SessionsDirectory, project_name: &ProjectName) -> Result<()> {
+    let directory = existing_project_directory(&sessions_directory, project_name)?;
+    let project_file = directory.join(format!("{project_name}.rpp"));
+    if !project_file.is_file() {
+        bail!("{} does not exist", project_file.display());
+    }
+    let manifest_path = SessionManifest::path(&directory);
+    let mut manifest = SessionManifest::load(&manifest_path)?;
+    let backup = crate::dynamic_template::finish_project(&project_file, &manifest)?;
+    manifest.mark_project_finished();
+    manifest.save(&manifest_path)?;
+    match backup {
+        Some(backup) => println!("original kept in {}", backup.display()),
+        None => println!("nothing left to finish"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session_manifest::{
+        tests::{after, camera, run},
+        CameraRun, VideoSegment,
+    };
+
+    fn sessions_directory(root: &Path) -> SessionsDirectory {
+        root.display()
+            .to_string()
+            .parse()
+            .expect("existing sessions directory")
+    }
+
+    /// project with a .rpp, two takes and a manifest that knows about the first one
+    fn project(root: &Path, name: &str) -> PathBuf {
+        let directory = root.join(name);
+        let recordings = directory.join(VIDEO_RECORDINGS_DIRECTORY);
+        std::fs::create_dir_all(&recordings).expect("recordings directory");
+        std::fs::write(directory.join(format!("{name}.rpp")), "<REAPER_PROJECT\n>")
+            .expect("project file");
+        std::fs::write(recordings.join("take-1.mkv"), [0; 1024]).expect("first take");
+        std::fs::write(recordings.join("take-2.mkv"), [0; 512]).expect("second take");
+        std::fs::write(recordings.join("notes.txt"), "not a take").expect("notes");
+        let manifest = SessionManifest {
+            project_name: name.to_owned(),
+            runs: vec![RecordingRun {
+                video_files: vec![recordings.join("take-1.mkv")],
+                ..run(Some(after(90)))
+            }],
+        };
+        std::fs::write(
+            SessionManifest::path(&directory),
+            serde_json::to_string(&manifest).expect("serializing manifest"),
+        )
+        .expect("manifest");
+        directory
+    }
+
+    #[test]
+    fn only_finalized_runs_know_the_duration() {
+        let manifest = SessionManifest {
+            project_name: "song".to_owned(),
+            runs: vec![run(Some(after(90))), run(None)],
+        };
+        assert_eq!(
+            manifest_duration(Some(&manifest), Path::new("take.mkv")),
+            Some(90.0)
+        );
+        assert_eq!(
+            manifest_duration(Some(&manifest), Path::new("other.mkv")),
+            None
+        );
+        assert_eq!(manifest_duration(None, Path::new("take.mkv")), None);
+    }
+
+    #[test]
+    fn cameras_that_started_late_are_shorter() {
+        let manifest = SessionManifest {
+            project_name: "song".to_owned(),
+            runs: vec![RecordingRun {
+                cameras: vec![
+                    camera("first.mkv", after(0)),
+                    camera("second.mkv", after(2)),
+                ],
+                video_files: vec!["first.mkv".into(), "second.mkv".into()],
+                ..run(Some(after(90)))
+            }],
+        };
+        assert_eq!(
+            manifest_duration(Some(&manifest), Path::new("first.mkv")),
+            Some(90.0)
+        );
+        assert_eq!(
+            manifest_duration(Some(&manifest), Path::new("second.mkv")),
+            Some(88.0)
+        );
+    }
+
+    #[test]
+    fn segments_know_their_own_duration() {
+        let segment = |path: &str, start_seconds: f64, end_seconds: Option<f64>| VideoSegment {
+            path: path.into(),
+            start_seconds,
+            end_seconds,
+        };
+        let manifest = SessionManifest {
+            project_name: "song".to_owned(),
+            runs: vec![RecordingRun {
+                cameras: vec![CameraRun {
+                    segments: vec![
+                        segment("take---00000.mkv", 0.0, Some(600.0)),
+                        segment("take---00001.mkv", 600.0, None),
+                    ],
+                    ..camera("take---00000.mkv", after(0))
+                }],
+                video_files: vec!["take---00000.mkv".into(), "take---00001.mkv".into()],
+                ..run(Some(after(700)))
+            }],
+        };
+        assert_eq!(
+            manifest_duration(Some(&manifest), Path::new("take---00000.mkv")),
+            Some(600.0)
+        );
+        // the pipeline crashed before closing it
+        assert_eq!(
+            manifest_duration(Some(&manifest), Path::new("take---00001.mkv")),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn projects_are_scanned_for_takes() {
+        let root = tempfile::tempdir().expect("temp dir");
+        let directory = project(root.path(), "song");
+        let session = SessionSummary::scan(directory.clone())
+            .await
+            .expect("scanning");
+        assert_eq!(session.project_name, "song");
+        assert_eq!(session.project_file, Some(directory.join("song.rpp")));
+        assert_eq!(session.takes.len(), 2);
+        assert_eq!(session.takes[0].size_bytes, 1024);
+        assert_eq!(session.takes[0].duration_seconds, Some(90.0));
+        assert!(session.disk_usage_bytes > 1024 + 512);
+        assert!(session.manifest.is_some());
+        assert!(session.details().contains("take-1.mkv"));
+        assert!(!session.list_line().contains("(no .rpp)"));
+    }
+
+    #[tokio::test]
+    async fn every_project_gets_listed() {
+        let root = tempfile::tempdir().expect("temp dir");
+        project(root.path(), "song");
+        std::fs::create_dir(root.path().join("empty")).expect("empty project");
+        let (sessions, unreadable) = scan_all(&sessions_directory(root.path()))
+            .await
+            .expect("scanning");
+        assert!(unreadable.is_empty());
+        assert_eq!(
+            sessions
+                .iter()
+                .map(|session| session.project_name.as_str())
+                .sorted()
+                .collect_vec(),
+            ["empty", "song"]
+        );
+        let empty = sessions
+            .iter()
+            .find(|session| session.project_name == "empty")
+            .expect("empty project");
+        assert!(empty.list_line().contains("(no .rpp)"));
+        assert!(scan_one(
+            &sessions_directory(root.path()),
+            &"no-such-song".parse().expect("project name")
+        )
+        .await
+        .is_err());
+    }
+
+    #[test]
+    fn mistyped_project_names_leave_nothing_behind() {
+        let root = tempfile::tempdir().expect("temp dir");
+        let message = finish_project(
+            sessions_directory(root.path()),
+            &"no-such-song".parse().expect("project name"),
+        )
+        .expect_err("project does not exist");
+        assert!(format!("{message}").contains("no project named 'no-such-song'"));
+        assert!(!root.path().join("no-such-song").exists());
+    }
+}
@@ -1,3 +1,5 @@
+use std::{collections::VecDeque, path::Path, time::Instant};
+use tokio_util::sync::CancellationToken;
 use tui::{
     style::{Color, Modifier, Style},
     text::Span,
@@ -5,49 +7,240 @@ use tui::{
 };
 
 use super::*;
+
+/// the write rate is averaged over this many samples, one per second
+const RATE_WINDOW: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskSpace {
+    pub available_bytes: u64,
+    pub total_bytes: u64,
+}
+
+impl DiskSpace {
+    #[instrument(err, level = "DEBUG")]
+    pub fn of(directory: &Path) -> Result<Self> {
+        nix::sys::statvfs::statvfs(directory)
+            .wrap_err_with(|| format!("statvfs {}", directory.display()))
+            .map(|stat| {
+                let fragment_size = stat.fragment_size() as u64;
+                Self {
+                    available_bytes: stat.blocks_available() as u64 * fragment_size,
+                    total_bytes: stat.blocks() as u64 * fragment_size,
+                }
+            })
+    }
+
+    pub fn available_fraction(&self) -> f64 {
+        match self.total_bytes {
+            0 => 0.0,
+            total => self.available_bytes as f64 / total as f64,
+        }
+    }
+}
+
+const GIGABYTE: u64 = 1024 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DiskLevel {
+    #[default]
+    Fine,
+    /// the header flashes
+    Warning,
+    /// REAPER and the capture pipelines get stopped while there's still room to finalize the files
+    Critical,
+}
+
+/// how much free space is left when the engineer gets warned and when recording stops
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LowDiskThresholds {
+    pub warning_bytes: u64,
+    pub critical_bytes: u64,
+}
+
+impl LowDiskThresholds {
+    pub const DEFAULT_WARNING_GIGABYTES: u32 = 50;
+    pub const DEFAULT_CRITICAL_GIGABYTES: u32 = 10;
+
+    pub fn from_gigabytes(warning: u32, critical: u32) -> Result<Self> {
+        match critical < warning {
+            true => Ok(Self {
+                warning_bytes: warning as u64 * GIGABYTE,
+                critical_bytes: critical as u64 * GIGABYTE,
+            }),
+            false => bail!(
+                "critical threshold ({critical}GB) must be below the warning one ({warning}GB)"
+            ),
+        }
+    }
+
+    pub fn level(&self, disk: DiskSpace) -> DiskLevel {
+        match disk.available_bytes {
+            available if available <= self.critical_bytes => DiskLevel::Critical,
+            available if available <= self.warning_bytes => DiskLevel::Warning,
+            _ => DiskLevel::Fine,
+        }
+    }
+}
+
+/// everything below the tracked paths, files that disappear in the meantime are skipped
+fn bytes_in(tracked: &[PathBuf]) -> u64 {
+    tracked
+        .iter()
+        .map(|path| match path.is_dir() {
+            true => crate::sessions::files_in(path)
+                .map(|files| files.iter().map(|(_, metadata)| metadata.len()).sum())
+                .unwrap_or_default(),
+            false => path
+                .metadata()
+                .map(|metadata| metadata.len())
+                .unwrap_or_default(),
+        })
+        .sum()
+}
+
+#[derive(Debug, Default)]
+struct WriteRate {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl WriteRate {
+    /// bytes per second between the oldest and the newest sample
+    fn push(&mut self, bytes: u64) -> Option<f64> {
+        self.push_at(Instant::now(), bytes)
+    }
+
+    fn push_at(&mut self, at: Instant, bytes: u64) -> Option<f64> {
+        if self.samples.len() >= RATE_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((at, bytes));
+        match (self.samples.front(), self.samples.back()) {
+            (Some((since, first)), Some((until, last))) if until > since => {
+                Some(last.saturating_sub(*first) as f64 / (*until - *since).as_secs_f64())
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SpaceReport {
+    pub disk: Option<DiskSpace>,
+    pub level: DiskLevel,
+    /// bytes per second written into the tracked paths
+    pub write_rate: Option<f64>,
+    pub error: Option<String>,
+}
+
+impl SpaceReport {
+    /// at the current write rate, unknown while nothing is being written
+    pub fn time_remaining(&self) -> Option<std::time::Duration> {
+        match (self.disk, self.write_rate) {
+            (Some(disk), Some(rate)) if rate > 0.0 => Some(std::time::Duration::from_secs_f64(
+                disk.available_bytes as f64 / rate,
+            )),
+            _ => None,
+        }
+    }
+
+    fn summary(&self) -> String {
+        let format_duration = |duration: std::time::Duration| {
+            let minutes = duration.as_secs() / 60;
+            format!("{}h {:02}m", minutes / 60, minutes % 60)
+        };
+        [
+            self.disk.map(|disk| {
+                format!(
+                    "free: {} of {} ({:.0}%)",
+                    crate::sessions::human_size(disk.available_bytes),
+                    crate::sessions::human_size(disk.total_bytes),
+                    disk.available_fraction() * 100.0
+                )
+            }),
+            Some(match self.write_rate {
+                Some(rate) => format!("writing: {}/s", crate::sessions::human_size(rate as u64)),
+                None => "writing: ?".to_owned(),
+            }),
+            Some(match self.time_remaining() {
+                Some(remaining) => format!("recording time left: {}", format_duration(remaining)),
+                None => "recording time left: -".to_owned(),
+            }),
+            self.error.clone(),
+        ]
+        .into_iter()
+        .flatten()
+        .join("  |  ")
+    }
+}
+
+/// free space on the sessions disk and how fast the recordings are eating it,
+/// `safe_stop` gets cancelled once the free space drops below the critical threshold
 #[derive(Debug)]
 pub struct SpaceAvailableWatcher {
     directory: PathBuf,
-    stdout: Arc<RwLock<String>>,
+    report: Arc<RwLock<SpaceReport>>,
+    safe_stop: CancellationToken,
     _watcher: AbortOnDrop<()>,
 }
 
 impl SpaceAvailableWatcher {
-    pub fn new(target_directory: PathBuf) -> Self {
-        let stdout = Arc::new(RwLock::new(String::new()));
+    /// `tracked` are the paths recordings are written to, REAPER's audio and the video files
+    pub fn new(
+        target_directory: PathBuf,
+        tracked: Vec<PathBuf>,
+        thresholds: LowDiskThresholds,
+        safe_stop: CancellationToken,
+        notify: ProcessEventBus,
+    ) -> Self {
+        let report = Arc::new(RwLock::new(SpaceReport::default()));
         let directory = target_directory.clone();
         let watcher = {
-            to_owned![stdout];
+            to_owned![report, safe_stop];
             tokio::task::spawn(async move {
                 let mut interval =
                     crate::process::app_interval(tokio::time::Duration::from_secs(1));
+                let mut write_rate = WriteRate::default();
                 loop {
                     interval.tick().await;
-                    tokio::process::Command::new("df")
-                        .arg("-P")
-                        .arg("-h")
-                        .arg(&target_directory)
-                        .output()
-                        .await
-                        .wrap_err("process failed")
-                        .and_then(|output| {
-                            let status = output.status;
-                            output
-                                .status
-                                .success()
-                                .then_some(output)
-                                .ok_or_else(move || eyre!("exited [{:?}]", status))
+                    let sample = {
+                        to_owned![target_directory, tracked];
+                        tokio::task::spawn_blocking(move || {
+                            (DiskSpace::of(&target_directory), bytes_in(&tracked))
                         })
-                        .map(|output| String::from_utf8_lossy(&output.stdout).to_string())
-                        .map(|output| *stdout.write() = output)
-                        .unwrap_or_else(|error| *stdout.write() = format!("{error:?}"));
+                    }
+                    .await
+                    .wrap_err("space sampling thread crashed");
+                    let next = match sample {
+                        Ok((Ok(disk), written)) => SpaceReport {
+                            disk: Some(disk),
+                            level: thresholds.level(disk),
+                            write_rate: write_rate.push(written),
+                            error: None,
+                        },
+                        Ok((Err(message), _)) | Err(message) => SpaceReport {
+                            error: Some(format!("{message:?}")),
+                            ..report.read().clone()
+                        },
+                    };
+                    let level = next.level;
+                    *report.write() = next;
+                    if level == DiskLevel::Critical && !safe_stop.is_cancelled() {
+                        tracing::error!(?thresholds, "disk almost full, stopping the recording");
+                        safe_stop.cancel();
+                    }
+                    // keeps the header flashing
+                    if level != DiskLevel::Fine {
+                        notify.send(ProcessEvent::NewInput).ok();
+                    }
                 }
             })
             .abort_on_drop()
         };
         Self {
             directory,
-            stdout,
+            report,
+            safe_stop,
             _watcher: watcher,
         }
     }
@@ -59,18 +252,154 @@ impl RenderToTerm for SpaceAvailableWatcher {
         f: &mut Frame<B>,
         rect: tui::layout::Rect,
     ) -> Result<()> {
-        let text_block = |text: &str| {
-            let block = Block::default().borders(Borders::ALL).title(Span::styled(
-                format!("available: [{}]", self.directory.display()),
-                Style::default()
-                    .fg(Color::Magenta)
-                    .add_modifier(Modifier::BOLD),
-            ));
-            Paragraph::new(text.to_owned())
-                .block(block)
-                .wrap(Wrap { trim: false })
+        let report = self.report.read().clone();
+        let flash_on = crate::now().timestamp() % 2 == 0;
+        let (title, style) = match (report.level, self.safe_stop.is_cancelled()) {
+            (_, true) => (
+                "LOW DISK SPACE, RECORDING STOPPED",
+                Style::default().fg(Color::White).bg(Color::Red),
+            ),
+            (DiskLevel::Critical, false) => (
+                "LOW DISK SPACE, STOPPING",
+                Style::default().fg(Color::White).bg(Color::Red),
+            ),
+            (DiskLevel::Warning, false) if flash_on => (
+                "LOW DISK SPACE",
+                Style::default().fg(Color::Black).bg(Color::Yellow),
+            ),
+            (DiskLevel::Warning, false) => ("LOW DISK SPACE", Style::default().fg(Color::Yellow)),
+            (DiskLevel::Fine, false) => ("available", Style::default().fg(Color::Magenta)),
         };
-        f.render_widget(text_block(self.stdout.read().as_str()), rect);
+        let block = Block::default().borders(Borders::ALL).title(Span::styled(
+            format!("{title}: [{}]", self.directory.display()),
+            style.add_modifier(Modifier::BOLD),
+        ));
+        f.render_widget(
+            Paragraph::new(report.summary())
+                .style(match report.level {
+                    DiskLevel::Fine => Style::default(),
+                    _ => style,
+                })
+                .block(block)
+                .wrap(Wrap { trim: false }),
+            rect,
+        );
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const MEGABYTE: u64 = GIGABYTE / 1024;
+
+    fn disk(available_gigabytes: u64) -> DiskSpace {
+        DiskSpace {
+            available_bytes: available_gigabytes * GIGABYTE,
+            total_bytes: 1000 * GIGABYTE,
+        }
+    }
+
+    #[test]
+    fn write_rate_is_averaged_over_the_window() {
+        let start = Instant::now();
+        let mut rate = WriteRate::default();
+        assert_eq!(rate.push_at(start, 0), None, "a single sample has no rate");
+        assert_eq!(
+            rate.push_at(start + Duration::from_secs(1), 100),
+            Some(100.0)
+        );
+        (2..=RATE_WINDOW as u64).for_each(|second| {
+            rate.push_at(start + Duration::from_secs(second), second * 100);
+        });
+        // the first sample fell out of the window
+        assert_eq!(rate.samples.len(), RATE_WINDOW);
+        assert_eq!(rate.samples.front().map(|(_, bytes)| *bytes), Some(100));
+        assert_eq!(
+            rate.push_at(start + Duration::from_secs(RATE_WINDOW as u64 + 1), 100),
+            Some(0.0),
+            "deleted files don't count as negative writes"
+        );
+    }
+
+    #[test]
+    fn time_remaining_needs_something_being_written() {
+        let report = |write_rate| SpaceReport {
+            disk: Some(disk(36)),
+            write_rate,
+            ..Default::default()
+        };
+        assert_eq!(report(None).time_remaining(), None);
+        assert_eq!(report(Some(0.0)).time_remaining(), None);
+        assert_eq!(
+            report(Some(MEGABYTE as f64)).time_remaining(),
+            Some(Duration::from_secs(36 * 1024))
+        );
+        assert_eq!(
+            SpaceReport {
+                write_rate: Some(1.0),
+                ..Default::default()
+            }
+            .time_remaining(),
+            None
+        );
+    }
+
+    #[test]
+    fn summary_shows_free_space_rate_and_time_left() {
+        assert_eq!(
+            SpaceReport {
+                disk: Some(disk(250)),
+                level: DiskLevel::Fine,
+                write_rate: Some(MEGABYTE as f64),
+                error: None,
+            }
+            .summary(),
+            format!(
+                "free: {} of {} (25%)  |  writing: {}/s  |  recording time left: 71h 06m",
+                crate::sessions::human_size(250 * GIGABYTE),
+                crate::sessions::human_size(1000 * GIGABYTE),
+                crate::sessions::human_size(MEGABYTE),
+            )
+        );
+        assert_eq!(
+            SpaceReport {
+                error: Some("statvfs failed".to_owned()),
+                ..Default::default()
+            }
+            .summary(),
+            "writing: ?  |  recording time left: -  |  statvfs failed"
+        );
+    }
+
+    #[test]
+    fn thresholds_are_given_in_gigabytes_and_critical_comes_first() {
+        assert_eq!(
+            LowDiskThresholds::from_gigabytes(50, 10).expect("valid thresholds"),
+            LowDiskThresholds {
+                warning_bytes: 50 * GIGABYTE,
+                critical_bytes: 10 * GIGABYTE,
+            }
+        );
+        assert!(LowDiskThresholds::from_gigabytes(10, 10).is_err());
+        assert!(LowDiskThresholds::from_gigabytes(10, 50).is_err());
+    }
+
+    #[test]
+    fn levels_include_their_thresholds() {
+        let thresholds = LowDiskThresholds::from_gigabytes(50, 10).expect("valid thresholds");
+        let level = |available_bytes| {
+            thresholds.level(DiskSpace {
+                available_bytes,
+                total_bytes: 1000 * GIGABYTE,
+            })
+        };
+        assert_eq!(level(50 * GIGABYTE + 1), DiskLevel::Fine);
+        assert_eq!(level(50 * GIGABYTE), DiskLevel::Warning);
+        assert_eq!(level(10 * GIGABYTE + 1), DiskLevel::Warning);
+        assert_eq!(level(10 * GIGABYTE), DiskLevel::Critical);
+        assert_eq!(level(0), DiskLevel::Critical);
+    }
+}
@@ -1,225 +1,471 @@
-use super::*;
-use crate::{
-    space_available_watcher::SpaceAvailableWatcher,
-    video_capture::gstreamer_process::GstreamerInstance,
-};
-use tokio_stream::wrappers::UnboundedReceiverStream;
-use tui::layout::Rect;
-
-pub struct StudioState {
-    pub wake_up: Option<UnboundedReceiverStream<ProcessEvent>>,
-    reaper: ReaperInstance,
-    qpwgraph: QpwgraphInstance,
-    // ffmpeg: FfmpegInstance,
-    gstreamer: GstreamerInstance,
-    space_available: SpaceAvailableWatcher,
-}
-
-mod dynamic_template {
-    use std::iter::once;
-
-    use reaper_save_rs::{
-        low_level::{Attribute, Object, ReaperString},
-        prelude::{ObjectWrapper, ReaperProject, SerializeAndDeserialize, Track},
-    };
-    pub const DEFAULT_VIDEO_FILE_OFFSET: f64 = 281.820_313_303_141_7;
-
-    use super::*;
-    fn template_video_track() -> Result<Track> {
-        let template_video_track_code = r#"
-<TRACK {E2DDC8BD-1B29-165D-D141-7267E8F39ECD}
-  NAME "VIDEO 1"
-  PEAKCOL 16576
-  BEAT -1
-  AUTOMODE 0
-  VOLPAN 1 0 -1 -1 1
-  MUTESOLO 0 0 0
-  IPHASE 0
-  PLAYOFFS 0 1
-  ISBUS 0 0
-  BUSCOMP 0 0 0 0 0
-  SHOWINMIX 1 0.558065 0.5 1 0.5 0 0 0
-  SEL 0
-  REC 0 0 0 0 0 0 0 0
-  VU 2
-  TRACKHEIGHT 0 0 0 0 0 0
-  INQ 0 0 0 0.5 100 0 0 100
-  NCHAN 2
-  FX 1
-  TRACKID {E2DDC8BD-1B29-165D-D141-7267E8F39ECD}
-  PERF 0
-  MIDIOUT -1
-  MAINSEND 1 0
-  <ITEM
-    POSITION 281.82031330314169
-    SNAPOFFS 0
-    LENGTH 188.04
-    LOOP 1
-    ALLTAKES 0
-    FADEIN 1 0.01 0 1 0 0 0
-    FADEOUT 1 0.01 0 1 0 0 0
-    MUTE 0 0
-    SEL 1
-    IGUID {2F6AD700-840B-EFB6-D384-7F8316E1C1E7}
-    IID 21
-    NAME barbarah-anne---2023-07-31--20-51-57.mov
-    VOLPAN 1 0 1 -1
-    SOFFS 0
-    PLAYRATE 1 0 0 -1 0 0.0025
-    CHANMODE 0
-    GUID {A365E92F-3BF8-24E8-1FF4-8FDF30208BCB}
-    <SOURCE VIDEO
-      FILE "video-recordings/barbarah-anne---2023-07-31--20-51-57.mov"
-    >
-  >
->
-        "#
-        .trim();
-        Object::deserialize(template_video_track_code, 0)
-            .wrap_err("deserializing template track")
-            .and_then(|(_, o)| Track::from_object(o).wrap_err("validating input"))
-    }
-
-    fn double_quote(val: &str) -> Attribute {
-        Attribute::String(ReaperString::DoubleQuote(val.to_owned()))
-    }
-
-    fn float(val: f64) -> Attribute {
-        Attribute::Float(val.into())
-    }
-
-    pub fn append_video_to(
-        mut reaper_project: ReaperProject,
-        video_path: PathBuf,
-        offset: f64,
-    ) -> Result<ReaperProject> {
-        template_video_track()
-            .and_then(|mut track| -> Result<_> {
-                // asd
-                let file_path = double_quote(video_path.clone().display().to_string().as_str());
-                *track.as_mut().single_attribute_mut("NAME")? = file_path.clone();
-                let item = track
-                    .as_mut()
-                    .child_object_mut("ITEM")
-                    .ok_or_else(|| eyre!("no ITEM in template"))?;
-                *item.single_attribute_mut("NAME")? = file_path.clone();
-                *item.single_attribute_mut("POSITION")? = float(offset);
-                let source = item
-                    .child_object_mut("SOURCE")
-                    .ok_or_else(|| eyre!("no SOURCE in ITEM"))?;
-                *source.single_attribute_mut("FILE")? = file_path;
-                Ok(track)
-            })
-            .wrap_err("creating video track")
-            .and_then(|video_track| {
-                reaper_project
-                    .modify_tracks(|tracks| {
-                        tracks
-                            .into_iter()
-                            .chain(once(video_track.clone()))
-                            .collect()
-                    })
-                    .wrap_err("modifying tracks")
-            })
-            .map(move |_| reaper_project)
-    }
-
-    pub fn with_video_track(
-        template_path: PathBuf,
-        video_path: PathBuf,
-        offset: f64,
-    ) -> Result<PathBuf> {
-        std::fs::read_to_string(template_path)
-            .wrap_err("reading original")
-            .and_then(|original| {
-                ReaperProject::parse_from_str(&original).wrap_err("parsing original")
-            })
-            .and_then(|parsed| append_video_to(parsed, video_path, offset))
-            .and_then(|modified| modified.serialize_to_string().wrap_err("serializing"))
-            .and_then(|serialized| {
-                directory_shenanigans::temp_home_path("generated-template.rpp").and_then(|path| {
-                    std::fs::write(&path, serialized)
-                        .wrap_err_with(|| format!("writing to {}", path.display()))
-                        .map(|_| path)
-                })
-            })
-    }
-}
-
-impl StudioState {
-    pub async fn new(
-        sessions_directory: SessionsDirectory,
-        project_name: ProjectName,
-        template: PathBuf,
-        reaper_web_base_url: reqwest::Url,
-        video_device: VideoDevice,
-    ) -> Result<Self> {
-        let (notify, wake_up) = tokio::sync::mpsc::unbounded_channel();
-        let qpwgraph = crate::qpwgraph::QpwgraphInstance::new(notify.clone())
-            .await
-            .wrap_err("Spawning qpwgraph")?;
-        let video_file_path = video_file_path(sessions_directory.clone(), &project_name)?;
-        let gstreamer = GstreamerInstance::new(video_device, video_file_path, notify.clone())
-            .map(|v| v.wrap_err("spawning video recorder"))
-            .await?;
-        let space_available =
-            SpaceAvailableWatcher::new(sessions_directory.as_ref().as_ref().to_owned());
-
-        let template_with_video = dynamic_template::with_video_track(
-            template,
-            gstreamer.video_file_path.clone(),
-            dynamic_template::DEFAULT_VIDEO_FILE_OFFSET,
-        )?;
-        let reaper = crate::reaper::ReaperInstance::new(
-            sessions_directory,
-            project_name.clone(),
-            template_with_video,
-            notify.clone(),
-            reaper_web_base_url,
-        )
-        .map(|v| v.wrap_err("starting reaper"))
-        .await?;
-        Ok(Self {
-            space_available,
-            wake_up: Some(UnboundedReceiverStream::new(wake_up)),
-            reaper,
-            qpwgraph,
-            gstreamer,
-        })
-    }
-}
-
-impl crate::rendering::RenderToTerm for StudioState {
-    fn render_to_term<B: Backend>(
-        &mut self,
-        frame: &mut Frame<B>,
-        rect: tui::layout::Rect,
-    ) -> Result<()> {
-        let Self {
-            wake_up: _,
-            reaper,
-            qpwgraph,
-            gstreamer,
-            space_available,
-        } = self;
-        let [header, body]: [Rect; 2] = layout!(Layout::default()
-            .direction(Direction::Vertical)
-            .constraints([Constraint::Percentage(10), Constraint::Percentage(90)].as_ref())
-            .split(rect));
-        let [qpwgraph_col, reaper_col, gstreamer_frame]: [Rect; 3] = layout!(Layout::default()
-            .direction(Direction::Horizontal)
-            .constraints([
-                Constraint::Ratio(1, 3),
-                Constraint::Ratio(1, 3),
-                Constraint::Ratio(1, 3),
-            ])
-            .split(body));
-
-        space_available.render_to_term(frame, header)?;
-        qpwgraph.render_to_term(frame, qpwgraph_col)?;
-        reaper.render_to_term(frame, reaper_col)?;
-        gstreamer.render_to_term(frame, gstreamer_frame)?;
-
-        Ok(())
-    }
-}
+use super::*;
+use crate::{
+    capture_supervisor::CaptureSupervisor,
+    directory_shenanigans::{project_directory, project_file_path},
+    dynamic_template,
+    session_manifest::{CameraRun, RecordingRun, SessionManifest, SessionManifestFile},
+    session_markers::SessionMarkers,
+    session_mirror::SessionMirror,
+    space_available_watcher::{LowDiskThresholds, SpaceAvailableWatcher},
+    transport_controls::TransportControls,
+    video_capture::gstreamer_process::GstreamerInstance,
+};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_util::sync::CancellationToken;
+use tui::{
+    layout::Rect,
+    style::{Color, Modifier, Style},
+    text::Span,
+};
+
+pub struct StudioState {
+    pub wake_up: Option<UnboundedReceiverStream<ProcessEvent>>,
+    reaper: ReaperInstance,
+    qpwgraph: QpwgraphInstance,
+    // ffmpeg: FfmpegInstance,
+    /// one per camera
+    gstreamer: Vec<CaptureSupervisor>,
+    /// camera whose pipeline log takes the scroll keys, Tab moves on to the next one
+    selected_camera: usize,
+    space_available: SpaceAvailableWatcher,
+    mirror: Option<SessionMirror>,
+    transport_controls: TransportControls,
+    markers: SessionMarkers,
+    manifest: SessionManifestFile,
+    project_file_path: PathBuf,
+    _manifest_watcher: AbortOnDrop<()>,
+    _reaper_safe_stop: AbortOnDrop<()>,
+}
+
+impl StudioState {
+    pub async fn new(
+        sessions_directory: SessionsDirectory,
+        project_name: ProjectName,
+        template: PathBuf,
+        reaper_web_base_url: reqwest::Url,
+        video_devices: Vec<VideoDevice>,
+        capture_settings: CaptureSettings,
+        low_disk: LowDiskThresholds,
+        mirror_directory: Option<PathBuf>,
+    ) -> Result<Self> {
+        let started_at = crate::now();
+        let (notify, wake_up) = tokio::sync::mpsc::unbounded_channel();
+        let qpwgraph = crate::qpwgraph::QpwgraphInstance::new(notify.clone())
+            .await
+            .wrap_err("Spawning qpwgraph")?;
+        let gstreamer = futures::future::join_all(video_devices.into_iter().map(|video_device| {
+            ready(video_file_path(
+                sessions_directory.clone(),
+                &project_name,
+                &video_device,
+                capture_settings.container,
+            ))
+            .and_then({
+                to_owned![video_device, notify];
+                move |video_file_path| {
+                    GstreamerInstance::new(video_device, capture_settings, video_file_path, notify)
+                }
+            })
+            .map(move |v| v.wrap_err_with(|| format!("spawning video recorder for {video_device}")))
+        }))
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+        // cameras start one by one, the earliest one is what REAPER gets synced to
+        let (reference_index, reference) = gstreamer
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, gstreamer)| gstreamer.started_at)
+            .ok_or_else(|| eyre!("no video device configured"))?;
+        let video_started_at = reference.started_at;
+        let markers_file = crate::session_markers::sidecar_path(&reference.video_file_path);
+        let relative_positions = gstreamer
+            .iter()
+            .map(|gstreamer| {
+                (
+                    gstreamer.first_file(),
+                    (gstreamer.started_at - video_started_at)
+                        .num_microseconds()
+                        .unwrap_or_default() as f64
+                        / 1_000_000.0,
+                )
+            })
+            .collect_vec();
+        let known_devices = video_capture::list_devices()
+            .await
+            .map_err(|message| tracing::warn!(?message, "video device details unavailable"))
+            .unwrap_or_default();
+        let project_file_path = project_file_path(sessions_directory.clone(), &project_name)?;
+        let project_directory = project_directory(sessions_directory.clone(), &project_name)?;
+        // REAPER records its audio next to the project file, the videos go to a subdirectory
+        let safe_stop = CancellationToken::new();
+        let space_available = SpaceAvailableWatcher::new(
+            sessions_directory.as_ref().as_ref().to_owned(),
+            vec![project_directory.as_ref().to_owned()],
+            low_disk,
+            safe_stop.clone(),
+            notify.clone(),
+        );
+        let mut previous = SessionManifest::load_or_move_aside(&SessionManifest::path(
+            project_directory.as_ref(),
+        ))?;
+        // the previous run might have ended before REAPER got closed, takes recovered
+        // in it couldn't be added while REAPER was running
+        if let (Some(previous), true) = (previous.as_mut(), project_file_path.exists()) {
+            if let Err(message) = dynamic_template::finish_project(&project_file_path, previous)
+                .and_then(|_| {
+                    previous.mark_project_finished();
+                    previous.save(&SessionManifest::path(project_directory.as_ref()))
+                })
+            {
+                tracing::warn!(?message, "previous run could not be finished");
+            }
+        }
+        let (template_with_video, item_position, project_backup) = match project_file_path.exists()
+        {
+            true => dynamic_template::resume_with_video_tracks(
+                project_file_path.clone(),
+                relative_positions.clone(),
+            )
+            .map(|(position, backup)| (template.clone(), position, Some(backup)))?,
+            false => dynamic_template::with_video_tracks(
+                template.clone(),
+                relative_positions
+                    .iter()
+                    .cloned()
+                    .map(|(video_path, offset)| {
+                        (video_path, dynamic_template::VIDEO_ITEM_POSITION + offset)
+                    })
+                    .collect(),
+            )
+            .map(|template| (template, dynamic_template::VIDEO_ITEM_POSITION, None))?,
+        };
+        let cameras = gstreamer
+            .iter()
+            .zip(relative_positions.iter())
+            .map(|(gstreamer, (_, offset))| CameraRun {
+                video_device: gstreamer.video_device.clone(),
+                video_device_details: known_devices
+                    .iter()
+                    .find(|device| device.is(&gstreamer.video_device))
+                    .cloned(),
+                pipeline: gstreamer.pipeline.clone(),
+                video_file: gstreamer.first_file(),
+                video_started_at: gstreamer.started_at,
+                item_position_seconds: item_position + offset,
+                segments: vec![],
+                recoveries: vec![],
+            })
+            .collect_vec();
+        let manifest = SessionManifestFile::create(
+            project_directory.as_ref(),
+            &project_name,
+            RecordingRun {
+                template: template.clone(),
+                project_backup,
+                cameras,
+                capture_settings,
+                video_files: gstreamer
+                    .iter()
+                    .map(GstreamerInstance::first_file)
+                    .collect(),
+                started_at,
+                video_started_at,
+                stopped_at: None,
+                reaper_start: None,
+                reaper_stop_position_seconds: None,
+                children: Default::default(),
+                finalized: false,
+                mirror_directory: mirror_directory.clone(),
+                mirrored: vec![],
+                markers_file: Some(markers_file.clone()),
+                project_finished: false,
+            },
+        )
+        .wrap_err("writing session manifest")?;
+        let markers = SessionMarkers::new(markers_file, manifest.clone(), reference_index);
+
+        let video_sync = VideoSync {
+            video_started_at,
+            item_position,
+            clock: reference.clock.clone(),
+        };
+        let reaper = crate::reaper::ReaperInstance::new(
+            sessions_directory,
+            project_name.clone(),
+            template_with_video,
+            notify.clone(),
+            reaper_web_base_url,
+            video_sync.clone(),
+        )
+        .map(|v| v.wrap_err("starting reaper"))
+        .await?;
+        // the position REAPER records from is only known once it runs, and it saves over
+        // whatever gets written to the .rpp meanwhile - the measured item positions go to the
+        // manifest and into the project once REAPER is closed
+        let video_sync = video_sync.corrected(&reaper.recording_start);
+        manifest.update_run(|run| {
+            run.reaper_start = Some(reaper.recording_start);
+            run.cameras.iter_mut().for_each(|camera| {
+                camera.item_position_seconds += reaper.recording_start.sync_error_seconds
+            });
+        })?;
+        let gstreamer: Vec<_> = gstreamer
+            .into_iter()
+            .enumerate()
+            .map(|(camera_index, gstreamer)| {
+                CaptureSupervisor::new(
+                    gstreamer,
+                    camera_index,
+                    manifest.clone(),
+                    markers.clone(),
+                    reaper.web_client(),
+                    video_sync.clone(),
+                    safe_stop.clone(),
+                    notify.clone(),
+                )
+            })
+            .collect();
+        let manifest_watcher = manifest.watch(
+            [
+                ("qpwgraph".to_owned(), qpwgraph.exit_status()),
+                ("reaper".to_owned(), reaper.exit_status()),
+            ]
+            .into_iter()
+            .chain(gstreamer.iter().map(|camera| {
+                (
+                    format!("gstreamer {}", camera.video_device().label()),
+                    camera.exit_status(),
+                )
+            }))
+            .collect(),
+            gstreamer.iter().map(CaptureSupervisor::segments).collect(),
+            reaper.status(),
+        );
+        let mirror = mirror_directory.map(|mirror_directory| {
+            SessionMirror::new(
+                project_directory.as_ref().to_owned(),
+                mirror_directory,
+                &project_name,
+                manifest.clone(),
+                reaper.status(),
+                reaper.closed(),
+                gstreamer
+                    .iter()
+                    .map(CaptureSupervisor::exit_status)
+                    .collect(),
+                notify.clone(),
+            )
+        });
+        let reaper_safe_stop = {
+            let web_client = reaper.web_client();
+            tokio::task::spawn(async move {
+                use crate::reaper::reaper_web_client::rea_request::ActionId;
+                safe_stop.cancelled().await;
+                tracing::error!("low disk space, stopping and saving the REAPER project");
+                if let Err(message) = web_client
+                    .run_batch((ActionId::TransportStop, ActionId::SaveProject))
+                    .await
+                {
+                    tracing::error!(?message, "REAPER could not be stopped");
+                }
+            })
+            .abort_on_drop()
+        };
+        Ok(Self {
+            markers,
+            manifest,
+            project_file_path,
+            _manifest_watcher: manifest_watcher,
+            _reaper_safe_stop: reaper_safe_stop,
+            space_available,
+            mirror,
+            transport_controls: TransportControls::new(notify),
+            wake_up: Some(UnboundedReceiverStream::new(wake_up)),
+            reaper,
+            qpwgraph,
+            gstreamer,
+            selected_camera: 0,
+        })
+    }
+}
+
+impl StudioState {
+    /// returns true when the key was consumed
+    pub fn handle_key(&mut self, key: crossterm::event::KeyEvent) -> bool {
+        use crossterm::event::KeyCode;
+        if self
+            .transport_controls
+            .handle_key(key, &self.reaper, &self.markers)
+        {
+            return true;
+        }
+        match key.code {
+            KeyCode::Tab if !self.gstreamer.is_empty() => {
+                self.selected_camera = (self.selected_camera + 1) % self.gstreamer.len();
+                true
+            }
+            _ => self
+                .gstreamer
+                .get(self.selected_camera)
+                .map(|camera| camera.log().handle_key(key))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// the mirror keeps copying for a while once the state is dropped
+    pub fn mirror_drained(&self) -> Option<CancellationToken> {
+        self.mirror.as_ref().map(SessionMirror::drained)
+    }
+
+    /// selects the camera that failed and puts its error in the status line
+    pub fn pipeline_failed(&mut self) {
+        let failed = self
+            .gstreamer
+            .iter()
+            .enumerate()
+            .find_map(|(index, camera)| {
+                camera
+                    .log()
+                    .last_error()
+                    .map(|error| (index, camera.video_device(), error))
+            });
+        if let Some((index, video_device, error)) = failed {
+            tracing::error!(?video_device, error = %error.text, "capture pipeline failed");
+            self.selected_camera = index;
+            self.transport_controls.show_error(eyre!(
+                "camera {} ({video_device}) failed: {}",
+                index + 1,
+                error.text
+            ));
+        }
+    }
+}
+
+impl crate::rendering::RenderToTerm for StudioState {
+    fn render_to_term<B: Backend>(
+        &mut self,
+        frame: &mut Frame<B>,
+        rect: tui::layout::Rect,
+    ) -> Result<()> {
+        let Self {
+            wake_up: _,
+            reaper,
+            qpwgraph,
+            gstreamer,
+            selected_camera,
+            space_available,
+            mirror,
+            transport_controls,
+            markers: _,
+            manifest: _,
+            project_file_path: _,
+            _manifest_watcher: _,
+            _reaper_safe_stop: _,
+        } = self;
+        let [header, body, footer]: [Rect; 3] = layout!(Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(
+                [
+                    Constraint::Percentage(10),
+                    Constraint::Min(0),
+                    Constraint::Length(3),
+                ]
+                .as_ref()
+            )
+            .split(rect));
+        let [qpwgraph_col, reaper_col, gstreamer_frame]: [Rect; 3] = layout!(Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([
+                Constraint::Ratio(1, 3),
+                Constraint::Ratio(1, 3),
+                Constraint::Ratio(1, 3),
+            ])
+            .split(body));
+
+        match mirror {
+            Some(mirror) => {
+                let [space_available_col, mirror_col]: [Rect; 2] = layout!(Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints([Constraint::Ratio(1, 2), Constraint::Ratio(1, 2)])
+                    .split(header));
+                space_available.render_to_term(frame, space_available_col)?;
+                mirror.render_to_term(frame, mirror_col)?;
+            }
+            None => space_available.render_to_term(frame, header)?,
+        }
+        qpwgraph.render_to_term(frame, qpwgraph_col)?;
+        reaper.render_to_term(frame, reaper_col)?;
+        Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(
+                gstreamer
+                    .iter()
+                    .map(|_| Constraint::Ratio(1, gstreamer.len() as u32))
+                    .collect_vec(),
+            )
+            .split(gstreamer_frame)
+            .iter()
+            .zip(gstreamer.iter_mut())
+            .enumerate()
+            .try_for_each(|(index, (rect, gstreamer))| {
+                let (title, color) = match (gstreamer.log().last_error(), index == *selected_camera)
+                {
+                    (Some(error), _) => (
+                        format!("camera {} FAILED: {}", index + 1, error.text),
+                        Color::Red,
+                    ),
+                    (None, true) => (
+                        format!(
+                            "camera {} (Tab: next camera, arrows: scroll log)",
+                            index + 1
+                        ),
+                        Color::Cyan,
+                    ),
+                    (None, false) => (format!("camera {}", index + 1), Color::DarkGray),
+                };
+                let block = Block::default()
+                    .borders(Borders::ALL)
+                    .border_style(Style::default().fg(color))
+                    .title(Span::styled(
+                        title,
+                        Style::default().fg(color).add_modifier(Modifier::BOLD),
+                    ));
+                let inner = block.inner(*rect);
+                frame.render_widget(block, *rect);
+                gstreamer.render_to_term(frame, inner)
+            })?;
+        transport_controls.render_to_term(frame, footer)?;
+
+        Ok(())
+    }
+}
+
+impl Drop for StudioState {
+    fn drop(&mut self) {
+        if let Err(message) = self.manifest.finalize() {
+            tracing::error!(?message, "finalizing session manifest");
+        }
+        let closed = self.reaper.closed();
+        let manifest = self.manifest.clone();
+        let project_file_path = self.project_file_path.clone();
+        tokio::task::spawn(async move {
+            closed.cancelled().await;
+            match tokio::task::spawn_blocking(move || {
+                dynamic_template::finish_project(&project_file_path, &manifest.snapshot()).and_then(
+                    |backup| {
+                        manifest
+                            .update(SessionManifest::mark_project_finished)
+                            .map(|_| backup)
+                    },
+                )
+            })
+            .await
+            {
+                Ok(Ok(backup)) => tracing::info!(?backup, "project finished"),
+                Ok(Err(message)) => tracing::error!(?message, "finishing the project"),
+                Err(message) => tracing::error!(?message, "finishing thread crashed"),
+            }
+        });
+    }
+}
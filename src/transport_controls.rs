@@ -0,0 +1,408 @@
+use super::*;
+use crate::{
+    reaper::{
+        reaper_web_client::rea_request::{ActionId, Playstate},
+        ReaperInstance,
+    },
+    session_markers::{format_position, PendingMarker, SessionMarkers},
+};
+use crossterm::event::KeyEvent;
+use tui::{
+    style::{Color, Modifier, Style},
+    text::{Span, Spans},
+    widgets::{Paragraph, Wrap},
+};
+
+/// single-key transport controls sent to reaper through the web interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::EnumIter, strum::Display)]
+pub enum TransportCommand {
+    Record,
+    Stop,
+    Play,
+    Marker,
+    Save,
+    Repeat,
+}
+
+impl TransportCommand {
+    pub fn key(self) -> char {
+        match self {
+            Self::Record => 'r',
+            Self::Stop => 's',
+            Self::Play => 'p',
+            Self::Marker => 'm',
+            Self::Save => 'w',
+            Self::Repeat => 't',
+        }
+    }
+
+    pub fn action(self) -> ActionId {
+        match self {
+            Self::Record => ActionId::TransportRecord,
+            Self::Stop => ActionId::TransportStop,
+            Self::Play => ActionId::TransportPlay,
+            Self::Marker => ActionId::InsertMarker,
+            Self::Save => ActionId::SaveProject,
+            Self::Repeat => ActionId::TransportToggleRepeat,
+        }
+    }
+
+    pub fn from_key(key: KeyEvent) -> Option<Self> {
+        use strum::IntoEnumIterator;
+        match key.code {
+            KeyCode::Char(c) if key.modifiers.is_empty() => {
+                Self::iter().find(|command| command.key() == c)
+            }
+            _ => None,
+        }
+    }
+
+    /// anything that would end the take needs a second keypress
+    pub fn needs_confirmation(self, playstate: Option<Playstate>) -> bool {
+        matches!(
+            (self, playstate),
+            (
+                Self::Record | Self::Stop | Self::Play,
+                Some(Playstate::Recording | Playstate::RecordPaused)
+            )
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+enum Mode {
+    #[default]
+    Keys,
+    Confirm(TransportCommand),
+    /// marker is already placed, waiting for the note
+    Note(PendingMarker, String),
+}
+
+#[derive(Debug)]
+pub struct TransportControls {
+    mode: Mode,
+    last_outcome: Arc<RwLock<Option<Result<String>>>>,
+    notify: ProcessEventBus,
+}
+
+impl TransportControls {
+    pub const CONFIRM_KEY: char = 'y';
+
+    pub fn new(notify: ProcessEventBus) -> Self {
+        Self {
+            mode: Mode::Keys,
+            last_outcome: Default::default(),
+            notify,
+        }
+    }
+
+    /// returns true when the key was consumed
+    pub fn handle_key(
+        &mut self,
+        key: KeyEvent,
+        reaper: &ReaperInstance,
+        markers: &SessionMarkers,
+    ) -> bool {
+        self.mode = match std::mem::take(&mut self.mode) {
+            Mode::Confirm(pending) => {
+                if key.code == KeyCode::Char(Self::CONFIRM_KEY) {
+                    self.send(pending, reaper);
+                }
+                Mode::Keys
+            }
+            Mode::Note(pending, mut note) => match key.code {
+                KeyCode::Char(c) => {
+                    note.push(c);
+                    Mode::Note(pending, note)
+                }
+                KeyCode::Backspace => {
+                    note.pop();
+                    Mode::Note(pending, note)
+                }
+                KeyCode::Enter => {
+                    self.commit_marker(pending, note, reaper, markers);
+                    Mode::Keys
+                }
+                KeyCode::Esc => {
+                    self.commit_marker(pending, String::new(), reaper, markers);
+                    Mode::Keys
+                }
+                _ => Mode::Note(pending, note),
+            },
+            Mode::Keys => match TransportCommand::from_key(key) {
+                Some(TransportCommand::Marker) => {
+                    Mode::Note(markers.insert(reaper.web_client()), String::new())
+                }
+                Some(command) if command.needs_confirmation(reaper.playstate()) => {
+                    Mode::Confirm(command)
+                }
+                Some(command) => {
+                    self.send(command, reaper);
+                    Mode::Keys
+                }
+                None => return false,
+            },
+        };
+        true
+    }
+
+    /// shows up in place of the outcome of the last command
+    pub fn show_error(&self, report: eyre::Report) {
+        let _ = self.last_outcome.write().insert(Err(report));
+    }
+
+    fn report(&self, task: impl futures::Future<Output = Result<String>> + Send + 'static) {
+        let last_outcome = self.last_outcome.clone();
+        let notify = self.notify.clone();
+        tokio::task::spawn(async move {
+            let outcome = task.await;
+            if let Err(message) = &outcome {
+                tracing::error!(?message, "transport command failed");
+            }
+            let _ = last_outcome.write().insert(outcome);
+            notify.send(ProcessEvent::NewInput).ok();
+        });
+    }
+
+    fn send(&self, command: TransportCommand, reaper: &ReaperInstance) {
+        self.report(
+            reaper
+                .web_client()
+                .run_single(command.action())
+                .map_ok(move |_| format!("sent {command}"))
+                .map(move |outcome| outcome.wrap_err_with(|| format!("sending {command}"))),
+        );
+    }
+
+    fn commit_marker(
+        &self,
+        pending: PendingMarker,
+        note: String,
+        reaper: &ReaperInstance,
+        markers: &SessionMarkers,
+    ) {
+        self.report(
+            markers
+                .clone()
+                .commit(pending, note, reaper.web_client())
+                .map_ok(|marker| {
+                    format!(
+                        "marker {} '{}' at video {}",
+                        marker
+                            .reaper_marker_id
+                            .map(|id| id.to_string())
+                            .unwrap_or_else(|| "?".to_owned()),
+                        marker.note,
+                        format_position(marker.video_position_seconds)
+                    )
+                })
+                .map(|outcome| outcome.wrap_err("saving marker")),
+        );
+    }
+}
+
+impl RenderToTerm for TransportControls {
+    fn render_to_term<B: Backend>(
+        &mut self,
+        f: &mut Frame<B>,
+        rect: tui::layout::Rect,
+    ) -> Result<()> {
+        use strum::IntoEnumIterator;
+        let key_style = Style::default()
+            .fg(Color::Black)
+            .bg(Color::Gray)
+            .add_modifier(Modifier::BOLD);
+        let prompt_style = Style::default()
+            .fg(Color::White)
+            .bg(Color::Red)
+            .add_modifier(Modifier::BOLD);
+        let line = match &self.mode {
+            Mode::Confirm(pending) => Spans::from(vec![Span::styled(
+                format!(
+                    " {pending} while recording? press '{}' to confirm, any other key cancels ",
+                    Self::CONFIRM_KEY
+                ),
+                prompt_style,
+            )]),
+            Mode::Note(pending, note) => Spans::from(vec![
+                Span::styled(
+                    format!(" marker at {} - note: ", pending.time.format("%H:%M:%S")),
+                    prompt_style,
+                ),
+                Span::raw(format!(" {note}█ ")),
+                Span::styled(" Enter ", key_style),
+                Span::raw(" save  "),
+                Span::styled(" Esc ", key_style),
+                Span::raw(" no note "),
+            ]),
+            Mode::Keys => Spans::from(
+                TransportCommand::iter()
+                    .flat_map(|command| {
+                        [
+                            Span::styled(format!(" {} ", command.key()), key_style),
+                            Span::raw(format!(" {command}  ")),
+                        ]
+                    })
+                    .chain([
+                        Span::styled(" Alt+Shift+Q ", key_style),
+                        Span::raw(" quit  "),
+                    ])
+                    .chain(
+                        self.last_outcome
+                            .read()
+                            .as_ref()
+                            .map(|outcome| match outcome {
+                                Ok(message) => Span::styled(
+                                    format!("│ {message}"),
+                                    Style::default().fg(Color::Green),
+                                ),
+                                Err(message) => Span::styled(
+                                    format!("│ {message:#}"),
+                                    Style::default().fg(Color::Red),
+                                ),
+                            }),
+                    )
+                    .collect_vec(),
+            ),
+        };
+        f.render_widget(
+            Paragraph::new(line)
+                .wrap(Wrap { trim: true })
+                .block(Block::default().borders(Borders::ALL).title("Transport")),
+            rect,
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reaper::mock_reaper::tests::{instance, mock, wait_until};
+
+    fn key(c: char) -> KeyEvent {
+        KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE)
+    }
+
+    fn controls() -> TransportControls {
+        let (notify, _wake_up) = tokio::sync::mpsc::unbounded_channel();
+        TransportControls::new(notify)
+    }
+
+    #[test]
+    fn plain_keys_map_to_commands() {
+        assert_eq!(
+            TransportCommand::from_key(key('r')),
+            Some(TransportCommand::Record)
+        );
+        assert_eq!(
+            TransportCommand::from_key(key('t')),
+            Some(TransportCommand::Repeat)
+        );
+        assert_eq!(TransportCommand::from_key(key('x')), None);
+        assert_eq!(
+            TransportCommand::from_key(KeyEvent::new(KeyCode::Char('r'), KeyModifiers::CONTROL)),
+            None
+        );
+    }
+
+    #[test]
+    fn only_ending_the_take_needs_confirmation() {
+        use strum::IntoEnumIterator;
+        let recording = Some(Playstate::Recording);
+        assert_eq!(
+            TransportCommand::iter()
+                .filter(|command| command.needs_confirmation(recording))
+                .collect_vec(),
+            [
+                TransportCommand::Record,
+                TransportCommand::Stop,
+                TransportCommand::Play
+            ]
+        );
+        assert!(TransportCommand::iter()
+            .all(|command| !command.needs_confirmation(Some(Playstate::Stopped))));
+        assert!(TransportCommand::iter().all(|command| !command.needs_confirmation(None)));
+    }
+
+    fn markers(directory: &tempfile::TempDir) -> SessionMarkers {
+        SessionMarkers::new(directory.path().join("take.mov"), crate::now())
+    }
+
+    #[tokio::test]
+    async fn keys_are_sent_right_away_when_not_recording() {
+        let (mock, web_client) = mock().await;
+        let reaper = instance(web_client).await;
+        let directory = tempfile::tempdir().expect("temp directory");
+        let markers = markers(&directory);
+        let mut controls = controls();
+        assert!(controls.handle_key(key('r'), &reaper, &markers));
+        wait_until(&mock, |state| state.playstate == Playstate::Recording).await;
+        assert!(!controls.handle_key(key('x'), &reaper, &markers));
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(matches!(
+            controls.last_outcome.read().as_ref(),
+            Some(Ok(outcome)) if outcome == "sent Record"
+        ));
+    }
+
+    #[tokio::test]
+    async fn stopping_a_take_waits_for_confirmation() {
+        let (mock, web_client) = mock().await;
+        web_client
+            .clone()
+            .start_reaper_recording()
+            .await
+            .expect("recording");
+        let reaper = instance(web_client).await;
+        let directory = tempfile::tempdir().expect("temp directory");
+        let markers = markers(&directory);
+        let mut controls = controls();
+
+        assert!(controls.handle_key(key('s'), &reaper, &markers));
+        assert!(matches!(
+            controls.mode,
+            Mode::Confirm(TransportCommand::Stop)
+        ));
+        // any other key cancels, and is swallowed
+        assert!(controls.handle_key(key('m'), &reaper, &markers));
+        assert!(matches!(controls.mode, Mode::Keys));
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        assert_eq!(mock.state.read().playstate, Playstate::Recording);
+        assert!(mock.state.read().markers.is_empty());
+
+        assert!(controls.handle_key(key('s'), &reaper, &markers));
+        assert!(controls.handle_key(key(TransportControls::CONFIRM_KEY), &reaper, &markers));
+        wait_until(&mock, |state| state.playstate == Playstate::Stopped).await;
+    }
+
+    #[tokio::test]
+    async fn notes_are_typed_in_after_the_marker_is_placed() {
+        let (mock, web_client) = mock().await;
+        let reaper = instance(web_client).await;
+        let directory = tempfile::tempdir().expect("temp directory");
+        let markers = markers(&directory);
+        let mut controls = controls();
+
+        assert!(controls.handle_key(key('m'), &reaper, &markers));
+        wait_until(&mock, |state| state.markers.len() == 1).await;
+        // transport keys are part of the note now
+        for c in "rsx".chars() {
+            assert!(controls.handle_key(key(c), &reaper, &markers));
+        }
+        let backspace = KeyEvent::new(KeyCode::Backspace, KeyModifiers::NONE);
+        assert!(controls.handle_key(backspace, &reaper, &markers));
+        assert!(matches!(&controls.mode, Mode::Note(_, note) if note == "rs"));
+        assert_eq!(mock.state.read().playstate, Playstate::Stopped);
+
+        let enter = KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE);
+        assert!(controls.handle_key(enter, &reaper, &markers));
+        assert!(matches!(controls.mode, Mode::Keys));
+        wait_until(&mock, |state| !state.project_ext_state.is_empty()).await;
+        assert_eq!(
+            mock.state.read().project_ext_state.values().collect_vec(),
+            ["rs"]
+        );
+        assert!(markers.sidecar_path().exists());
+    }
+}
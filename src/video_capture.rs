@@ -1,15 +1,21 @@
 use super::*;
 use crate::directory_shenanigans::{project_directory, ExistingDirectoryExt};
 use once_cell::sync::Lazy;
-use std::{collections::HashSet, future::ready, process::Output};
-use tokio::process::{Child, Command};
+use std::{collections::HashSet, future::ready, path::Path, process::Output};
+use tokio::process::Command;
 use tui::{
     layout::Rect,
     style::{Color, Modifier, Style},
     text::Span,
     widgets::{Paragraph, Wrap},
 };
+pub mod capture_settings;
+pub mod device_capabilities;
+pub mod discovery;
 pub mod gstreamer_process;
+pub mod preview_window;
+pub use capture_settings::CaptureSettings;
+pub use preview_window::{PreviewSink, PreviewWindow};
 
 /// uses ffmpeg
 ///
@@ -40,39 +46,92 @@ pub mod gstreamer_process;
 #[derive(Debug)]
 pub struct FfmpegInstance {
     process: Arc<RwLock<ProcessWatcher>>,
-    preview_process: Arc<RwLock<Result<ProcessWatcher>>>,
+    preview: Result<PreviewWindow>,
     pub video_file_path: PathBuf,
     _file_size_updater: AbortOnDrop<()>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, derive_more::AsRef)]
-pub struct VideoDevice(PathBuf);
+/// written as `/dev/video1`, or `test:smpte` for frames from `videotestsrc`
+#[derive(
+    Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(into = "String", try_from = "String")]
+pub enum VideoDevice {
+    V4l2(PathBuf),
+    /// no camera needed, the name is a `videotestsrc` pattern
+    Test(String),
+}
 
 impl VideoDevice {
+    const TEST_PREFIX: &'static str = "test";
+    const DEFAULT_TEST_PATTERN: &'static str = "smpte";
+
+    /// every video4linux node along with its udev symlinks
     pub fn all() -> Result<Vec<Self>> {
-        std::fs::read_dir("/dev/")
-            .wrap_err("reading /dev")
-            .map(|dev| {
-                dev.into_iter()
-                    .filter_map(|e| e.ok())
-                    .map(|e| e.path())
-                    .filter(|p| {
-                        p.as_os_str()
-                            .to_str()
-                            .map(|name| name.contains("video"))
-                            .unwrap_or_default()
-                    })
-                    .map(Self)
-                    .sorted()
-                    .collect()
-            })
+        discovery::discover().map(|devices| {
+            devices
+                .into_iter()
+                .flat_map(|device| {
+                    std::iter::once(device.video_device)
+                        .chain(device.stable_paths.into_iter().map(Self::V4l2))
+                })
+                .sorted()
+                .collect()
+        })
+    }
+
+    /// `video1` for `/dev/video1`, used in file names
+    pub fn label(&self) -> String {
+        match self {
+            Self::V4l2(path) => path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| path.display().to_string().replace('/', "-")),
+            Self::Test(pattern) => format!("{}-{pattern}", Self::TEST_PREFIX),
+        }
     }
 
+    /// only real devices have a path that v4l2 tools can be pointed at
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Self::V4l2(path) => Some(path),
+            Self::Test(_) => None,
+        }
+    }
+
+    /// gst-launch syntax for the element producing the frames
+    pub fn source_element(&self) -> String {
+        match self {
+            Self::V4l2(path) => format!("v4l2src device={}", path.display()),
+            Self::Test(pattern) => format!("videotestsrc is-live=true pattern={pattern}"),
+        }
+    }
+
+    /// `test` alone is the SMPTE colour bars
     pub fn new(value: &str) -> Result<Self> {
-        value.parse().wrap_err("invalid path").map(Self)
+        let test_pattern = match value.split_once(':') {
+            Some((Self::TEST_PREFIX, pattern)) => Some(pattern),
+            Some(_) => None,
+            None => (value == Self::TEST_PREFIX).then_some(""),
+        };
+        match test_pattern {
+            Some("") => Ok(Self::Test(Self::DEFAULT_TEST_PATTERN.to_owned())),
+            Some(pattern) => Ok(Self::Test(pattern.to_owned())),
+            None => value.parse().wrap_err("invalid path").map(Self::V4l2),
+        }
     }
 
+    /// test sources always exist, selectors like `card:"Magewell Pro Capture"` or
+    /// `/dev/v4l/by-id/...` name a camera across reboots
     pub fn new_checked(value: &str) -> Result<Self> {
+        if let Some(selector) = discovery::DeviceSelector::parse(value) {
+            return discovery::discover()
+                .and_then(|devices| selector.resolve(&devices))
+                .wrap_err_with(|| format!("resolving {selector}"));
+        }
+        if let test @ Self::Test(_) = Self::new(value)? {
+            return Ok(test);
+        }
         Self::all()
             .wrap_err("unable to read video devices")
             .and_then(|devices| {
@@ -88,31 +147,50 @@ impl VideoDevice {
 
 impl std::fmt::Display for VideoDevice {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.0.display().fmt(f)
+        match self {
+            Self::V4l2(path) => path.display().fmt(f),
+            Self::Test(pattern) => write!(f, "{}:{pattern}", Self::TEST_PREFIX),
+        }
+    }
+}
+
+impl From<VideoDevice> for String {
+    fn from(value: VideoDevice) -> Self {
+        value.to_string()
+    }
+}
+
+impl TryFrom<String> for VideoDevice {
+    type Error = eyre::Report;
+
+    fn try_from(value: String) -> Result<Self> {
+        Self::new(&value)
     }
 }
 
 pub fn video_file_path(
     sessions_directory: SessionsDirectory,
     project_name: &ProjectName,
+    video_device: &VideoDevice,
+    container: capture_settings::Container,
 ) -> Result<PathBuf> {
     let now = crate::now().format("%Y-%m-%d--%H-%M-%S").to_string();
     project_directory(sessions_directory, project_name)
         .and_then(|project_dir| {
             project_dir
                 .as_ref()
-                .join("video-recordings")
+                .join(crate::sessions::VIDEO_RECORDINGS_DIRECTORY)
                 .directory_exists()
         })
         .map(|project_video_dir| {
-            project_video_dir
-                .as_ref()
-                .join(format!("{project_name}---{now}.mkv"))
+            project_video_dir.as_ref().join(format!(
+                "{project_name}---{}---{now}.{}",
+                video_device.label(),
+                container.extension()
+            ))
         })
 }
 
-const FFPLAY_PATH: &str = "ffplay";
-
 macro_rules! arg {
     ($arg:expr) => {
         format!("{}", $arg).as_str()
@@ -158,53 +236,60 @@ impl OutputExt for Output {
     }
 }
 
-#[derive(Hash, Debug, PartialEq, Eq, Clone)]
+/// one `/dev/videoN` node, a single camera usually has a capture node and a metadata node
+#[derive(Hash, Debug, PartialEq, Eq, Clone, serde::Serialize, serde::Deserialize)]
 pub struct DetailedVideoDevice {
     pub video_device: VideoDevice,
+    /// `Magewell Pro Capture (PCI:0000:03:00.0)`
     pub details: String,
+    #[serde(default)]
+    pub name: String,
+    /// position of this node among the ones created for the same physical device
+    #[serde(default)]
+    pub index: Option<u32>,
+    #[serde(default)]
+    pub driver: Option<String>,
+    #[serde(default)]
+    pub bus_info: Option<String>,
+    #[serde(default)]
+    pub serial: Option<String>,
+    /// devices that can't be opened are assumed to capture when they are the first node
+    #[serde(default)]
+    pub capture: bool,
+    /// udev symlinks that survive renumbering, by-id first
+    #[serde(default)]
+    pub stable_paths: Vec<PathBuf>,
 }
 
-#[tracing::instrument(ret, err, level = "DEBUG")]
-pub async fn list_devices() -> Result<Vec<DetailedVideoDevice>> {
-    #[tracing::instrument(ret, err, level = "DEBUG")]
-    fn parse_section(section: &str) -> Result<Vec<DetailedVideoDevice>> {
-        section
-            .split_once('\n')
-            .into_iter()
-            .flat_map(|(header, devices)| {
-                tracing::debug!(%header, %devices);
-                devices
-                    .split('\n')
-                    .filter_map(|v| {
-                        let v = v.trim();
-                        (!v.is_empty()).then_some(v)
-                    })
-                    .map(|device| {
-                        VideoDevice::new(device).map(|video_device| DetailedVideoDevice {
-                            video_device,
-                            details: header.trim().to_string(),
-                        })
-                    })
-            })
-            .collect()
+impl DetailedVideoDevice {
+    /// also true when `video_device` is one of the udev symlinks to this node
+    pub fn is(&self, video_device: &VideoDevice) -> bool {
+        self.video_device == *video_device
+            || video_device
+                .path()
+                .map(|path| self.stable_paths.iter().any(|stable| stable == path))
+                .unwrap_or_default()
     }
-    Command::new("v4l2-ctl")
-        .arg("--list-devices")
-        .output()
+}
+
+pub async fn list_devices() -> Result<Vec<DetailedVideoDevice>> {
+    tokio::task::spawn_blocking(discovery::discover)
         .await
-        .wrap_err("reading command output")
-        .and_then(|output| output.success_output())
-        .and_then(|SuccessOutput { stdout, stderr }| {
-            tracing::debug!(%stdout, %stderr, "success output");
-            stdout
-                .split("\n\n")
-                .map(parse_section)
-                .collect::<Result<Vec<_>>>()
-                .map(|v| v.into_iter().flatten().collect::<Vec<_>>())
-        })
+        .wrap_err("discovery thread crashed")
+        .and_then(|devices| devices)
         .wrap_err("reading current video devices")
 }
 
+/// only the nodes that produce frames, metadata nodes are left out
+pub async fn list_capture_devices() -> Result<Vec<DetailedVideoDevice>> {
+    list_devices().await.map(|devices| {
+        devices
+            .into_iter()
+            .filter(|device| device.capture)
+            .collect()
+    })
+}
+
 #[derive(Debug)]
 pub struct LoopbackDevice {
     pub loopback_device: VideoDevice,
@@ -213,7 +298,8 @@ pub struct LoopbackDevice {
 
 fn video_nr_from_video(device: &VideoDevice) -> Result<i32> {
     device
-        .0
+        .path()
+        .ok_or_else(|| eyre!("{device} has no device number"))?
         .display()
         .to_string()
         .chars()
@@ -260,6 +346,7 @@ pub async fn get_or_create_loopback_device_for(
         |DetailedVideoDevice {
              video_device,
              details,
+             ..
          }: &DetailedVideoDevice| {
             details
                 .contains(&loopback_label)
@@ -290,31 +377,13 @@ pub async fn get_or_create_loopback_device_for(
         .map(as_loopback_device)
 }
 
-pub async fn ffplay_preview(video_device: VideoDevice) -> Result<Child> {
-    try_enable_low_latency_for_magewell(video_device.clone()).await;
-    let mut command = bounded_command(FFPLAY_PATH);
-    #[cfg(not(debug_assertions))]
-    {
-        command
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .stdin(Stdio::null());
-    }
-    #[cfg(debug_assertions)]
-    {
-        command.stdout(Stdio::piped()).stderr(Stdio::piped());
-    }
-    // apply_video_read_args(&mut command)
-    command
-        .args([arg!(video_device)])
-        .spawn()
-        .wrap_err_with(|| format!("spawning ffplay instance for {video_device:?}"))
-}
-
 pub static MWCAP_CONTROL_LOCK: Lazy<tokio::sync::Mutex<()>> = Lazy::new(Default::default);
 
 #[tracing::instrument]
 pub async fn try_enable_low_latency_for_magewell(video_device: VideoDevice) {
+    if video_device.path().is_none() {
+        return;
+    }
     if let Err(magewell_error) = enable_low_latency_for_magewell(video_device).await {
         tracing::error!(?magewell_error);
     }
@@ -322,12 +391,15 @@ pub async fn try_enable_low_latency_for_magewell(video_device: VideoDevice) {
 
 #[instrument(ret, err, level = "info")]
 pub async fn enable_low_latency_for_magewell(video_device: VideoDevice) -> Result<()> {
+    let path = video_device
+        .path()
+        .ok_or_else(|| eyre!("{video_device} is not a capture card"))?;
     let _guard = MWCAP_CONTROL_LOCK.lock().await;
     tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
     Command::new("mwcap-control")
         .arg("--video-output-lowlatency")
         .arg("on")
-        .arg(video_device.as_ref())
+        .arg(path)
         .output()
         .await
         .wrap_err("spawning command")
@@ -358,68 +430,66 @@ impl FfmpegInstance {
         let process_path = "ffmpeg".to_owned();
         try_enable_low_latency_for_magewell(video_device.clone()).await;
 
-        let preview_process = ffplay_preview(loopback_device.clone())
-            .map_err(|v| v.wrap_err("spawning ffplay"))
-            .and_then(|c| {
-                c.gracefully_shutdown_on_drop()
-                    .map_err(|e| e.wrap_err("making sure it doesn't quit"))
-            })
-            .map_ok(|child| ProcessWatcher::new("ffplay".to_owned(), child, notify.clone()))
-            .map_err(|v| {
-                v.wrap_err(format!(
-                    "creating preview window for device {loopback_device}"
-                ))
-            })
-            .map(RwLock::new)
-            .map(Arc::new)
-            .await;
-        ready(video_file_path(sessions_directory, &project_name))
-            .and_then(|video_file_path| {
-                let mut command = bounded_command(&process_path);
-                ready(
-                    apply_video_read_args(&mut command)
-                        .args(["-thread_queue_size", arg!(512)])
-                        .args(["-r", arg!(RATE)])
-                        .args(["-i", arg!(video_device)])
-                        .args(["-crf", arg!(0)])
-                        .args(["-c:v", arg!("libx264")])
-                        .args(["-preset", arg!("ultrafast")])
-                        .args(["-threads", arg!(8)])
-                        .args([&video_file_path])
-                        .spawn()
-                        .wrap_err("spawning ffmpeg instance"),
-                )
-                .and_then(|child| child.gracefully_shutdown_on_drop())
-                .map_ok({
-                    to_owned![notify];
-                    move |child| ProcessWatcher::new(process_path, child, notify.clone())
+        let preview =
+            preview_window::open_preview(loopback_device.clone(), None, PreviewSink::Window)
+                .map_err(|v| {
+                    v.wrap_err(format!(
+                        "creating preview window for device {loopback_device}"
+                    ))
                 })
-                .map_ok(RwLock::new)
-                .map_ok(Arc::new)
-                .map_ok({
-                    to_owned![notify];
-                    move |process| {
-                        let file_size_updater = tokio::task::spawn(async move {
-                            let mut interval =
-                                crate::process::app_interval(std::time::Duration::from_secs(1));
-
-                            loop {
-                                interval.tick().await;
-                                notify.send(ProcessEvent::NewInput).ok();
-                            }
-                        })
-                        .abort_on_drop();
-
-                        Self {
-                            preview_process,
-                            process,
-                            video_file_path,
-                            _file_size_updater: file_size_updater,
+                .await;
+        ready(video_file_path(
+            sessions_directory,
+            &project_name,
+            &video_device,
+            capture_settings::Container::Mkv,
+        ))
+        .and_then(|video_file_path| {
+            let mut command = bounded_command(&process_path);
+            ready(
+                apply_video_read_args(&mut command)
+                    .args(["-thread_queue_size", arg!(512)])
+                    .args(["-r", arg!(RATE)])
+                    .args(["-i", arg!(video_device)])
+                    .args(["-crf", arg!(0)])
+                    .args(["-c:v", arg!("libx264")])
+                    .args(["-preset", arg!("ultrafast")])
+                    .args(["-threads", arg!(8)])
+                    .args([&video_file_path])
+                    .spawn()
+                    .wrap_err("spawning ffmpeg instance"),
+            )
+            .and_then(|child| child.gracefully_shutdown_on_drop())
+            .map_ok({
+                to_owned![notify];
+                move |child| ProcessWatcher::new(process_path, child, notify.clone())
+            })
+            .map_ok(RwLock::new)
+            .map_ok(Arc::new)
+            .map_ok({
+                to_owned![notify];
+                move |process| {
+                    let file_size_updater = tokio::task::spawn(async move {
+                        let mut interval =
+                            crate::process::app_interval(std::time::Duration::from_secs(1));
+
+                        loop {
+                            interval.tick().await;
+                            notify.send(ProcessEvent::NewInput).ok();
                         }
+                    })
+                    .abort_on_drop();
+
+                    Self {
+                        preview,
+                        process,
+                        video_file_path,
+                        _file_size_updater: file_size_updater,
                     }
-                })
+                }
             })
-            .await
+        })
+        .await
     }
 
     pub fn file_size(&self) -> Result<String> {
@@ -441,7 +511,7 @@ impl RenderToTerm for FfmpegInstance {
         f: &mut Frame<B>,
         rect: tui::layout::Rect,
     ) -> Result<()> {
-        let [file_size_block, ffmpeg_block, ffplay_block]: [Rect; 3] = layout!(Layout::default()
+        let [file_size_block, ffmpeg_block, preview_block]: [Rect; 3] = layout!(Layout::default()
             .direction(Direction::Vertical)
             .constraints([
                 Constraint::Ratio(1, 9),
@@ -467,9 +537,53 @@ impl RenderToTerm for FfmpegInstance {
             file_size_block,
         );
         self.process.write().render_to_term(f, ffmpeg_block)?;
-        self.preview_process
-            .write()
-            .render_to_term(f, ffplay_block)?;
+        f.render_widget(
+            text_block(match &self.preview {
+                Ok(preview) => format!("previewing {}", preview.video_device),
+                Err(message) => format!("no preview: {message:?}"),
+            }),
+            preview_block,
+        );
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_devices_are_written_with_a_prefix() {
+        assert_eq!(
+            VideoDevice::new("test").expect("test device"),
+            VideoDevice::Test("smpte".to_owned())
+        );
+        assert_eq!(
+            VideoDevice::new("test:ball").expect("test device"),
+            VideoDevice::Test("ball".to_owned())
+        );
+        assert_eq!(
+            VideoDevice::new("/dev/video1").expect("camera"),
+            VideoDevice::V4l2("/dev/video1".into())
+        );
+        let test_device = VideoDevice::new_checked("test:ball").expect("always there");
+        assert_eq!(test_device.to_string(), "test:ball");
+        assert_eq!(test_device.label(), "test-ball");
+        assert_eq!(test_device.path(), None);
+        assert_eq!(
+            serde_json::to_string(&test_device).expect("serializing"),
+            r#""test:ball""#
+        );
+    }
+
+    #[test]
+    fn cameras_are_labelled_by_their_device_node() {
+        let camera = VideoDevice::new("/dev/video1").expect("camera");
+        assert_eq!(camera.label(), "video1");
+        assert_eq!(camera.source_element(), "v4l2src device=/dev/video1");
+        assert_eq!(
+            serde_json::from_str::<VideoDevice>(r#""/dev/video1""#).expect("deserializing"),
+            camera
+        );
+    }
+}
@@ -0,0 +1,653 @@
+use super::*;
+use std::{path::Path, str::FromStr};
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    strum::Display,
+    strum::EnumString,
+    strum::EnumIter,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "UPPERCASE")]
+#[strum(serialize_all = "UPPERCASE", ascii_case_insensitive)]
+pub enum PixelFormat {
+    Yuy2,
+    Nv12,
+    I420,
+    /// compressed by the camera itself
+    Mjpg,
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    strum::Display,
+    strum::EnumString,
+    strum::EnumIter,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case", ascii_case_insensitive)]
+pub enum Encoder {
+    X264,
+    X265,
+    /// lossless, huge files
+    Ffv1,
+    /// MJPEG frames straight from the camera, no re-encoding
+    Passthrough,
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    strum::Display,
+    strum::EnumString,
+    strum::EnumIter,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case", ascii_case_insensitive)]
+pub enum Container {
+    Mkv,
+    /// fragmented, so that a crash doesn't lose the whole file
+    Mp4,
+    Mov,
+}
+
+impl Container {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Mkv => "mkv",
+            Self::Mp4 => "mp4",
+            Self::Mov => "mov",
+        }
+    }
+
+    fn muxer(self) -> &'static str {
+        match self {
+            Self::Mkv => "matroskamux",
+            Self::Mp4 => "mp4mux fragment-duration=1000",
+            Self::Mov => "qtmux",
+        }
+    }
+
+    /// every segment gets finalized on its own, so no fragmenting needed
+    fn muxer_factory(self) -> &'static str {
+        match self {
+            Self::Mkv => "matroskamux",
+            Self::Mp4 => "mp4mux",
+            Self::Mov => "qtmux",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct Resolution {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl std::fmt::Display for Resolution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+impl From<Resolution> for String {
+    fn from(value: Resolution) -> Self {
+        value.to_string()
+    }
+}
+
+impl TryFrom<String> for Resolution {
+    type Error = eyre::Report;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl FromStr for Resolution {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.split_once('x')
+            .ok_or_else(|| eyre!("expected WIDTHxHEIGHT, eg. 1920x1080"))
+            .and_then(|(width, height)| {
+                width
+                    .trim()
+                    .parse()
+                    .wrap_err("bad width")
+                    .zip(height.trim().parse().wrap_err("bad height"))
+            })
+            .map(|(width, height)| Self { width, height })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct Framerate {
+    pub numerator: u32,
+    pub denominator: u32,
+}
+
+impl Framerate {
+    pub fn as_f64(self) -> f64 {
+        self.numerator as f64 / self.denominator.max(1) as f64
+    }
+}
+
+impl std::fmt::Display for Framerate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.numerator, self.denominator)
+    }
+}
+
+impl From<Framerate> for String {
+    fn from(value: Framerate) -> Self {
+        value.to_string()
+    }
+}
+
+impl TryFrom<String> for Framerate {
+    type Error = eyre::Report;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl FromStr for Framerate {
+    type Err = eyre::Report;
+
+    /// `25`, `25/1` and `30000/1001` are all fine
+    fn from_str(s: &str) -> Result<Self> {
+        let (numerator, denominator) = s.split_once('/').unwrap_or((s, "1"));
+        numerator
+            .trim()
+            .parse()
+            .wrap_err("bad numerator")
+            .zip(denominator.trim().parse().wrap_err("bad denominator"))
+            .map(|(numerator, denominator)| Self {
+                numerator,
+                denominator,
+            })
+            .wrap_err("expected a framerate like 25 or 30000/1001")
+    }
+}
+
+/// everything that ends up in the capture pipeline
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CaptureSettings {
+    pub resolution: Resolution,
+    pub format: PixelFormat,
+    pub framerate: Framerate,
+    pub encoder: Encoder,
+    /// kbit/s, only used by x264 and x265
+    pub bitrate: u32,
+    pub container: Container,
+    /// start a new file every this many seconds, a crash loses at most one segment
+    #[serde(default)]
+    pub segment_seconds: Option<u32>,
+    /// start a new file once the current one reaches this many megabytes
+    #[serde(default)]
+    pub segment_megabytes: Option<u32>,
+    /// depends on the machine rather than the recording, so it's not kept in the manifest
+    #[serde(skip)]
+    pub preview: PreviewSink,
+}
+
+/// element names the pipeline statistics are read from
+pub const PREVIEW_QUEUE: &str = "preview-queue";
+pub const RECORD_QUEUE: &str = "record-queue";
+pub const ENCODED: &str = "encoded";
+
+/// `<name>---00000.<ext>`, numbered from zero
+pub fn segment_path(output_file: &Path, index: u32) -> PathBuf {
+    output_file.with_file_name(segment_file_name(output_file, &format!("{index:05}")))
+}
+
+/// splitmuxsink `location`, the same names [segment_path] produces
+fn segment_location(output_file: &Path) -> PathBuf {
+    output_file.with_file_name(segment_file_name(output_file, "%05d"))
+}
+
+/// a path as a double quoted gst-launch property value, so spaces and quotes in
+/// session and take names don't split the pipeline description
+fn quoted_path(path: &Path) -> String {
+    format!(
+        "\"{}\"",
+        path.display()
+            .to_string()
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+    )
+}
+
+fn segment_file_name(output_file: &Path, index: &str) -> String {
+    let stem = output_file
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    match output_file.extension() {
+        Some(extension) => format!("{stem}---{index}.{}", extension.to_string_lossy()),
+        None => format!("{stem}---{index}"),
+    }
+}
+
+impl Default for CaptureSettings {
+    fn default() -> Self {
+        Self {
+            resolution: Resolution {
+                width: 1920,
+                height: 1080,
+            },
+            format: PixelFormat::Yuy2,
+            framerate: Framerate {
+                numerator: 25,
+                denominator: 1,
+            },
+            encoder: Encoder::X264,
+            bitrate: 20_000,
+            container: Container::Mkv,
+            segment_seconds: None,
+            segment_megabytes: None,
+            preview: PreviewSink::Window,
+        }
+    }
+}
+
+impl std::fmt::Display for CaptureSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            resolution,
+            format,
+            framerate,
+            encoder,
+            bitrate,
+            container,
+            segment_seconds,
+            segment_megabytes,
+            ..
+        } = self;
+        match encoder {
+            Encoder::X264 | Encoder::X265 => write!(
+                f,
+                "{resolution} {format} @ {framerate} -> {encoder} {bitrate}kbit/s {container}"
+            ),
+            _ => write!(
+                f,
+                "{resolution} {format} @ {framerate} -> {encoder} {container}"
+            ),
+        }?;
+        match (segment_seconds, segment_megabytes) {
+            (None, None) => Ok(()),
+            (seconds, megabytes) => write!(
+                f,
+                " split every {}",
+                seconds
+                    .map(|seconds| format!("{seconds}s"))
+                    .into_iter()
+                    .chain(megabytes.map(|megabytes| format!("{megabytes}MB")))
+                    .join(" / ")
+            ),
+        }
+    }
+}
+
+impl CaptureSettings {
+    pub fn with_preview(self, preview: PreviewSink) -> Self {
+        Self { preview, ..self }
+    }
+
+    pub fn is_segmented(&self) -> bool {
+        self.segment_seconds.is_some() || self.segment_megabytes.is_some()
+    }
+
+    /// every problem is reported, not only the first one
+    pub fn validate(self) -> Result<Self> {
+        let Self {
+            resolution,
+            format,
+            framerate,
+            encoder,
+            bitrate,
+            container,
+            segment_seconds,
+            segment_megabytes,
+            ..
+        } = self;
+        let problems = [
+            (resolution.width == 0 || resolution.height == 0)
+                .then(|| format!("resolution {resolution} is empty")),
+            (framerate.numerator == 0 || framerate.denominator == 0)
+                .then(|| format!("framerate {framerate} is not valid")),
+            (encoder == Encoder::Passthrough && format != PixelFormat::Mjpg).then(|| {
+                format!(
+                    "{encoder} only works with {} cameras, not {format}",
+                    PixelFormat::Mjpg
+                )
+            }),
+            (encoder == Encoder::Ffv1 && container != Container::Mkv).then(|| {
+                format!(
+                    "{encoder} can only be stored in {}, not {container}",
+                    Container::Mkv
+                )
+            }),
+            (matches!(encoder, Encoder::X264 | Encoder::X265) && bitrate == 0)
+                .then(|| format!("{encoder} needs a bitrate")),
+            (segment_seconds == Some(0)).then(|| "segment duration can't be zero".to_owned()),
+            (segment_megabytes == Some(0)).then(|| "segment size can't be zero".to_owned()),
+        ]
+        .into_iter()
+        .flatten()
+        .collect_vec();
+        match problems.is_empty() {
+            true => Ok(self),
+            false => bail!("invalid capture settings: {}", problems.join("; ")),
+        }
+    }
+
+    /// test sources only produce raw frames, MJPEG gets encoded right after them
+    fn source(&self, video_device: &VideoDevice, source_name: &str) -> String {
+        let source = format!(r#"{} name="{source_name}""#, video_device.source_element());
+        match (video_device, self.format) {
+            (VideoDevice::Test(_), PixelFormat::Mjpg) => format!("{source} ! jpegenc"),
+            _ => source,
+        }
+    }
+
+    fn source_caps(&self) -> String {
+        let Self {
+            resolution: Resolution { width, height },
+            format,
+            framerate,
+            ..
+        } = self;
+        match format {
+            PixelFormat::Mjpg => {
+                format!("image/jpeg, width={width}, height={height}, framerate={framerate}")
+            }
+            raw => format!(
+                "video/x-raw, width={width}, height={height}, format={raw}, framerate={framerate}"
+            ),
+        }
+    }
+
+    /// decodes the camera output when the encoder needs raw frames
+    fn decoder(&self) -> &'static str {
+        match (self.format, self.encoder) {
+            (PixelFormat::Mjpg, Encoder::Passthrough) => "",
+            (PixelFormat::Mjpg, _) => "! jpegdec",
+            _ => "",
+        }
+    }
+
+    fn encoder(&self) -> String {
+        let bitrate = self.bitrate;
+        match self.encoder {
+            Encoder::X264 => format!(
+                "! videoconvert ! x264enc bitrate={bitrate} speed-preset=ultrafast tune=zerolatency ! video/x-h264 ! h264parse"
+            ),
+            Encoder::X265 => format!(
+                "! videoconvert ! x265enc bitrate={bitrate} speed-preset=ultrafast tune=zerolatency ! video/x-h265 ! h265parse"
+            ),
+            Encoder::Ffv1 => "! videoconvert ! avenc_ffv1".to_owned(),
+            Encoder::Passthrough => "! jpegparse".to_owned(),
+        }
+    }
+
+    fn preview_branch(&self, sink: PreviewSink) -> String {
+        match self.format {
+            PixelFormat::Mjpg => format!("! jpegdec ! videoconvert ! {}", sink.element()),
+            _ => format!("! {}", sink.element()),
+        }
+    }
+
+    /// the pre-flight preview, the device is opened with the same caps as for recording
+    pub fn preview_pipeline_description(
+        &self,
+        video_device: &VideoDevice,
+        sink: PreviewSink,
+    ) -> String {
+        format!(
+            r#"{source} ! capsfilter caps="{caps}" {preview}"#,
+            source = self.source(video_device, "preview-source"),
+            caps = self.source_caps(),
+            preview = self.preview_branch(sink),
+        )
+    }
+
+    /// `output_file` is only the base name of the segments in segmented mode
+    fn sink(&self, output_file: &Path) -> String {
+        match (self.segment_seconds, self.segment_megabytes) {
+            (None, None) => format!(
+                "! {} ! filesink location={}",
+                self.container.muxer(),
+                quoted_path(output_file)
+            ),
+            (seconds, megabytes) => format!(
+                "! splitmuxsink location={} muxer-factory={} max-size-time={} max-size-bytes={}",
+                quoted_path(&segment_location(output_file)),
+                self.container.muxer_factory(),
+                seconds
+                    .map(|seconds| u64::from(seconds) * 1_000_000_000)
+                    .unwrap_or_default(),
+                megabytes
+                    .map(|megabytes| u64::from(megabytes) * 1024 * 1024)
+                    .unwrap_or_default(),
+            ),
+        }
+    }
+
+    /// gst-launch syntax, `source_name` is used to send EOS when stopping
+    pub fn pipeline_description(
+        &self,
+        video_device: &VideoDevice,
+        source_name: &str,
+        output_file: &Path,
+    ) -> String {
+        format!(
+            r#"
+    {source}
+        ! capsfilter caps="{caps}"
+        ! tee name=t
+            t. ! queue name="{PREVIEW_QUEUE}" {preview}
+            t. ! queue name="{RECORD_QUEUE}"
+                {decoder}
+                {encoder}
+                ! identity name="{ENCODED}"
+                {sink}
+            "#,
+            source = self.source(video_device, source_name),
+            caps = self.source_caps(),
+            preview = self.preview_branch(self.preview),
+            decoder = self.decoder(),
+            encoder = self.encoder(),
+            sink = self.sink(output_file),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolutions_and_framerates_parse() {
+        assert_eq!(
+            "1280x720".parse::<Resolution>().expect("resolution"),
+            Resolution {
+                width: 1280,
+                height: 720
+            }
+        );
+        assert!("1280".parse::<Resolution>().is_err());
+        assert!("widex720".parse::<Resolution>().is_err());
+        assert_eq!(
+            "30000/1001".parse::<Framerate>().expect("framerate"),
+            Framerate {
+                numerator: 30000,
+                denominator: 1001
+            }
+        );
+        assert_eq!(
+            "25".parse::<Framerate>().expect("framerate").to_string(),
+            "25/1"
+        );
+        assert!("fast".parse::<Framerate>().is_err());
+    }
+
+    #[test]
+    fn names_are_case_insensitive() {
+        assert_eq!(
+            "mjpg".parse::<PixelFormat>().expect("format"),
+            PixelFormat::Mjpg
+        );
+        assert_eq!("X265".parse::<Encoder>().expect("encoder"), Encoder::X265);
+        assert_eq!(
+            "MP4".parse::<Container>().expect("container"),
+            Container::Mp4
+        );
+    }
+
+    #[test]
+    fn every_incompatible_combination_is_reported() {
+        assert!(CaptureSettings::default().validate().is_ok());
+        let error = CaptureSettings {
+            encoder: Encoder::Ffv1,
+            container: Container::Mp4,
+            framerate: Framerate {
+                numerator: 25,
+                denominator: 0,
+            },
+            ..Default::default()
+        }
+        .validate()
+        .expect_err("ffv1 in mp4");
+        let message = error.to_string();
+        assert!(
+            message.contains("ffv1 can only be stored in mkv, not mp4"),
+            "{message}"
+        );
+        assert!(message.contains("framerate 25/0 is not valid"), "{message}");
+        assert!(CaptureSettings {
+            encoder: Encoder::Passthrough,
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+        assert!(CaptureSettings {
+            bitrate: 0,
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn pipeline_follows_the_settings() {
+        let device = VideoDevice::new("/dev/video0").expect("video device");
+        let output_file = Path::new("/sessions/song/video-recordings/take.mp4");
+        let default = CaptureSettings::default().pipeline_description(&device, "src", output_file);
+        assert!(default.contains("format=YUY2"), "{default}");
+        assert!(default.contains("x264enc bitrate=20000"), "{default}");
+        assert!(default.contains("matroskamux"), "{default}");
+        assert!(!default.contains("jpegdec"), "{default}");
+        assert!(
+            default.contains(r#"queue name="record-queue""#),
+            "{default}"
+        );
+        assert!(default.contains(r#"identity name="encoded""#), "{default}");
+
+        let mjpeg = CaptureSettings {
+            format: PixelFormat::Mjpg,
+            container: Container::Mp4,
+            ..Default::default()
+        }
+        .pipeline_description(&device, "src", output_file);
+        assert!(mjpeg.contains("image/jpeg, width=1920"), "{mjpeg}");
+        assert!(mjpeg.contains("! jpegdec\n"), "{mjpeg}");
+        assert!(mjpeg.contains("mp4mux fragment-duration=1000"), "{mjpeg}");
+
+        let passthrough = CaptureSettings {
+            format: PixelFormat::Mjpg,
+            encoder: Encoder::Passthrough,
+            ..Default::default()
+        }
+        .pipeline_description(&device, "src", output_file);
+        assert!(passthrough.contains("! jpegparse"), "{passthrough}");
+        assert!(!passthrough.contains("x264enc"), "{passthrough}");
+
+        let test_source = CaptureSettings {
+            format: PixelFormat::Mjpg,
+            ..Default::default()
+        }
+        .pipeline_description(&VideoDevice::Test("ball".to_owned()), "src", output_file);
+        assert!(
+            test_source.contains(r#"videotestsrc is-live=true pattern=ball name="src" ! jpegenc"#),
+            "{test_source}"
+        );
+    }
+
+    #[test]
+    fn segments_are_numbered_next_to_the_output_file() {
+        let output_file = Path::new("/sessions/song/video-recordings/take.mkv");
+        assert_eq!(
+            segment_path(output_file, 3),
+            Path::new("/sessions/song/video-recordings/take---00003.mkv")
+        );
+        assert_eq!(
+            segment_location(output_file),
+            Path::new("/sessions/song/video-recordings/take---%05d.mkv")
+        );
+        assert_eq!(
+            segment_path(Path::new("take"), 0),
+            Path::new("take---00000")
+        );
+    }
+
+    #[test]
+    fn segmented_recordings_use_splitmuxsink() {
+        let device = VideoDevice::new("/dev/video0").expect("video device");
+        let output_file = Path::new("/sessions/song/video-recordings/take.mp4");
+        let settings = CaptureSettings {
+            container: Container::Mp4,
+            segment_seconds: Some(600),
+            ..Default::default()
+        };
+        assert!(settings.is_segmented());
+        assert_eq!(
+            settings.to_string(),
+            "1920x1080 YUY2 @ 25/1 -> x264 20000kbit/s mp4 split every 600s"
+        );
+        let description = settings.pipeline_description(&device, "src", output_file);
+        assert!(
+            description.contains(r#"splitmuxsink location="/sessions/song/video-recordings/take---%05d.mp4" muxer-factory=mp4mux max-size-time=600000000000 max-size-bytes=0"#),
+            "{description}"
+        );
+        assert!(!description.contains("filesink"), "{description}");
+        assert!(CaptureSettings {
+            segment_megabytes: Some(0),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,421 @@
+use super::*;
+use capture_settings::{Framerate, PixelFormat, Resolution};
+
+/// show what the cameras can actually produce
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// only this device, every capture device otherwise
+    #[arg(long, short, value_parser = VideoDevice::new_checked)]
+    video_device: Option<VideoDevice>,
+}
+
+/// a single format/resolution/framerate combination the device can produce
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct CaptureMode {
+    pub format: PixelFormat,
+    pub resolution: Resolution,
+    pub framerate: Framerate,
+}
+
+impl std::fmt::Display for CaptureMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            format,
+            resolution,
+            framerate,
+        } = self;
+        write!(f, "{resolution} {format} @ {framerate}")
+    }
+}
+
+impl CaptureMode {
+    fn of(settings: &CaptureSettings) -> Self {
+        Self {
+            format: settings.format,
+            resolution: settings.resolution,
+            framerate: settings.framerate,
+        }
+    }
+
+    /// 25/1 and 25000/1000 are the same thing
+    fn matches(&self, other: &Self) -> bool {
+        self.format == other.format
+            && self.resolution == other.resolution
+            && (self.framerate.as_f64() - other.framerate.as_f64()).abs() < 0.01
+    }
+
+    /// keeping the pixel format matters most, then the resolution, then the framerate
+    fn distance(&self, other: &Self) -> (bool, u64, u64) {
+        let difference = |one: u32, other: u32| one.abs_diff(other) as u64;
+        (
+            self.format != other.format,
+            difference(self.resolution.width, other.resolution.width)
+                + difference(self.resolution.height, other.resolution.height),
+            ((self.framerate.as_f64() - other.framerate.as_f64()).abs() * 1000.0) as u64,
+        )
+    }
+
+    /// flags that would select this mode instead
+    fn as_args(&self) -> String {
+        let Self {
+            format,
+            resolution,
+            framerate,
+        } = self;
+        format!("--resolution {resolution} --pixel-format {format} --framerate {framerate}")
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DeviceCapabilities {
+    pub video_device: VideoDevice,
+    pub modes: Vec<CaptureMode>,
+}
+
+fn pixel_format_from_fourcc(fourcc: &str) -> Option<PixelFormat> {
+    match fourcc {
+        "YUYV" => Some(PixelFormat::Yuy2),
+        "NV12" => Some(PixelFormat::Nv12),
+        "YU12" => Some(PixelFormat::I420),
+        "MJPG" => Some(PixelFormat::Mjpg),
+        _ => None,
+    }
+}
+
+/// v4l2-ctl only prints the rounded fps, NTSC rates get their 1001 denominator back
+fn framerate_from_fps(fps: f64) -> Option<Framerate> {
+    let ntsc = (fps * 1.001).round();
+    match (
+        fps.is_finite() && fps > 0.0,
+        (fps - fps.round()).abs() < 0.001,
+        (fps * 1.001 - ntsc).abs() < 0.001,
+    ) {
+        (false, ..) => None,
+        (true, true, _) => Some(Framerate {
+            numerator: fps.round() as u32,
+            denominator: 1,
+        }),
+        (true, false, true) => Some(Framerate {
+            numerator: ntsc as u32 * 1000,
+            denominator: 1001,
+        }),
+        (true, false, false) => Some(Framerate {
+            numerator: (fps * 1000.0).round() as u32,
+            denominator: 1000,
+        }),
+    }
+}
+
+/// `--list-formats-ext` output, only discrete sizes and intervals are supported:
+///
+/// ```text
+/// ioctl: VIDIOC_ENUM_FMT
+///     Type: Video Capture
+///
+///     [0]: 'YUYV' (YUYV 4:2:2)
+///         Size: Discrete 1920x1080
+///             Interval: Discrete 0.040s (25.000 fps)
+/// ```
+fn parse_formats(output: &str) -> Vec<CaptureMode> {
+    output
+        .lines()
+        .map(str::trim)
+        .fold(
+            (None::<PixelFormat>, None::<Resolution>, vec![]),
+            |(format, resolution, mut modes), line| {
+                if line.starts_with('[') {
+                    let fourcc = line.split('\'').nth(1).unwrap_or_default();
+                    let format = pixel_format_from_fourcc(fourcc);
+                    if format.is_none() {
+                        tracing::debug!(%fourcc, "skipping unsupported pixel format");
+                    }
+                    return (format, None, modes);
+                }
+                if let Some(size) = line.strip_prefix("Size: Discrete ") {
+                    return (format, size.parse().ok(), modes);
+                }
+                if let Some(interval) = line.strip_prefix("Interval: Discrete ") {
+                    let framerate = interval
+                        .split_once('(')
+                        .and_then(|(_, fps)| fps.trim_end_matches("fps)").trim().parse().ok())
+                        .and_then(framerate_from_fps);
+                    if let (Some(format), Some(resolution), Some(framerate)) =
+                        (format, resolution, framerate)
+                    {
+                        modes.push(CaptureMode {
+                            format,
+                            resolution,
+                            framerate,
+                        });
+                    }
+                    return (format, resolution, modes);
+                }
+                if line.starts_with("Size: ") || line.starts_with("Interval: ") {
+                    tracing::debug!(%line, "skipping non-discrete size or interval");
+                }
+                (format, resolution, modes)
+            },
+        )
+        .2
+}
+
+impl DeviceCapabilities {
+    #[tracing::instrument(ret, err, level = "DEBUG")]
+    pub async fn probe(video_device: VideoDevice) -> Result<Self> {
+        // videotestsrc produces whatever it's asked for
+        let Some(path) = video_device.path() else {
+            return Ok(Self {
+                modes: vec![],
+                video_device,
+            });
+        };
+        Command::new("v4l2-ctl")
+            .arg("--device")
+            .arg(path)
+            .arg("--list-formats-ext")
+            .output()
+            .await
+            .wrap_err("reading command output")
+            .and_then(|output| output.success_output())
+            .map(|SuccessOutput { stdout, .. }| Self {
+                modes: parse_formats(&stdout),
+                video_device: video_device.clone(),
+            })
+            .wrap_err_with(|| format!("reading capabilities of {video_device}"))
+    }
+
+    pub fn nearest(&self, settings: &CaptureSettings) -> Option<CaptureMode> {
+        let wanted = CaptureMode::of(settings);
+        self.modes
+            .iter()
+            .copied()
+            .min_by_key(|mode| mode.distance(&wanted))
+    }
+
+    /// devices that only report stepwise/continuous modes can't be checked and are let through
+    pub fn check(&self, settings: &CaptureSettings) -> Result<()> {
+        let wanted = CaptureMode::of(settings);
+        if self.modes.is_empty() || self.modes.iter().any(|mode| mode.matches(&wanted)) {
+            return Ok(());
+        }
+        match self.nearest(settings) {
+            Some(nearest) => bail!(
+                "{} can't capture {wanted}, nearest supported mode is {nearest} ({})",
+                self.video_device,
+                nearest.as_args()
+            ),
+            None => bail!("{} can't capture {wanted}", self.video_device),
+        }
+    }
+}
+
+impl std::fmt::Display for DeviceCapabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.video_device)?;
+        if self.modes.is_empty() {
+            return writeln!(f, "  no discrete capture modes reported");
+        }
+        self.modes
+            .iter()
+            .group_by(|mode| (mode.format, mode.resolution))
+            .into_iter()
+            .try_for_each(|((format, resolution), modes)| {
+                writeln!(
+                    f,
+                    "  {resolution} {format} @ {}",
+                    modes.map(|mode| mode.framerate).join(", ")
+                )
+            })
+    }
+}
+
+/// a device that can't be probed can't be recorded from either, so that is refused up front too
+#[instrument(ret, err, level = "INFO")]
+pub async fn check_supported(video_device: VideoDevice, settings: CaptureSettings) -> Result<()> {
+    DeviceCapabilities::probe(video_device)
+        .await
+        .wrap_err("checking capture settings before recording")
+        .and_then(|capabilities| capabilities.check(&settings))
+}
+
+pub async fn run(Args { video_device }: Args) -> Result<()> {
+    let video_devices = match video_device {
+        Some(video_device) => vec![video_device],
+        None => list_capture_devices()
+            .await?
+            .into_iter()
+            .map(|device| device.video_device)
+            .collect(),
+    };
+    futures::future::join_all(video_devices.into_iter().map(DeviceCapabilities::probe))
+        .await
+        .into_iter()
+        .for_each(|capabilities| match capabilities {
+            Ok(capabilities) => println!("{capabilities}"),
+            Err(message) => println!("{message:#}\n"),
+        });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Logitech C920
+    const WEBCAM: &str = "ioctl: VIDIOC_ENUM_FMT
+	Type: Video Capture
+
+	[0]: 'YUYV' (YUYV 4:2:2)
+		Size: Discrete 640x480
+			Interval: Discrete 0.033s (30.000 fps)
+			Interval: Discrete 0.042s (24.000 fps)
+			Interval: Discrete 0.133s (7.500 fps)
+		Size: Discrete 1920x1080
+			Interval: Discrete 0.200s (5.000 fps)
+	[1]: 'H264' (H.264, compressed)
+		Size: Discrete 1920x1080
+			Interval: Discrete 0.033s (30.000 fps)
+	[2]: 'MJPG' (Motion-JPEG, compressed)
+		Size: Discrete 1920x1080
+			Interval: Discrete 0.033s (30.000 fps)
+			Interval: Discrete 0.042s (24.000 fps)
+";
+
+    /// HDMI capture card
+    const CAPTURE_CARD: &str = "ioctl: VIDIOC_ENUM_FMT
+	Type: Video Capture
+
+	[0]: 'YUYV' (YUYV 4:2:2)
+		Size: Stepwise 16x16 - 1920x1080 with step 1/1
+	[1]: 'NV12' (Y/CbCr 4:2:0)
+		Size: Discrete 1920x1080
+			Interval: Discrete 0.033s (29.970 fps)
+			Interval: Continuous 0.017s - 1.000s with step 0.000s (1.000-60.000 fps)
+";
+
+    fn mode(format: PixelFormat, resolution: &str, framerate: &str) -> CaptureMode {
+        CaptureMode {
+            format,
+            resolution: resolution.parse().expect("resolution"),
+            framerate: framerate.parse().expect("framerate"),
+        }
+    }
+
+    fn webcam() -> DeviceCapabilities {
+        DeviceCapabilities {
+            video_device: VideoDevice::new("/dev/video0").expect("video device"),
+            modes: parse_formats(WEBCAM),
+        }
+    }
+
+    #[test]
+    fn discrete_modes_of_known_formats_are_read() {
+        assert_eq!(
+            parse_formats(WEBCAM),
+            vec![
+                mode(PixelFormat::Yuy2, "640x480", "30"),
+                mode(PixelFormat::Yuy2, "640x480", "24"),
+                mode(PixelFormat::Yuy2, "640x480", "7500/1000"),
+                mode(PixelFormat::Yuy2, "1920x1080", "5"),
+                mode(PixelFormat::Mjpg, "1920x1080", "30"),
+                mode(PixelFormat::Mjpg, "1920x1080", "24"),
+            ]
+        );
+        assert_eq!(
+            parse_formats(CAPTURE_CARD),
+            vec![mode(PixelFormat::Nv12, "1920x1080", "30000/1001")]
+        );
+        assert!(parse_formats("").is_empty());
+    }
+
+    #[test]
+    fn rounded_fps_are_turned_back_into_fractions() {
+        assert_eq!(
+            framerate_from_fps(25.0),
+            Some("25/1".parse().expect("framerate"))
+        );
+        assert_eq!(
+            framerate_from_fps(29.970),
+            Some("30000/1001".parse().expect("framerate"))
+        );
+        assert_eq!(
+            framerate_from_fps(59.940),
+            Some("60000/1001".parse().expect("framerate"))
+        );
+        assert_eq!(
+            framerate_from_fps(7.5),
+            Some("7500/1000".parse().expect("framerate"))
+        );
+        assert_eq!(framerate_from_fps(0.0), None);
+        assert_eq!(framerate_from_fps(f64::NAN), None);
+    }
+
+    #[test]
+    fn supported_settings_pass() {
+        let webcam = webcam();
+        webcam
+            .check(&CaptureSettings {
+                format: PixelFormat::Mjpg,
+                framerate: "30000/1000".parse().expect("framerate"),
+                ..Default::default()
+            })
+            .expect("MJPG 1080p30 is listed");
+        DeviceCapabilities {
+            modes: vec![],
+            ..webcam
+        }
+        .check(&CaptureSettings::default())
+        .expect("nothing to check against");
+    }
+
+    #[tokio::test]
+    async fn devices_that_cant_be_probed_are_refused() {
+        let message = check_supported(
+            VideoDevice::new("/dev/no-such-video-device").expect("video device"),
+            CaptureSettings::default(),
+        )
+        .await
+        .expect_err("nothing to probe");
+        assert!(
+            format!("{message:#}").contains("reading capabilities of /dev/no-such-video-device"),
+            "{message:#}"
+        );
+    }
+
+    #[test]
+    fn unsupported_settings_are_rejected_with_the_nearest_mode() {
+        let webcam = webcam();
+        let message = webcam
+            .check(&CaptureSettings::default())
+            .expect_err("no 1080p25 YUYV")
+            .to_string();
+        assert!(
+            message.contains("/dev/video0 can't capture 1920x1080 YUY2 @ 25/1"),
+            "{message}"
+        );
+        assert!(
+            message.contains("--resolution 1920x1080 --pixel-format YUY2 --framerate 5/1"),
+            "{message}"
+        );
+        let message = webcam
+            .check(&CaptureSettings {
+                format: PixelFormat::I420,
+                resolution: "640x480".parse().expect("resolution"),
+                framerate: "30".parse().expect("framerate"),
+                ..Default::default()
+            })
+            .expect_err("no I420 at all")
+            .to_string();
+        assert!(
+            message.contains("nearest supported mode is 640x480 YUY2 @ 30/1"),
+            "{message}"
+        );
+        assert!(webcam
+            .check(&CaptureSettings {
+                format: PixelFormat::Mjpg,
+                framerate: "30000/1001".parse().expect("framerate"),
+                ..Default::default()
+            })
+            .is_err());
+    }
+}
@@ -0,0 +1,490 @@
+use super::*;
+use std::{collections::BTreeMap, os::fd::AsRawFd};
+
+const SYSFS_VIDEO4LINUX: &str = "/sys/class/video4linux";
+/// udev symlinks, by-id has the vendor, model and serial, by-path the port it's plugged into
+const STABLE_LINK_DIRECTORIES: [&str; 2] = ["/dev/v4l/by-id", "/dev/v4l/by-path"];
+
+const V4L2_CAP_VIDEO_CAPTURE: u32 = 0x0000_0001;
+const V4L2_CAP_VIDEO_CAPTURE_MPLANE: u32 = 0x0000_1000;
+/// `device_caps` is filled in, `capabilities` describes the whole physical device otherwise
+const V4L2_CAP_DEVICE_CAPS: u32 = 0x8000_0000;
+
+mod ioctl {
+    /// `struct v4l2_capability` from `linux/videodev2.h`
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct V4l2Capability {
+        pub driver: [u8; 16],
+        pub card: [u8; 32],
+        pub bus_info: [u8; 32],
+        pub version: u32,
+        pub capabilities: u32,
+        pub device_caps: u32,
+        pub reserved: [u32; 3],
+    }
+
+    nix::ioctl_read!(vidioc_querycap, b'V', 0, V4l2Capability);
+}
+
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim().to_owned()
+}
+
+#[derive(Debug, Clone)]
+struct QueriedCapabilities {
+    driver: String,
+    card: String,
+    bus_info: String,
+    capture: bool,
+}
+
+fn query_capabilities(device: &Path) -> Result<QueriedCapabilities> {
+    let file = std::fs::File::open(device).wrap_err("opening device")?;
+    let mut capability = ioctl::V4l2Capability::default();
+    // SAFETY: the struct matches the kernel layout and lives for the whole call
+    unsafe { ioctl::vidioc_querycap(file.as_raw_fd(), &mut capability) }
+        .wrap_err("VIDIOC_QUERYCAP")?;
+    let capabilities = match capability.capabilities & V4L2_CAP_DEVICE_CAPS {
+        0 => capability.capabilities,
+        _ => capability.device_caps,
+    };
+    Ok(QueriedCapabilities {
+        driver: c_string(&capability.driver),
+        card: c_string(&capability.card),
+        bus_info: c_string(&capability.bus_info),
+        capture: capabilities & (V4L2_CAP_VIDEO_CAPTURE | V4L2_CAP_VIDEO_CAPTURE_MPLANE) != 0,
+    })
+}
+
+fn read_attribute(node: &Path, attribute: &str) -> Option<String> {
+    std::fs::read_to_string(node.join(attribute))
+        .ok()
+        .map(|value| value.trim().to_owned())
+}
+
+/// `/dev/videoN` to every udev symlink pointing at it
+fn stable_links(directories: &[&Path]) -> BTreeMap<PathBuf, Vec<PathBuf>> {
+    directories
+        .iter()
+        .filter_map(|directory| std::fs::read_dir(directory).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter_map(|link| {
+            std::fs::canonicalize(&link)
+                .ok()
+                .map(|device| (device, link))
+        })
+        .into_group_map()
+        .into_iter()
+        .map(|(device, links)| (device, links.into_iter().sorted().collect()))
+        .collect()
+}
+
+impl DetailedVideoDevice {
+    fn read(node: &Path, stable_links: &BTreeMap<PathBuf, Vec<PathBuf>>) -> Option<Self> {
+        let device = PathBuf::from("/dev").join(node.file_name()?);
+        let index = read_attribute(node, "index").and_then(|index| index.parse().ok());
+        let queried = query_capabilities(&device)
+            .map_err(|message| tracing::debug!(?message, ?device, "capabilities unknown"))
+            .ok();
+        let name = read_attribute(node, "name")
+            .or_else(|| queried.as_ref().map(|queried| queried.card.clone()))
+            .unwrap_or_default();
+        let bus_info = queried.as_ref().map(|queried| queried.bus_info.clone());
+        Some(Self {
+            // the way `v4l2-ctl --list-devices` prints it
+            details: match &bus_info {
+                Some(bus_info) => format!("{name} ({bus_info})"),
+                None => name.clone(),
+            },
+            name,
+            driver: std::fs::read_link(node.join("device/driver"))
+                .ok()
+                .and_then(|driver| {
+                    driver
+                        .file_name()
+                        .map(|name| name.to_string_lossy().to_string())
+                })
+                .or_else(|| queried.as_ref().map(|queried| queried.driver.clone())),
+            bus_info,
+            // the interface node points at the USB device, which is the one with the serial
+            serial: read_attribute(node, "device/../serial"),
+            capture: queried
+                .as_ref()
+                .map(|queried| queried.capture)
+                .unwrap_or(index.unwrap_or_default() == 0),
+            stable_paths: stable_links.get(&device).cloned().unwrap_or_default(),
+            index,
+            video_device: VideoDevice::V4l2(device),
+        })
+    }
+}
+
+/// every video4linux node the kernel knows about, sorted by device path
+#[tracing::instrument(ret, err, level = "DEBUG")]
+pub fn discover() -> Result<Vec<DetailedVideoDevice>> {
+    discover_in(
+        Path::new(SYSFS_VIDEO4LINUX),
+        &stable_links(&STABLE_LINK_DIRECTORIES.map(Path::new)),
+    )
+}
+
+fn discover_in(
+    sysfs: &Path,
+    stable_links: &BTreeMap<PathBuf, Vec<PathBuf>>,
+) -> Result<Vec<DetailedVideoDevice>> {
+    std::fs::read_dir(sysfs)
+        .wrap_err_with(|| format!("reading {}", sysfs.display()))
+        .map(|nodes| {
+            nodes
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|node| {
+                    node.file_name()
+                        .map(|name| name.to_string_lossy().starts_with("video"))
+                        .unwrap_or_default()
+                })
+                .filter_map(|node| DetailedVideoDevice::read(&node, stable_links))
+                .sorted_by(|one, other| one.video_device.cmp(&other.video_device))
+                .collect()
+        })
+}
+
+/// a camera described by something that doesn't change when `/dev/videoN` gets renumbered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    /// `card:"Magewell Pro Capture"`, the name the driver reports, with the bus in
+    /// parentheses when there are several identical cards
+    Card(String),
+    /// `by-id:usb-Logitech_HD_Pro_Webcam_C920_1234ABCD-video-index0`
+    ById(String),
+    /// `serial:1234ABCD`, only USB devices report one
+    Serial(String),
+}
+
+impl std::fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Card(card) => write!(f, "card:\"{card}\""),
+            Self::ById(id) => write!(f, "by-id:{id}"),
+            Self::Serial(serial) => write!(f, "serial:{serial}"),
+        }
+    }
+}
+
+impl DeviceSelector {
+    /// `None` for anything that isn't a selector, eg. a plain device path
+    pub fn parse(value: &str) -> Option<Self> {
+        let (kind, selected) = value.split_once(':')?;
+        let selected = selected.trim().trim_matches('"').to_owned();
+        match kind.trim() {
+            "card" => Some(Self::Card(selected)),
+            "by-id" => Some(Self::ById(selected)),
+            "serial" => Some(Self::Serial(selected)),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, device: &DetailedVideoDevice) -> bool {
+        match self {
+            Self::Card(card) => {
+                device.name.eq_ignore_ascii_case(card) || device.details.eq_ignore_ascii_case(card)
+            }
+            Self::ById(id) => device.stable_paths.iter().any(|path| {
+                path.file_name()
+                    .map(|name| name == id.as_str())
+                    .unwrap_or_default()
+            }),
+            Self::Serial(serial) => device.serial.as_deref() == Some(serial.as_str()),
+        }
+    }
+
+    /// metadata nodes are skipped, they share the card name and serial with the capture node
+    pub fn resolve(&self, devices: &[DetailedVideoDevice]) -> Result<VideoDevice> {
+        let matching = devices
+            .iter()
+            .filter(|device| device.capture && self.matches(device))
+            .collect_vec();
+        match matching.as_slice() {
+            [device] => Ok(device.video_device.clone()),
+            [] => bail!(
+                "no capture device matches {self}, available: {}",
+                devices
+                    .iter()
+                    .filter(|device| device.capture)
+                    .map(|device| format!("{} ({})", device.video_device, device.details))
+                    .join(", ")
+            ),
+            many => bail!(
+                "{self} matches {}, pick one of them with by-id:",
+                many.iter()
+                    .map(|device| {
+                        device
+                            .stable_paths
+                            .iter()
+                            .find(|path| path.starts_with(STABLE_LINK_DIRECTORIES[0]))
+                            .and_then(|path| path.file_name())
+                            .map(|id| format!("{} ({})", device.video_device, id.to_string_lossy()))
+                            .unwrap_or_else(|| device.video_device.to_string())
+                    })
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a sysfs node the way the kernel lays it out, nothing behind it in /dev
+    fn sysfs_node(sysfs: &Path, node: &str, name: &str, index: u32) {
+        let node = sysfs.join(node);
+        std::fs::create_dir_all(&node).expect("sysfs node");
+        std::fs::write(node.join("name"), format!("{name}\n")).expect("name");
+        std::fs::write(node.join("index"), format!("{index}\n")).expect("index");
+    }
+
+    #[test]
+    fn kernel_strings_end_at_the_first_nul() {
+        assert_eq!(c_string(b"uvcvideo\0\0\0garbage"), "uvcvideo");
+        assert_eq!(c_string(b"no terminator "), "no terminator");
+    }
+
+    #[test]
+    fn stable_links_are_grouped_by_the_device_they_point_at() {
+        let root = tempfile::tempdir().expect("temp dir");
+        let (dev, by_id, by_path) = (
+            root.path().join("dev"),
+            root.path().join("by-id"),
+            root.path().join("by-path"),
+        );
+        [&dev, &by_id, &by_path]
+            .iter()
+            .for_each(|directory| std::fs::create_dir(directory).expect("directory"));
+        ["video0", "video2"]
+            .iter()
+            .for_each(|node| std::fs::write(dev.join(node), "").expect("device node"));
+        let link = |target: &str, link: PathBuf| {
+            std::os::unix::fs::symlink(dev.join(target), link).expect("symlink")
+        };
+        link(
+            "video0",
+            by_id.join("usb-046d_HD_Pro_Webcam_C920_ABCD1234-video-index0"),
+        );
+        link(
+            "video0",
+            by_path.join("pci-0000:00:14.0-usb-0:2:1.0-video-index0"),
+        );
+        link(
+            "video2",
+            by_path.join("pci-0000:00:14.0-usb-0:3:1.0-video-index0"),
+        );
+        link("video9", by_path.join("unplugged-video-index0"));
+
+        let links = stable_links(&[&by_id, &by_path, &root.path().join("missing")]);
+        let canonical = |node: &str| std::fs::canonicalize(dev.join(node)).expect("device node");
+        assert_eq!(links.len(), 2, "{links:?}");
+        assert_eq!(
+            links[&canonical("video0")],
+            [
+                by_id.join("usb-046d_HD_Pro_Webcam_C920_ABCD1234-video-index0"),
+                by_path.join("pci-0000:00:14.0-usb-0:2:1.0-video-index0"),
+            ]
+        );
+        assert_eq!(links[&canonical("video2")].len(), 1);
+    }
+
+    #[test]
+    fn sysfs_nodes_are_described() {
+        let sysfs = tempfile::tempdir().expect("temp dir");
+        sysfs_node(sysfs.path(), "video91", "HD Pro Webcam C920", 1);
+        sysfs_node(sysfs.path(), "video90", "HD Pro Webcam C920", 0);
+        std::fs::create_dir(sysfs.path().join("vbi0")).expect("not a video node");
+        // `device` is a link to the USB interface in a real sysfs, `..` is the USB device
+        std::fs::create_dir(sysfs.path().join("video90/device")).expect("device");
+        std::fs::write(sysfs.path().join("video90/serial"), "ABCD1234\n").expect("serial");
+        let by_id =
+            PathBuf::from("/dev/v4l/by-id/usb-046d_HD_Pro_Webcam_C920_ABCD1234-video-index0");
+        let stable_links = [(PathBuf::from("/dev/video90"), vec![by_id.clone()])]
+            .into_iter()
+            .collect();
+
+        let devices = discover_in(sysfs.path(), &stable_links).expect("discovering");
+        assert_eq!(
+            devices
+                .iter()
+                .map(|device| device.video_device.to_string())
+                .collect_vec(),
+            ["/dev/video90", "/dev/video91"]
+        );
+        let [capture, metadata] = &devices[..] else {
+            panic!("two nodes expected");
+        };
+        assert_eq!(capture.name, "HD Pro Webcam C920");
+        assert_eq!(capture.details, "HD Pro Webcam C920");
+        assert_eq!(capture.index, Some(0));
+        assert_eq!(capture.serial.as_deref(), Some("ABCD1234"));
+        assert_eq!(metadata.serial, None);
+        // can't be opened, so only the first node is assumed to capture
+        assert!(capture.capture);
+        assert!(!metadata.capture);
+        assert_eq!(capture.stable_paths, [by_id]);
+        assert!(metadata.stable_paths.is_empty());
+        assert!(discover_in(&sysfs.path().join("missing"), &stable_links).is_err());
+    }
+
+    fn device(
+        node: &str,
+        details: &str,
+        capture: bool,
+        serial: Option<&str>,
+    ) -> DetailedVideoDevice {
+        let name = details
+            .split_once(" (")
+            .map(|(name, _)| name)
+            .unwrap_or(details);
+        DetailedVideoDevice {
+            video_device: VideoDevice::V4l2(PathBuf::from("/dev").join(node)),
+            details: details.to_owned(),
+            name: name.to_owned(),
+            index: Some(u32::from(!capture)),
+            driver: None,
+            bus_info: None,
+            serial: serial.map(str::to_owned),
+            capture,
+            stable_paths: vec![],
+        }
+    }
+
+    fn with_id(device: DetailedVideoDevice, id: &str) -> DetailedVideoDevice {
+        DetailedVideoDevice {
+            stable_paths: vec![Path::new(STABLE_LINK_DIRECTORIES[0]).join(id)],
+            ..device
+        }
+    }
+
+    /// two identical capture cards, a webcam with its metadata node
+    fn discovered() -> Vec<DetailedVideoDevice> {
+        vec![
+            device(
+                "video0",
+                "Magewell Pro Capture (PCI:0000:03:00.0)",
+                true,
+                None,
+            ),
+            device(
+                "video1",
+                "Magewell Pro Capture (PCI:0000:04:00.0)",
+                true,
+                None,
+            ),
+            with_id(
+                device("video2", "HD Pro Webcam C920", true, Some("ABCD1234")),
+                "usb-046d_HD_Pro_Webcam_C920_ABCD1234-video-index0",
+            ),
+            with_id(
+                device("video3", "HD Pro Webcam C920", false, Some("ABCD1234")),
+                "usb-046d_HD_Pro_Webcam_C920_ABCD1234-video-index1",
+            ),
+        ]
+    }
+
+    #[test]
+    fn selectors_are_told_apart_from_paths() {
+        assert_eq!(
+            DeviceSelector::parse(r#"card:"Magewell Pro Capture""#),
+            Some(DeviceSelector::Card("Magewell Pro Capture".to_owned()))
+        );
+        assert_eq!(
+            DeviceSelector::parse("by-id:usb-046d_HD_Pro_Webcam_C920_ABCD1234-video-index0"),
+            Some(DeviceSelector::ById(
+                "usb-046d_HD_Pro_Webcam_C920_ABCD1234-video-index0".to_owned()
+            ))
+        );
+        assert_eq!(
+            DeviceSelector::parse(" serial: ABCD1234"),
+            Some(DeviceSelector::Serial("ABCD1234".to_owned()))
+        );
+        assert_eq!(DeviceSelector::parse("/dev/video1"), None);
+        assert_eq!(DeviceSelector::parse("test:ball"), None);
+        assert_eq!(DeviceSelector::parse("usb:1234"), None);
+        assert_eq!(
+            DeviceSelector::Card("Magewell Pro Capture".to_owned()).to_string(),
+            r#"card:"Magewell Pro Capture""#
+        );
+    }
+
+    #[test]
+    fn selectors_resolve_to_the_capture_node() {
+        let devices = discovered();
+        let resolve = |value: &str| {
+            DeviceSelector::parse(value)
+                .expect("selector")
+                .resolve(&devices)
+        };
+        let webcam = VideoDevice::V4l2("/dev/video2".into());
+        assert_eq!(resolve("serial:ABCD1234").expect("serial"), webcam);
+        assert_eq!(
+            resolve(r#"card:"hd pro webcam c920""#).expect("card"),
+            webcam
+        );
+        assert_eq!(
+            resolve("by-id:usb-046d_HD_Pro_Webcam_C920_ABCD1234-video-index0").expect("by-id"),
+            webcam
+        );
+        assert!(resolve("by-id:usb-046d_HD_Pro_Webcam_C920_ABCD1234-video-index1").is_err());
+        assert_eq!(
+            resolve(r#"card:"Magewell Pro Capture (PCI:0000:04:00.0)""#).expect("card with bus"),
+            VideoDevice::V4l2("/dev/video1".into())
+        );
+    }
+
+    #[test]
+    fn missing_and_ambiguous_selectors_say_what_is_there() {
+        let devices = discovered();
+        let message = DeviceSelector::Serial("FFFF".to_owned())
+            .resolve(&devices)
+            .expect_err("no such serial")
+            .to_string();
+        assert!(
+            message.contains("no capture device matches serial:FFFF"),
+            "{message}"
+        );
+        assert!(
+            message.contains("/dev/video2 (HD Pro Webcam C920)"),
+            "{message}"
+        );
+        assert!(!message.contains("/dev/video3"), "{message}");
+
+        let message = DeviceSelector::Card("Magewell Pro Capture".to_owned())
+            .resolve(&devices)
+            .expect_err("two identical cards")
+            .to_string();
+        assert!(
+            message.contains(r#"card:"Magewell Pro Capture" matches /dev/video0, /dev/video1"#),
+            "{message}"
+        );
+
+        let message = DeviceSelector::Card("HD Pro Webcam C920".to_owned())
+            .resolve(&[
+                discovered()[2].clone(),
+                with_id(
+                    device("video4", "HD Pro Webcam C920", true, Some("EFGH5678")),
+                    "usb-046d_HD_Pro_Webcam_C920_EFGH5678-video-index0",
+                ),
+            ])
+            .expect_err("two identical webcams")
+            .to_string();
+        assert!(
+            message.contains("/dev/video4 (usb-046d_HD_Pro_Webcam_C920_EFGH5678-video-index0)"),
+            "{message}"
+        );
+    }
+}
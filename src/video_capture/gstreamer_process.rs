@@ -1,54 +1,183 @@
 use super::*;
+use crate::session_manifest::{ChildExitStatus, VideoSegments};
 use tokio_util::sync::CancellationToken;
+use tui::text::{Spans, Text};
 pub mod low_level;
+pub mod pipeline_messages;
+pub mod pipeline_stats;
 
 #[derive(Debug)]
 pub struct GstreamerInstance {
     pub video_device: VideoDevice,
+    pub settings: CaptureSettings,
+    /// only the base name of the segments when recording in segments
     pub video_file_path: PathBuf,
+    pub segments: VideoSegments,
+    pub stats: pipeline_stats::SharedPipelineStats,
+    pub log: pipeline_messages::PipelineLog,
+    /// wall clock time of pipeline running time zero, video timestamps are relative to it
+    pub started_at: ProjectTime,
+    /// missing when the pipeline never reported its start
+    pub clock: Option<low_level::PipelineClock>,
+    /// `started_at` of the first pipeline of this camera, segments stay relative to it across restarts
+    pub time_base: ProjectTime,
+    pub pipeline: String,
+    pub exit_status: ChildExitStatus,
     cancel: CancellationToken,
     _process: AbortOnDrop<Result<()>>,
     _file_size_updater: AbortOnDrop<()>,
 }
 
+/// v4l2 devices can take a while to negotiate
+const STARTED_AT_DEADLINE: std::time::Duration = std::time::Duration::from_secs(10);
+
 impl GstreamerInstance {
+    /// the file REAPER gets to see, the first segment when recording in segments
+    pub fn first_file(&self) -> PathBuf {
+        match self.settings.is_segmented() {
+            true => capture_settings::segment_path(&self.video_file_path, 0),
+            false => self.video_file_path.clone(),
+        }
+    }
+
     pub fn file_size(&self) -> Result<String> {
-        self.video_file_path
-            .metadata()
-            .wrap_err("reading file metadata")
-            .map(|m| m.len())
-            .map(|size| {
-                byte_unit::Byte::from_bytes(size as _)
-                    .get_appropriate_unit(true)
-                    .to_string()
-            })
+        let segments = self.segments.read().clone();
+        match segments.is_empty() {
+            true => vec![self.first_file()],
+            false => segments.into_iter().map(|segment| segment.path).collect(),
+        }
+        .iter()
+        .map(|path| {
+            path.metadata()
+                .wrap_err_with(|| format!("reading metadata of {}", path.display()))
+                .map(|m| m.len())
+        })
+        .sum::<Result<u64>>()
+        .map(|size| {
+            byte_unit::Byte::from_bytes(size as _)
+                .get_appropriate_unit(true)
+                .to_string()
+        })
+    }
+
+    /// wall clock time of the last frame from the camera, the start of the pipeline without any
+    pub fn last_frame_at(&self) -> ProjectTime {
+        self.started_at
+            + self
+                .stats
+                .read()
+                .last_timestamp()
+                .map(|timestamp| chrono::Duration::nanoseconds(timestamp.nseconds() as i64))
+                .unwrap_or_else(chrono::Duration::zero)
+    }
+
+    pub fn is_running(&self) -> bool {
+        !(self.cancel.is_cancelled() || self._process.0.is_finished())
     }
+
+    /// sends EOS so that the muxer finalizes the file
+    pub fn stop(&self) {
+        self.cancel.cancel();
+    }
+
     pub async fn new(
         video_device: VideoDevice,
+        settings: CaptureSettings,
+        output_file_path: PathBuf,
+        notify: ProcessEventBus,
+    ) -> Result<Self> {
+        Self::start(
+            video_device,
+            settings,
+            output_file_path,
+            pipeline_messages::PipelineLog::new(notify.clone()),
+            VideoSegments::default(),
+            ChildExitStatus::default(),
+            None,
+            notify,
+        )
+        .await
+    }
+
+    /// same device and settings into a new file, the log, segments, exit status and time base carry over
+    pub fn restart(
+        &self,
+        output_file_path: PathBuf,
+        notify: ProcessEventBus,
+    ) -> impl std::future::Future<Output = Result<Self>> {
+        Self::start(
+            self.video_device.clone(),
+            self.settings,
+            output_file_path,
+            self.log.clone(),
+            self.segments.clone(),
+            self.exit_status.clone(),
+            Some(self.time_base),
+            notify,
+        )
+    }
+
+    async fn start(
+        video_device: VideoDevice,
+        settings: CaptureSettings,
         output_file_path: PathBuf,
+        log: pipeline_messages::PipelineLog,
+        segments: VideoSegments,
+        exit_status: ChildExitStatus,
+        time_base: Option<ProjectTime>,
         notify: ProcessEventBus,
     ) -> Result<Self> {
         let cancel = CancellationToken::new();
+        let spawned_at = crate::now();
+        let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+        let pipeline = low_level::pipeline_description(&video_device, &settings, &output_file_path);
+        // still running as far as the manifest is concerned
+        exit_status.write().take();
+        let stats = pipeline_stats::SharedPipelineStats::default();
+        let messages = log.bus();
         let process = {
-            to_owned![cancel, video_device, output_file_path];
+            to_owned![
+                cancel,
+                video_device,
+                output_file_path,
+                exit_status,
+                segments,
+                stats
+            ];
             tokio::task::spawn_blocking(move || {
-                low_level::start_stream(
+                let res = low_level::start_stream(
                     video_device.clone(),
+                    settings,
                     output_file_path.clone(),
                     cancel.clone(),
-                )
+                    Some(started_tx),
+                    time_base,
+                    segments,
+                    stats,
+                    messages,
+                );
+                let _ = exit_status.write().insert(format!("{res:?}"));
+                res
             })
             .abort_on_drop()
         };
-        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-        if process.0.is_finished() {
-            match process.await {
-                Ok(_) => {
-                    bail!("this should never happen - process just stopped recording for no reason")
-                }
-                Err(report) => return Err(eyre!("{report}")).and_then(|v| v),
+        let clock = match tokio::time::timeout(STARTED_AT_DEADLINE, started_rx).await {
+            Ok(Ok(clock)) => Some(clock),
+            // the sender goes away without a clock when the pipeline never got to play
+            Ok(Err(_)) => match process.await {
+                Ok(Ok(())) => bail!("pipeline finished before recording anything"),
+                Ok(Err(report)) => return Err(report).wrap_err("starting the pipeline"),
+                Err(report) => bail!("pipeline thread crashed: {report}"),
+            },
+            Err(message) => {
+                tracing::warn!(?message, "video start time is a guess, sync will be off");
+                None
             }
-        }
+        };
+        let started_at = clock
+            .as_ref()
+            .map(|clock| clock.started_at)
+            .unwrap_or(spawned_at);
         let file_size_updater = tokio::task::spawn(async move {
             let mut interval = crate::process::app_interval(std::time::Duration::from_secs(1));
 
@@ -60,7 +189,16 @@ impl GstreamerInstance {
         .abort_on_drop();
         Ok(Self {
             video_device,
+            settings,
             video_file_path: output_file_path,
+            segments,
+            stats,
+            log,
+            started_at,
+            clock,
+            time_base: time_base.unwrap_or(started_at),
+            pipeline,
+            exit_status,
             cancel,
             _process: process,
             _file_size_updater: file_size_updater,
@@ -80,10 +218,16 @@ impl RenderToTerm for GstreamerInstance {
         f: &mut Frame<B>,
         rect: tui::layout::Rect,
     ) -> Result<()> {
-        let [file_size_block, gstreamer_block]: [Rect; 2] = layout!(Layout::default()
-            .direction(Direction::Vertical)
-            .constraints([Constraint::Ratio(5, 9), Constraint::Ratio(4, 9),])
-            .split(rect));
+        let [file_size_block, gstreamer_block, stats_block, log_block]: [Rect; 4] =
+            layout!(Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([
+                    Constraint::Ratio(2, 12),
+                    Constraint::Ratio(2, 12),
+                    Constraint::Ratio(4, 12),
+                    Constraint::Ratio(4, 12),
+                ])
+                .split(rect));
         let text_block = |text: String, title: String| {
             let block = Block::default().borders(Borders::ALL).title(Span::styled(
                 title,
@@ -97,8 +241,10 @@ impl RenderToTerm for GstreamerInstance {
         f.render_widget(
             text_block(
                 format!(
-                    "running: {}",
-                    !(self.cancel.is_cancelled() || self._process.0.is_finished())
+                    "running: {}\n{}\nsegments: {}",
+                    self.is_running(),
+                    self.settings,
+                    self.segments.read().len()
                 ),
                 format!("GStreamer ({:?})", self.video_device),
             ),
@@ -112,7 +258,137 @@ impl RenderToTerm for GstreamerInstance {
             ),
             file_size_block,
         );
+        let stats = self.stats.read().clone();
+        let warnings = stats.warnings(&self.settings);
+        let stats_text = Text::from(
+            warnings
+                .iter()
+                .map(|warning| {
+                    Spans::from(Span::styled(
+                        format!("! {warning}"),
+                        Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                    ))
+                })
+                .chain(
+                    stats
+                        .summary()
+                        .lines()
+                        .map(|line| Spans::from(line.to_owned())),
+                )
+                .collect_vec(),
+        );
+        f.render_widget(
+            Paragraph::new(stats_text)
+                .block(
+                    Block::default().borders(Borders::ALL).title(Span::styled(
+                        "pipeline health",
+                        Style::default()
+                            .fg(match warnings.is_empty() {
+                                true => Color::Magenta,
+                                false => Color::Red,
+                            })
+                            .add_modifier(Modifier::BOLD),
+                    )),
+                )
+                .wrap(Wrap { trim: false }),
+            stats_block,
+        );
+        self.log.render_to_term(f, log_block)?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use low_level::tests::{headless_settings, test_device};
+
+    /// stopping only asks for EOS, the muxer needs a moment to finalize
+    async fn wait_for(exit_status: &ChildExitStatus) -> Option<String> {
+        for _ in 0..100 {
+            if let Some(status) = exit_status.read().clone() {
+                return Some(status);
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn a_dropped_instance_leaves_a_finalized_recording() {
+        let directory = tempfile::tempdir().expect("temp dir");
+        let output_file = directory.path().join("take.mkv");
+        let (notify, _wake_up) = tokio::sync::mpsc::unbounded_channel();
+        let instance = GstreamerInstance::new(
+            test_device(),
+            headless_settings(),
+            output_file.clone(),
+            notify,
+        )
+        .await
+        .expect("starting the test pipeline");
+        assert!((crate::now() - instance.started_at) < chrono::Duration::seconds(10));
+        assert!(instance.is_running());
+        assert_eq!(instance.first_file(), output_file);
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        let (exit_status, instance_log) = (instance.exit_status.clone(), instance.log.clone());
+        drop(instance);
+        assert_eq!(wait_for(&exit_status).await.as_deref(), Some("Ok(())"));
+        assert!(output_file.metadata().expect("recording").len() > 0);
+        assert!(!instance_log.has_failed());
+    }
+
+    #[tokio::test]
+    async fn restarts_keep_the_time_base_and_the_handles() {
+        let directory = tempfile::tempdir().expect("temp dir");
+        let (notify, _wake_up) = tokio::sync::mpsc::unbounded_channel();
+        let first = GstreamerInstance::new(
+            test_device(),
+            headless_settings(),
+            directory.path().join("take.mkv"),
+            notify.clone(),
+        )
+        .await
+        .expect("starting the test pipeline");
+        assert_eq!(first.time_base, first.started_at);
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        first.stop();
+        wait_for(&first.exit_status)
+            .await
+            .expect("first pipeline finished");
+        let last_frame_at = first.last_frame_at();
+        assert!(last_frame_at > first.started_at);
+        assert!(last_frame_at <= crate::now());
+        let restarted = first
+            .restart(directory.path().join("take---restart-01.mkv"), notify)
+            .await
+            .expect("restarting the test pipeline");
+        assert_eq!(restarted.time_base, first.started_at);
+        assert!(restarted.started_at > last_frame_at);
+        assert!(Arc::ptr_eq(&restarted.exit_status, &first.exit_status));
+        assert!(Arc::ptr_eq(&restarted.segments, &first.segments));
+        assert_eq!(restarted.exit_status.read().clone(), None, "running again");
+        restarted.stop();
+        assert_eq!(
+            wait_for(&restarted.exit_status).await.as_deref(),
+            Some("Ok(())")
+        );
+    }
+
+    #[tokio::test]
+    async fn pipelines_that_fail_to_start_report_why() {
+        let directory = tempfile::tempdir().expect("temp dir");
+        let (notify, _wake_up) = tokio::sync::mpsc::unbounded_channel();
+        let failed = GstreamerInstance::new(
+            test_device(),
+            headless_settings(),
+            directory.path().join("missing").join("take.mkv"),
+            notify,
+        )
+        .await
+        .err()
+        .expect("no directory to write into");
+        assert!(format!("{failed:#}").contains("filesink"), "{failed:#}");
+    }
+}
@@ -1,12 +1,15 @@
 use super::*;
+use crate::session_manifest::{VideoSegment, VideoSegments};
+use capture_settings::{ENCODED, PREVIEW_QUEUE, RECORD_QUEUE};
 use gst::prelude::*;
 use gstreamer as gst;
+use pipeline_messages::{PipelineMessage, PipelineMessageBus, PipelineMessageKind};
+use pipeline_stats::{SharedPipelineStats, StatsSampler};
+use std::{
+    path::Path,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
 use tracing::{info, warn};
-const WIDTH: u32 = 1920;
-const HEIGHT: u32 = 1080;
-const FORMAT: &str = "YUY2";
-const FRAMERATE: &str = "25/1";
-const BITRATE: u32 = 20_000;
 
 // #[instrument(ret, err, level = "INFO")]
 // fn construct_pipeline(video_device: VideoDevice, output_file: PathBuf) -> Result<gst::Pipeline> {
@@ -88,33 +91,142 @@ const BITRATE: u32 = 20_000;
 //     Ok(pipeline)
 // }
 
-#[instrument(ret, err, level = "INFO")]
+const VIDEO_SOURCE: &str = "video-source";
+
+/// gst-launch syntax, also ends up in the session manifest
+pub fn pipeline_description(
+    video_device: &VideoDevice,
+    settings: &CaptureSettings,
+    output_file: &Path,
+) -> String {
+    settings.pipeline_description(video_device, VIDEO_SOURCE, output_file)
+}
+
+/// splitmuxsink reports every file it opens and closes as an element message,
+/// `offset_seconds` moves them from this pipeline's running time onto the camera's time base
+fn track_segment(structure: &gst::StructureRef, segments: &VideoSegments, offset_seconds: f64) {
+    let opened = structure.has_name("splitmuxsink-fragment-opened");
+    if !(opened || structure.has_name("splitmuxsink-fragment-closed")) {
+        return;
+    }
+    match (
+        structure.get::<String>("location"),
+        structure.get::<gst::ClockTime>("running-time"),
+    ) {
+        (Ok(location), Ok(running_time)) => {
+            let seconds = running_time.nseconds() as f64 / 1_000_000_000.0 + offset_seconds;
+            let path = PathBuf::from(location);
+            info!(?path, %seconds, %opened, "video segment");
+            let mut segments = segments.write();
+            match opened {
+                true => segments.push(VideoSegment {
+                    path,
+                    start_seconds: seconds,
+                    end_seconds: None,
+                }),
+                false => segments
+                    .iter_mut()
+                    .filter(|segment| segment.path == path)
+                    .for_each(|segment| segment.end_seconds = Some(seconds)),
+            }
+        }
+        (location, running_time) => {
+            warn!(?location, ?running_time, "malformed splitmuxsink message")
+        }
+    }
+}
+
+/// clock and base time of a playing pipeline, readable from outside the capture thread
+#[derive(Debug, Clone)]
+pub struct PipelineClock {
+    /// wall clock time of running time zero
+    pub started_at: ProjectTime,
+    clock: gst::Clock,
+    base_time: gst::ClockTime,
+}
+
+impl PipelineClock {
+    fn of(pipeline: &gst::Pipeline) -> Self {
+        let clock = pipeline.clock().unwrap_or_else(gst::SystemClock::obtain);
+        let base_time = pipeline.base_time().unwrap_or(gst::ClockTime::ZERO);
+        let running_time = clock
+            .time()
+            .map(|now| now.saturating_sub(base_time))
+            .unwrap_or(gst::ClockTime::ZERO);
+        Self {
+            started_at: crate::now()
+                - chrono::Duration::nanoseconds(running_time.nseconds() as i64),
+            clock,
+            base_time,
+        }
+    }
+
+    /// running time of the frame being captured right now, what buffer timestamps are relative to
+    pub fn running_time_seconds(&self) -> f64 {
+        self.clock
+            .time()
+            .map(|now| now.saturating_sub(self.base_time))
+            .unwrap_or(gst::ClockTime::ZERO)
+            .nseconds() as f64
+            / 1_000_000_000.0
+    }
+}
+
+/// element path, error and debug string of a bus error
+fn error_text(message: &gst::MessageRef) -> Option<String> {
+    match message.view() {
+        gst::MessageView::Error(err) => Some(format!(
+            "{}: {} ({:?})",
+            message
+                .src()
+                .map(|src| src.path_string().to_string())
+                .unwrap_or_default(),
+            err.error(),
+            err.debug()
+        )),
+        _ => None,
+    }
+}
+
+fn buffer_probe(
+    pipeline: &gst::Pipeline,
+    element: &str,
+    on_buffer: impl Fn(&gst::BufferRef) + Send + Sync + 'static,
+) -> Result<()> {
+    pipeline
+        .by_name(element)
+        .and_then(|element| element.static_pad("src"))
+        .ok_or_else(|| eyre!("no src pad on {element}"))
+        .map(|pad| {
+            pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+                if let Some(gst::PadProbeData::Buffer(buffer)) = &info.data {
+                    on_buffer(buffer);
+                }
+                gst::PadProbeReturn::Ok
+            });
+        })
+}
+
+/// `started` receives the clock of the playing pipeline, buffer timestamps are relative to its
+/// running time, segments are tracked relative to `time_base` instead when the camera was started earlier
+#[instrument(skip(started, segments, stats, messages), ret, err, level = "INFO")]
 pub fn start_stream(
     video_device: VideoDevice,
+    settings: CaptureSettings,
     output_file: PathBuf,
     cancel: CancellationToken,
+    started: Option<tokio::sync::oneshot::Sender<PipelineClock>>,
+    time_base: Option<ProjectTime>,
+    segments: VideoSegments,
+    stats: SharedPipelineStats,
+    messages: PipelineMessageBus,
 ) -> Result<()> {
     gst::init()?;
     // gst-launch-1.0 -e  v4l2src device=/dev/video1 !  videoconvert !  video/x-raw,width=1920,height=1080,framerate=25/1,format=I420 !  x264enc bitrate=8000 speed-preset=ultrafast tune=zerolatency !  video/x-h264 !  matroskamux !  filesink location=output.mkv
 
     // Build the pipeline
     // let uri = "https://gstreamer.freedesktop.org/data/media/sintel_trailer-480p.webm";
-    const VIDEO_SOURCE: &str = "video-source";
-    let pipeline_str = format!(
-        r#"
-    v4l2src device={video_device} name="{VIDEO_SOURCE}"
-        ! capsfilter caps="video/x-raw, width={WIDTH}, height={HEIGHT}, format={FORMAT}, framerate={FRAMERATE}"
-        ! tee name=t
-            t. ! queue ! autovideosink
-            t. ! queue
-                ! videoconvert
-                ! x264enc bitrate={BITRATE} speed-preset=ultrafast tune=zerolatency
-                ! video/x-h264
-                ! matroskamux
-                ! filesink location={output_file}
-            "#,
-        output_file = output_file.display()
-    );
+    let pipeline_str = pipeline_description(&video_device, &settings, &output_file);
     info!(%pipeline_str);
     // let pipeline =
     //     construct_pipeline(video_device, output_file).wrap_err("constructing pipeline")?;
@@ -125,56 +237,167 @@ pub fn start_stream(
     let video_source = pipeline
         .by_name(VIDEO_SOURCE)
         .ok_or_else(|| eyre!("element {VIDEO_SOURCE} not properly setup"))?;
+    buffer_probe(&pipeline, VIDEO_SOURCE, {
+        let stats = stats.clone();
+        move |buffer| stats.write().record_frame(buffer.pts(), settings.framerate)
+    })?;
+    let encoded_bytes = Arc::new(AtomicU64::default());
+    buffer_probe(&pipeline, ENCODED, {
+        let encoded_bytes = encoded_bytes.clone();
+        move |buffer| {
+            encoded_bytes.fetch_add(buffer.size() as u64, Ordering::Relaxed);
+        }
+    })?;
+    let mut sampler = StatsSampler::new(
+        stats.clone(),
+        [PREVIEW_QUEUE, RECORD_QUEUE]
+            .into_iter()
+            .filter_map(|queue| pipeline.by_name(queue))
+            .collect(),
+        encoded_bytes,
+    );
 
     // Start playing
-    let _res = pipeline.set_state(gst::State::Playing)?;
-    let cancel_watcher = std::thread::spawn(move || loop {
-        std::thread::sleep(std::time::Duration::from_millis(100));
-        if cancel.is_cancelled() {
-            video_source.send_event(gst::event::Eos::new());
-            break;
+    if let Err(state_change) = pipeline.set_state(gst::State::Playing) {
+        // the bus watch isn't there yet, the reason is still waiting on the bus
+        let failure = pipeline
+            .bus()
+            .and_then(|bus| bus.pop_filtered(&[gst::MessageType::Error]))
+            .and_then(|message| error_text(&message));
+        let _ = pipeline.set_state(gst::State::Null);
+        if let Some(failure) = &failure {
+            messages
+                .send(PipelineMessage::new(
+                    PipelineMessageKind::Error,
+                    failure.clone(),
+                ))
+                .ok();
         }
-    });
+        return Err(eyre!(
+            "{}",
+            failure.unwrap_or_else(|| state_change.to_string())
+        ))
+        .wrap_err("pipeline failed to start");
+    }
+    if let (Err(message), ..) = pipeline.state(gst::ClockTime::from_seconds(5)) {
+        warn!(?message, "pipeline did not settle in playing state");
+    }
+    let clock = PipelineClock::of(&pipeline);
+    let started_at = clock.started_at;
+    if let Some(started) = started {
+        started.send(clock).ok();
+    }
+    // the bus watch only runs once the main loop does, no segment is tracked before this is known
+    let offset_seconds = time_base
+        .map(|time_base| {
+            (started_at - time_base)
+                .num_microseconds()
+                .unwrap_or_default() as f64
+                / 1_000_000.0
+        })
+        .unwrap_or_default();
+    // the main loop also ends on errors, nobody would cancel the watcher then
+    let finished = Arc::new(AtomicBool::new(false));
+    let cancel_watcher = {
+        let finished = finished.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            sampler.sample(std::time::Duration::from_secs(1));
+            if finished.load(Ordering::Relaxed) {
+                break;
+            }
+            if cancel.is_cancelled() {
+                video_source.send_event(gst::event::Eos::new());
+                break;
+            }
+        })
+    };
 
-    let main_loop = glib::MainLoop::new(None, false);
+    // cameras record side by side on their own threads, the watch of each bus goes on a context
+    // of its own instead of the global default one all of them would be dispatched from
+    let context = glib::MainContext::new();
+    let main_loop = glib::MainLoop::new(Some(&context), false);
     let main_loop_clone = main_loop.clone();
+    let failure = Arc::new(RwLock::new(None::<String>));
+    let failure_clone = failure.clone();
     let pipeline_weak = pipeline.downgrade();
     let bus = pipeline.bus().expect("Pipeline has no bus");
-    let _bus_watch = bus
-        .add_watch(move |_, msg| {
-            let pipeline = match pipeline_weak.upgrade() {
-                Some(pipeline) => pipeline,
-                None => return glib::ControlFlow::Continue,
-            };
-            let main_loop = &main_loop_clone;
-            match msg.view() {
-                gst::MessageView::Error(err) => {
-                    println!(
-                        "Error from {:?}: {} ({:?})",
-                        err.src().map(|s| s.path_string()),
-                        err.error(),
-                        err.debug()
-                    );
-                    let _ = pipeline.set_state(gst::State::Ready);
-                    main_loop.quit();
+    // the watch is attached to the thread default context, only pushed for as long as the loop runs
+    let watched = context
+        .with_thread_default(|| {
+            bus.add_watch(move |_, msg| {
+                let pipeline = match pipeline_weak.upgrade() {
+                    Some(pipeline) => pipeline,
+                    None => return glib::ControlFlow::Continue,
+                };
+                let main_loop = &main_loop_clone;
+                let source = || {
+                    msg.src()
+                        .map(|src| src.path_string().to_string())
+                        .unwrap_or_default()
+                };
+                let send = |kind, text: String| {
+                    messages.send(PipelineMessage::new(kind, text)).ok();
+                };
+                match msg.view() {
+                    gst::MessageView::Error(err) => {
+                        warn!(source = %source(), error = %err.error(), debug = ?err.debug());
+                        let text = error_text(msg).unwrap_or_default();
+                        send(PipelineMessageKind::Error, text.clone());
+                        let _ = failure_clone.write().insert(text);
+                        let _ = pipeline.set_state(gst::State::Ready);
+                        main_loop.quit();
+                    }
+                    gst::MessageView::Warning(warning) => {
+                        warn!(source = %source(), warning = %warning.error(), debug = ?warning.debug());
+                        send(
+                            PipelineMessageKind::Warning,
+                            format!("{}: {} ({:?})", source(), warning.error(), warning.debug()),
+                        );
+                    }
+                    gst::MessageView::StateChanged(changed)
+                        if msg.src() == Some(pipeline.upcast_ref()) =>
+                    {
+                        send(
+                            PipelineMessageKind::State,
+                            format!("{:?} -> {:?}", changed.old(), changed.current()),
+                        );
+                    }
+                    gst::MessageView::Eos(..) => {
+                        // end-of-stream
+                        send(PipelineMessageKind::Eos, "recording finalized".to_owned());
+                        let _ = pipeline.set_state(gst::State::Ready);
+                        main_loop.quit();
+                    }
+                    gst::MessageView::Qos(qos) => {
+                        let (_, dropped) = qos.stats();
+                        stats.write().record_qos(
+                            qos.src()
+                                .map(|src| src.path_string().to_string())
+                                .unwrap_or_default(),
+                            dropped.value().max(0) as u64,
+                        );
+                    }
+                    gst::MessageView::Element(element) => {
+                        if let Some(structure) = element.structure() {
+                            track_segment(structure, &segments, offset_seconds);
+                        }
+                    }
+                    gst::MessageView::ClockLost(_) => {
+                        // Get a new clock
+                        let _ = pipeline.set_state(gst::State::Paused);
+                        let _ = pipeline.set_state(gst::State::Playing);
+                    }
+                    _ => (),
                 }
-                gst::MessageView::Eos(..) => {
-                    // end-of-stream
-                    let _ = pipeline.set_state(gst::State::Ready);
-                    main_loop.quit();
-                }
-                gst::MessageView::ClockLost(_) => {
-                    // Get a new clock
-                    let _ = pipeline.set_state(gst::State::Paused);
-                    let _ = pipeline.set_state(gst::State::Playing);
-                }
-                _ => (),
-            }
-            glib::ControlFlow::Continue
+                glib::ControlFlow::Continue
+            })
+            .map(|_bus_watch| main_loop.run())
         })
-        .expect("Failed to add bus watch");
-
-    main_loop.run();
+        .map_err(eyre::Report::from)
+        .and_then(|added| added.map_err(eyre::Report::from))
+        .wrap_err("watching the pipeline bus");
+    finished.store(true, Ordering::Relaxed);
 
     pipeline.set_state(gst::State::Null)?;
     if let Err(join_error) = cancel_watcher
@@ -184,6 +407,247 @@ pub fn start_stream(
     {
         warn!(?join_error, "failed to shut down the watcher thread");
     }
+    watched?;
 
-    Ok(())
+    let failure = failure.write().take();
+    match failure {
+        Some(failure) => Err(eyre!("{failure}")).wrap_err("pipeline failed"),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::video_capture::{
+        capture_settings::{Framerate, Resolution},
+        preview_window::PreviewSink,
+    };
+
+    /// small enough to encode in real time anywhere, nothing shows up on screen
+    pub(crate) fn headless_settings() -> CaptureSettings {
+        CaptureSettings {
+            resolution: Resolution {
+                width: 320,
+                height: 240,
+            },
+            framerate: Framerate {
+                numerator: 25,
+                denominator: 1,
+            },
+            bitrate: 500,
+            ..Default::default()
+        }
+        .with_preview(PreviewSink::Headless)
+    }
+
+    pub(crate) fn test_device() -> VideoDevice {
+        VideoDevice::Test("smpte".to_owned())
+    }
+
+    fn fragment(name: &str, location: &str, seconds: u64) -> gst::Structure {
+        gst::Structure::builder(name)
+            .field("location", location)
+            .field("running-time", gst::ClockTime::from_seconds(seconds))
+            .build()
+    }
+
+    #[test]
+    fn test_sources_record_until_cancelled() {
+        let directory = tempfile::tempdir().expect("temp dir");
+        let output_file = directory.path().join("take.mkv");
+        let cancel = CancellationToken::new();
+        let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+        let (messages, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let stream = {
+            let (output_file, cancel) = (output_file.clone(), cancel.clone());
+            std::thread::spawn(move || {
+                start_stream(
+                    test_device(),
+                    headless_settings(),
+                    output_file,
+                    cancel,
+                    Some(started_tx),
+                    None,
+                    Default::default(),
+                    Default::default(),
+                    messages,
+                )
+            })
+        };
+        let clock = started_rx.blocking_recv().expect("pipeline started");
+        assert!((crate::now() - clock.started_at) < chrono::Duration::seconds(10));
+        std::thread::sleep(std::time::Duration::from_secs(2));
+        assert!(clock.running_time_seconds() >= 2.0);
+        cancel.cancel();
+        stream
+            .join()
+            .expect("pipeline thread")
+            .expect("recording finished");
+        assert!(output_file.metadata().expect("recording").len() > 0);
+        let kinds = std::iter::from_fn(|| received.try_recv().ok())
+            .map(|message| message.kind)
+            .collect_vec();
+        assert!(!kinds.contains(&PipelineMessageKind::Error), "{kinds:?}");
+        assert_eq!(kinds.last(), Some(&PipelineMessageKind::Eos));
+    }
+
+    #[test]
+    fn pipelines_on_parallel_threads_finish_independently() {
+        let directory = tempfile::tempdir().expect("temp dir");
+        let record = |name: &str, cancel: CancellationToken| {
+            let output_file = directory.path().join(name);
+            let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+            let (messages, received) = tokio::sync::mpsc::unbounded_channel();
+            let stream = std::thread::spawn(move || {
+                start_stream(
+                    test_device(),
+                    headless_settings(),
+                    output_file,
+                    cancel,
+                    Some(started_tx),
+                    None,
+                    Default::default(),
+                    Default::default(),
+                    messages,
+                )
+            });
+            started_rx.blocking_recv().expect("pipeline started");
+            (stream, received)
+        };
+        let last_kind = |mut received: tokio::sync::mpsc::UnboundedReceiver<PipelineMessage>| {
+            std::iter::from_fn(|| received.try_recv().ok())
+                .map(|message| message.kind)
+                .last()
+        };
+        let (cancel_first, cancel_second) = (CancellationToken::new(), CancellationToken::new());
+        let (first, first_messages) = record("first.mkv", cancel_first.clone());
+        let (second, second_messages) = record("second.mkv", cancel_second.clone());
+        cancel_first.cancel();
+        first
+            .join()
+            .expect("first pipeline thread")
+            .expect("first recording finished");
+        assert_eq!(last_kind(first_messages), Some(PipelineMessageKind::Eos));
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        assert!(!second.is_finished());
+        cancel_second.cancel();
+        second
+            .join()
+            .expect("second pipeline thread")
+            .expect("second recording finished");
+        assert_eq!(last_kind(second_messages), Some(PipelineMessageKind::Eos));
+    }
+
+    #[test]
+    fn test_sources_replace_the_camera() {
+        let description = pipeline_description(
+            &VideoDevice::Test("ball".to_owned()),
+            &headless_settings(),
+            Path::new("take.mkv"),
+        );
+        assert!(
+            description.contains("videotestsrc is-live=true pattern=ball"),
+            "{description}"
+        );
+        assert!(!description.contains("v4l2src"), "{description}");
+        assert!(description.contains("fakesink sync=false"), "{description}");
+    }
+
+    #[test]
+    fn recording_paths_with_spaces_and_quotes_reach_the_sink_unchanged() {
+        gst::init().expect("gstreamer");
+        let output_file = Path::new("/sessions/my \"best\" song/take 1.mkv");
+        let pipeline = gst::parse_launch(&pipeline_description(
+            &test_device(),
+            &headless_settings(),
+            output_file,
+        ))
+        .expect("pipeline description parses")
+        .downcast::<gst::Pipeline>()
+        .expect("pipeline");
+        let sink = pipeline
+            .iterate_sinks()
+            .into_iter()
+            .filter_map(Result::ok)
+            .find(|sink| {
+                sink.factory()
+                    .is_some_and(|factory| factory.name() == "filesink")
+            })
+            .expect("filesink");
+        assert_eq!(
+            sink.property::<String>("location"),
+            output_file.display().to_string()
+        );
+    }
+
+    #[test]
+    fn restarted_segments_stay_on_the_time_base_of_the_camera() {
+        gst::init().expect("gstreamer");
+        let segments = VideoSegments::default();
+        track_segment(
+            &fragment(
+                "splitmuxsink-fragment-opened",
+                "/session/take---restart-01-000.mkv",
+                0,
+            ),
+            &segments,
+            90.0,
+        );
+        track_segment(
+            &fragment(
+                "splitmuxsink-fragment-closed",
+                "/session/take---restart-01-000.mkv",
+                30,
+            ),
+            &segments,
+            90.0,
+        );
+        track_segment(
+            &fragment(
+                "splitmuxsink-fragment-opened",
+                "/session/take---restart-01-001.mkv",
+                30,
+            ),
+            &segments,
+            90.0,
+        );
+        track_segment(&fragment("GstMessageQOS", "ignored", 1), &segments, 90.0);
+        assert_eq!(
+            segments.read().clone(),
+            vec![
+                VideoSegment {
+                    path: PathBuf::from("/session/take---restart-01-000.mkv"),
+                    start_seconds: 90.0,
+                    end_seconds: Some(120.0),
+                },
+                VideoSegment {
+                    path: PathBuf::from("/session/take---restart-01-001.mkv"),
+                    start_seconds: 120.0,
+                    end_seconds: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn bus_errors_end_the_stream_with_an_error() {
+        let directory = tempfile::tempdir().expect("temp dir");
+        let (messages, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let failed = start_stream(
+            test_device(),
+            headless_settings(),
+            directory.path().join("missing").join("take.mkv"),
+            CancellationToken::new(),
+            None,
+            None,
+            Default::default(),
+            Default::default(),
+            messages,
+        )
+        .expect_err("no directory to write into");
+        assert!(format!("{failed:#}").contains("filesink"), "{failed:#}");
+        assert!(std::iter::from_fn(|| received.try_recv().ok())
+            .any(|message| message.kind == PipelineMessageKind::Error));
+    }
 }
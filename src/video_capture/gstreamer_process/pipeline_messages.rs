@@ -0,0 +1,322 @@
+use super::*;
+use crossterm::event::{KeyCode, KeyEvent};
+use std::collections::VecDeque;
+use tui::{
+    text::Spans,
+    widgets::{List, ListItem, ListState},
+};
+
+/// bus messages worth showing, everything else stays in the tracing log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display)]
+#[strum(serialize_all = "UPPERCASE")]
+pub enum PipelineMessageKind {
+    Error,
+    Warning,
+    State,
+    Eos,
+}
+
+impl PipelineMessageKind {
+    fn color(self) -> Color {
+        match self {
+            Self::Error => Color::Red,
+            Self::Warning => Color::Yellow,
+            Self::State => Color::Gray,
+            Self::Eos => Color::Green,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PipelineMessage {
+    pub time: ProjectTime,
+    pub kind: PipelineMessageKind,
+    pub text: String,
+}
+
+impl PipelineMessage {
+    pub fn new(kind: PipelineMessageKind, text: String) -> Self {
+        Self {
+            time: crate::now(),
+            kind,
+            text,
+        }
+    }
+}
+
+/// sent from the bus watch in [low_level::start_stream]
+pub type PipelineMessageBus = tokio::sync::mpsc::UnboundedSender<PipelineMessage>;
+
+/// newest first, errors also wake the UI up with [ProcessEvent::PipelineFailed],
+/// clones share the messages so that a restarted pipeline keeps the history
+#[derive(Debug, Clone)]
+pub struct PipelineLog {
+    pub inner: Arc<RwLock<VecDeque<PipelineMessage>>>,
+    /// nothing selected follows the newest message
+    scroll: Arc<RwLock<ListState>>,
+    messages: PipelineMessageBus,
+    _watcher: Arc<AbortOnDrop<()>>,
+}
+
+impl PipelineLog {
+    pub const MESSAGE_CAPACITY: usize = 200;
+    const PAGE: usize = 10;
+
+    pub fn new(notify: ProcessEventBus) -> Self {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<PipelineMessage>();
+        let inner = Arc::new(RwLock::new(VecDeque::with_capacity(Self::MESSAGE_CAPACITY)));
+        let scroll = Arc::new(RwLock::new(ListState::default()));
+        let watcher = {
+            to_owned![inner, scroll];
+            tokio::task::spawn(async move {
+                while let Some(message) = rx.recv().await {
+                    let event = match message.kind {
+                        PipelineMessageKind::Error => ProcessEvent::PipelineFailed,
+                        _ => ProcessEvent::NewInput,
+                    };
+                    Self::record(&mut inner.write(), &mut scroll.write(), message);
+                    notify.send(event).ok();
+                }
+            })
+            .abort_on_drop()
+        };
+        Self {
+            inner,
+            scroll,
+            messages: tx,
+            _watcher: Arc::new(watcher),
+        }
+    }
+
+    /// keeps the message scrolled to in place while new ones come in on top
+    fn record(
+        inner: &mut VecDeque<PipelineMessage>,
+        scroll: &mut ListState,
+        message: PipelineMessage,
+    ) {
+        while inner.len() >= Self::MESSAGE_CAPACITY {
+            inner.pop_back();
+        }
+        inner.push_front(message);
+        let last = inner.len() - 1;
+        scroll.select(scroll.selected().map(|selected| (selected + 1).min(last)));
+    }
+
+    /// index of the message scrolled to, newest first
+    pub fn selected(&self) -> Option<usize> {
+        self.scroll.read().selected()
+    }
+
+    /// Down/PageDown/End scroll to older messages, Up/PageUp back towards the newest,
+    /// Home/Esc follow the newest again, returns true when the key was consumed
+    pub fn handle_key(&self, key: KeyEvent) -> bool {
+        let len = self.inner.read().len();
+        let mut scroll = self.scroll.write();
+        let current = scroll.selected();
+        let older = |by: usize| {
+            current
+                .map(|selected| selected.saturating_add(by))
+                .unwrap_or(by - 1)
+                .min(len.saturating_sub(1))
+        };
+        let newer = |by: usize| current.and_then(|selected| selected.checked_sub(by));
+        let selected = match key.code {
+            KeyCode::Down => Some(older(1)),
+            KeyCode::PageDown => Some(older(Self::PAGE)),
+            KeyCode::End => Some(older(usize::MAX)),
+            KeyCode::Up => newer(1),
+            KeyCode::PageUp => newer(Self::PAGE),
+            KeyCode::Home | KeyCode::Esc => None,
+            _ => return false,
+        };
+        scroll.select(selected.filter(|_| len > 0));
+        true
+    }
+
+    pub fn bus(&self) -> PipelineMessageBus {
+        self.messages.clone()
+    }
+
+    /// for anything that happens outside of the pipeline itself
+    pub fn push(&self, kind: PipelineMessageKind, text: String) {
+        self.messages.send(PipelineMessage::new(kind, text)).ok();
+    }
+
+    /// the latest pipeline has failed, an earlier error followed by a restart doesn't count
+    pub fn has_failed(&self) -> bool {
+        self.last_error().is_some()
+    }
+
+    /// the error that stopped the latest pipeline
+    pub fn last_error(&self) -> Option<PipelineMessage> {
+        self.inner
+            .read()
+            .iter()
+            .find(|message| {
+                matches!(
+                    message.kind,
+                    PipelineMessageKind::Error | PipelineMessageKind::State
+                )
+            })
+            .filter(|message| message.kind == PipelineMessageKind::Error)
+            .cloned()
+    }
+}
+
+impl RenderToTerm for PipelineLog {
+    fn render_to_term<B: Backend>(
+        &mut self,
+        f: &mut Frame<B>,
+        rect: tui::layout::Rect,
+    ) -> Result<()> {
+        let items = self
+            .inner
+            .read()
+            .iter()
+            .map(|PipelineMessage { time, kind, text }| {
+                ListItem::new(vec![Spans::from(vec![
+                    Span::styled(
+                        format!("{} {kind:<7} ", time.format("%H:%M:%S")),
+                        Style::default().fg(kind.color()),
+                    ),
+                    Span::raw(text.clone()),
+                ])])
+            })
+            .collect_vec();
+        let position = self
+            .selected()
+            .map(|selected| format!(" [{}/{}, Home to follow]", selected + 1, items.len()))
+            .unwrap_or_default();
+        let (title, title_color) = match self.has_failed() {
+            true => ("pipeline log (FAILED)", Color::Red),
+            false => ("pipeline log", Color::Magenta),
+        };
+        f.render_stateful_widget(
+            List::new(items)
+                .block(
+                    Block::default().borders(Borders::ALL).title(Span::styled(
+                        format!("{title}{position}"),
+                        Style::default()
+                            .fg(title_color)
+                            .add_modifier(Modifier::BOLD),
+                    )),
+                )
+                .highlight_style(Style::default().add_modifier(Modifier::REVERSED)),
+            rect,
+            &mut self.scroll.write(),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::event::KeyModifiers;
+
+    fn message(text: &str) -> PipelineMessage {
+        PipelineMessage::new(PipelineMessageKind::State, text.to_owned())
+    }
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    #[tokio::test]
+    async fn messages_are_kept_newest_first_and_errors_wake_the_ui() {
+        let (notify, mut wake_up) = tokio::sync::mpsc::unbounded_channel();
+        let log = PipelineLog::new(notify);
+        let bus = log.bus();
+        bus.send(PipelineMessage::new(
+            PipelineMessageKind::State,
+            "Null -> Playing".to_owned(),
+        ))
+        .expect("log is listening");
+        assert!(matches!(wake_up.recv().await, Some(ProcessEvent::NewInput)));
+        assert!(!log.has_failed());
+        bus.send(PipelineMessage::new(
+            PipelineMessageKind::Error,
+            "v4l2src0: device busy".to_owned(),
+        ))
+        .expect("log is listening");
+        assert!(matches!(
+            wake_up.recv().await,
+            Some(ProcessEvent::PipelineFailed)
+        ));
+        assert!(log.has_failed());
+        assert_eq!(
+            log.inner
+                .read()
+                .iter()
+                .map(|message| message.text.as_str())
+                .collect_vec(),
+            ["v4l2src0: device busy", "Null -> Playing"]
+        );
+    }
+
+    #[test]
+    fn the_log_never_grows_past_its_capacity() {
+        let mut inner = VecDeque::new();
+        let mut scroll = ListState::default();
+        (0..PipelineLog::MESSAGE_CAPACITY + 5).for_each(|index| {
+            PipelineLog::record(&mut inner, &mut scroll, message(&index.to_string()))
+        });
+        assert_eq!(inner.len(), PipelineLog::MESSAGE_CAPACITY);
+        assert_eq!(
+            inner.front().map(|message| message.text.as_str()),
+            Some((PipelineLog::MESSAGE_CAPACITY + 4).to_string().as_str())
+        );
+    }
+
+    #[test]
+    fn scrolled_messages_stay_put_while_new_ones_come_in() {
+        let mut inner = VecDeque::new();
+        let mut scroll = ListState::default();
+        ["a", "b", "c"]
+            .into_iter()
+            .for_each(|text| PipelineLog::record(&mut inner, &mut scroll, message(text)));
+        assert_eq!(scroll.selected(), None);
+        scroll.select(Some(1));
+        PipelineLog::record(&mut inner, &mut scroll, message("d"));
+        assert_eq!(scroll.selected(), Some(2));
+        assert_eq!(inner[2].text, "b");
+    }
+
+    #[tokio::test]
+    async fn keys_scroll_between_the_newest_and_the_oldest_message() {
+        let (notify, _wake_up) = tokio::sync::mpsc::unbounded_channel();
+        let log = PipelineLog::new(notify);
+        assert!(log.handle_key(key(KeyCode::Down)));
+        assert_eq!(log.selected(), None, "nothing to scroll to yet");
+        (0..30).for_each(|index| log.inner.write().push_front(message(&index.to_string())));
+        assert!(log.handle_key(key(KeyCode::Down)));
+        assert_eq!(log.selected(), Some(0));
+        assert!(log.handle_key(key(KeyCode::PageDown)));
+        assert_eq!(log.selected(), Some(10));
+        assert!(log.handle_key(key(KeyCode::End)));
+        assert_eq!(log.selected(), Some(29));
+        assert!(log.handle_key(key(KeyCode::PageDown)));
+        assert_eq!(log.selected(), Some(29));
+        assert!(log.handle_key(key(KeyCode::Up)));
+        assert_eq!(log.selected(), Some(28));
+        assert!(log.handle_key(key(KeyCode::Home)));
+        assert_eq!(log.selected(), None);
+        assert!(!log.handle_key(key(KeyCode::Char('x'))));
+    }
+
+    #[tokio::test]
+    async fn only_an_error_after_the_last_state_change_counts_as_failed() {
+        let (notify, _wake_up) = tokio::sync::mpsc::unbounded_channel();
+        let log = PipelineLog::new(notify);
+        log.inner.write().push_front(PipelineMessage::new(
+            PipelineMessageKind::Error,
+            "gone".to_owned(),
+        ));
+        assert_eq!(
+            log.last_error().map(|error| error.text),
+            Some("gone".to_owned())
+        );
+        log.inner.write().push_front(message("restarted"));
+        assert!(!log.has_failed());
+    }
+}
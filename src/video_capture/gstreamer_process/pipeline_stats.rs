@@ -0,0 +1,294 @@
+use super::*;
+use capture_settings::Framerate;
+use gst::prelude::*;
+use gstreamer as gst;
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+/// written by the capture thread, read by the TUI
+pub type SharedPipelineStats = Arc<RwLock<PipelineStats>>;
+
+/// anything fuller than this means the element after the queue can't keep up
+const QUEUE_FILL_WARNING: f64 = 0.8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QueueLevel {
+    pub buffers: u32,
+    /// the fullest of buffers, bytes and time, as a fraction of the limit
+    pub fill: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PipelineStats {
+    pub frames_captured: u64,
+    /// gaps in the camera timestamps longer than a frame and a half
+    pub discontinuities: u64,
+    /// kbit/s leaving the encoder, measured over the last second
+    pub bitrate: Option<f64>,
+    pub queues: BTreeMap<String, QueueLevel>,
+    /// QoS messages report a running total per element
+    dropped_by_element: BTreeMap<String, u64>,
+    last_timestamp: Option<gst::ClockTime>,
+}
+
+impl PipelineStats {
+    pub fn frames_dropped(&self) -> u64 {
+        self.dropped_by_element.values().sum()
+    }
+
+    pub fn record_qos(&mut self, element: String, dropped: u64) {
+        self.dropped_by_element.insert(element, dropped);
+    }
+
+    /// timestamp of the newest frame from the camera, pipeline running time
+    pub fn last_timestamp(&self) -> Option<gst::ClockTime> {
+        self.last_timestamp
+    }
+
+    pub fn record_frame(&mut self, timestamp: Option<gst::ClockTime>, framerate: Framerate) {
+        self.frames_captured += 1;
+        let frame_nanoseconds = 1_000_000_000.0 / framerate.as_f64();
+        if let (Some(previous), Some(current)) = (self.last_timestamp, timestamp) {
+            if current.nseconds().saturating_sub(previous.nseconds()) as f64
+                > frame_nanoseconds * 1.5
+            {
+                self.discontinuities += 1;
+            }
+        }
+        self.last_timestamp = timestamp.or(self.last_timestamp);
+    }
+
+    pub fn warnings(&self, settings: &CaptureSettings) -> Vec<String> {
+        let dropped = self.frames_dropped();
+        [
+            (dropped > 0).then(|| format!("{dropped} frames dropped")),
+            (self.discontinuities > 0).then(|| {
+                format!(
+                    "{} timestamp discontinuities from the camera",
+                    self.discontinuities
+                )
+            }),
+            (self.frames_captured > 0 && self.bitrate == Some(0.0))
+                .then(|| format!("{} is not producing any data", settings.encoder)),
+        ]
+        .into_iter()
+        .flatten()
+        .chain(
+            self.queues
+                .iter()
+                .filter(|(_, level)| level.fill >= QUEUE_FILL_WARNING)
+                .map(|(name, level)| {
+                    format!(
+                        "{name} is {:.0}% full, whatever comes after it can't keep up",
+                        level.fill * 100.0
+                    )
+                }),
+        )
+        .collect()
+    }
+
+    pub fn summary(&self) -> String {
+        [
+            format!("frames: {}", self.frames_captured),
+            format!("dropped: {}", self.frames_dropped()),
+            format!(
+                "bitrate: {}",
+                self.bitrate
+                    .map(|bitrate| format!("{bitrate:.0}kbit/s"))
+                    .unwrap_or_else(|| "?".to_owned())
+            ),
+        ]
+        .into_iter()
+        .chain(self.queues.iter().map(|(name, level)| {
+            format!(
+                "{name}: {} buffers ({:.0}%)",
+                level.buffers,
+                level.fill * 100.0
+            )
+        }))
+        .join("\n")
+    }
+}
+
+/// polled from the watcher thread, properties and counters are fine to read from anywhere
+pub struct StatsSampler {
+    stats: SharedPipelineStats,
+    queues: Vec<gst::Element>,
+    encoded_bytes: Arc<AtomicU64>,
+    last_sample: Instant,
+}
+
+fn queue_level(queue: &gst::Element) -> QueueLevel {
+    let ratio = |current: u64, max: u64| match max {
+        0 => 0.0,
+        max => current as f64 / max as f64,
+    };
+    let buffers = queue.property::<u32>("current-level-buffers");
+    let fill = [
+        ratio(
+            buffers as u64,
+            queue.property::<u32>("max-size-buffers") as u64,
+        ),
+        ratio(
+            queue.property::<u32>("current-level-bytes") as u64,
+            queue.property::<u32>("max-size-bytes") as u64,
+        ),
+        ratio(
+            queue.property::<u64>("current-level-time"),
+            queue.property::<u64>("max-size-time"),
+        ),
+    ]
+    .into_iter()
+    .fold(0.0, f64::max);
+    QueueLevel { buffers, fill }
+}
+
+impl StatsSampler {
+    pub fn new(
+        stats: SharedPipelineStats,
+        queues: Vec<gst::Element>,
+        encoded_bytes: Arc<AtomicU64>,
+    ) -> Self {
+        Self {
+            stats,
+            queues,
+            encoded_bytes,
+            last_sample: Instant::now(),
+        }
+    }
+
+    /// does nothing until `every` has passed since the last sample
+    pub fn sample(&mut self, every: std::time::Duration) {
+        let elapsed = self.last_sample.elapsed();
+        if elapsed < every {
+            return;
+        }
+        self.last_sample = Instant::now();
+        let bytes = self.encoded_bytes.swap(0, Ordering::Relaxed);
+        let queues = self
+            .queues
+            .iter()
+            .map(|queue| (queue.name().to_string(), queue_level(queue)))
+            .collect();
+        let mut stats = self.stats.write();
+        stats.bitrate = Some(bytes as f64 * 8.0 / 1000.0 / elapsed.as_secs_f64());
+        stats.queues = queues;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capture_settings::{PREVIEW_QUEUE, RECORD_QUEUE};
+
+    fn framerate() -> Framerate {
+        "25".parse().expect("framerate")
+    }
+
+    fn frames(stats: &mut PipelineStats, milliseconds: impl IntoIterator<Item = u64>) {
+        milliseconds.into_iter().for_each(|milliseconds| {
+            stats.record_frame(
+                Some(gst::ClockTime::from_mseconds(milliseconds)),
+                framerate(),
+            )
+        });
+    }
+
+    #[test]
+    fn steady_frames_are_only_counted() {
+        let mut stats = PipelineStats::default();
+        frames(&mut stats, (0..50).map(|frame| frame * 40));
+        assert_eq!(stats.frames_captured, 50);
+        assert_eq!(stats.discontinuities, 0);
+        assert!(stats.warnings(&CaptureSettings::default()).is_empty());
+    }
+
+    #[test]
+    fn timestamp_gaps_are_discontinuities() {
+        let mut stats = PipelineStats::default();
+        // slightly late is fine, a missing frame is not
+        frames(&mut stats, [0, 40, 95, 135, 215, 255]);
+        assert_eq!(stats.discontinuities, 1);
+        // frames without a timestamp don't reset the reference
+        stats.record_frame(None, framerate());
+        frames(&mut stats, [295]);
+        assert_eq!(stats.frames_captured, 8);
+        assert_eq!(stats.discontinuities, 1);
+        assert_eq!(
+            stats.warnings(&CaptureSettings::default()),
+            ["1 timestamp discontinuities from the camera"]
+        );
+    }
+
+    #[test]
+    fn qos_reports_are_running_totals_per_element() {
+        let mut stats = PipelineStats::default();
+        stats.record_qos("x264enc0".to_owned(), 3);
+        stats.record_qos("x264enc0".to_owned(), 5);
+        stats.record_qos("autovideosink0".to_owned(), 2);
+        assert_eq!(stats.frames_dropped(), 7);
+        assert_eq!(
+            stats.warnings(&CaptureSettings::default()),
+            ["7 frames dropped"]
+        );
+    }
+
+    #[test]
+    fn full_queues_and_a_silent_encoder_are_warned_about() {
+        let mut stats = PipelineStats {
+            bitrate: Some(0.0),
+            queues: [
+                (
+                    RECORD_QUEUE.to_owned(),
+                    QueueLevel {
+                        buffers: 180,
+                        fill: 0.9,
+                    },
+                ),
+                (
+                    PREVIEW_QUEUE.to_owned(),
+                    QueueLevel {
+                        buffers: 1,
+                        fill: 0.005,
+                    },
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        // nothing captured yet, so no data is expected either
+        assert_eq!(stats.warnings(&CaptureSettings::default()).len(), 1);
+        frames(&mut stats, [0]);
+        assert_eq!(
+            stats.warnings(&CaptureSettings::default()),
+            [
+                "x264 is not producing any data".to_owned(),
+                format!("{RECORD_QUEUE} is 90% full, whatever comes after it can't keep up"),
+            ]
+        );
+    }
+
+    #[test]
+    fn summary_lists_counters_and_queues() {
+        let mut stats = PipelineStats::default();
+        assert_eq!(stats.summary(), "frames: 0\ndropped: 0\nbitrate: ?");
+        frames(&mut stats, [0, 40]);
+        stats.record_qos("x264enc0".to_owned(), 1);
+        stats.bitrate = Some(19876.4);
+        stats.queues.insert(
+            RECORD_QUEUE.to_owned(),
+            QueueLevel {
+                buffers: 12,
+                fill: 0.06,
+            },
+        );
+        assert_eq!(
+            stats.summary(),
+            format!("frames: 2\ndropped: 1\nbitrate: 19876kbit/s\n{RECORD_QUEUE}: 12 buffers (6%)")
+        );
+    }
+}
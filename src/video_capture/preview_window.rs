@@ -0,0 +1,159 @@
+use super::*;
+use gst::prelude::*;
+use gstreamer as gst;
+
+/// where the preview frames end up
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PreviewSink {
+    #[default]
+    Window,
+    /// frames are thrown away, for machines without a display
+    Headless,
+}
+
+impl PreviewSink {
+    pub fn new(headless: bool) -> Self {
+        match headless {
+            true => Self::Headless,
+            false => Self::Window,
+        }
+    }
+
+    pub fn element(self) -> &'static str {
+        match self {
+            Self::Window => "autovideosink",
+            Self::Headless => "fakesink sync=false",
+        }
+    }
+}
+
+/// how long the device gets to start producing frames before the preview is considered broken
+const START_TIMEOUT: gst::ClockTime = gst::ClockTime::from_seconds(5);
+
+/// in-process preview of a single device, the window closes when this is dropped
+#[derive(Debug)]
+pub struct PreviewWindow {
+    pub video_device: VideoDevice,
+    pipeline: gst::Pipeline,
+}
+
+impl PreviewWindow {
+    /// without `settings` the device is shown in whatever format it offers first
+    pub fn pipeline_description(
+        video_device: &VideoDevice,
+        settings: Option<&CaptureSettings>,
+        sink: PreviewSink,
+    ) -> String {
+        match settings {
+            Some(settings) => settings.preview_pipeline_description(video_device, sink),
+            None => format!(
+                "{} ! decodebin ! videoconvert ! {}",
+                video_device.source_element(),
+                sink.element()
+            ),
+        }
+    }
+
+    /// blocks until the device is streaming, use from [tokio::task::spawn_blocking]
+    #[instrument(ret, err, level = "INFO")]
+    pub fn open(
+        video_device: VideoDevice,
+        settings: Option<CaptureSettings>,
+        sink: PreviewSink,
+    ) -> Result<Self> {
+        gst::init()?;
+        let description = Self::pipeline_description(&video_device, settings.as_ref(), sink);
+        let pipeline = gst::parse_launch(&description)?
+            .downcast::<gst::Pipeline>()
+            .map_err(|_| eyre!("invalid pipeline object"))?;
+        let preview = Self {
+            video_device,
+            pipeline,
+        };
+        preview
+            .pipeline
+            .set_state(gst::State::Playing)
+            .wrap_err("starting the preview")
+            .and_then(|_| match preview.pipeline.state(START_TIMEOUT) {
+                (Ok(_), gst::State::Playing, _) => Ok(()),
+                (result, current, _) => Err(preview.bus_error().unwrap_or_else(|| {
+                    eyre!("preview is {current:?} instead of playing ({result:?})")
+                })),
+            })
+            .map(|_| preview)
+            .wrap_err_with(|| format!("previewing with: {description}"))
+    }
+
+    /// the reason the pipeline didn't start, if GStreamer posted one
+    fn bus_error(&self) -> Option<eyre::Report> {
+        self.pipeline.bus().and_then(|bus| {
+            bus.iter_filtered(&[gst::MessageType::Error])
+                .find_map(|message| match message.view() {
+                    gst::MessageView::Error(error) => Some(eyre!(
+                        "{}: {} ({:?})",
+                        message
+                            .src()
+                            .map(|src| src.path_string().to_string())
+                            .unwrap_or_default(),
+                        error.error(),
+                        error.debug()
+                    )),
+                    _ => None,
+                })
+        })
+    }
+}
+
+impl Drop for PreviewWindow {
+    fn drop(&mut self) {
+        if let Err(message) = self.pipeline.set_state(gst::State::Null) {
+            tracing::warn!(?message, video_device = %self.video_device, "preview did not close cleanly");
+        }
+    }
+}
+
+/// same as [PreviewWindow::open], but without blocking the runtime
+pub async fn open_preview(
+    video_device: VideoDevice,
+    settings: Option<CaptureSettings>,
+    sink: PreviewSink,
+) -> Result<PreviewWindow> {
+    try_enable_low_latency_for_magewell(video_device.clone()).await;
+    tokio::task::spawn_blocking(move || PreviewWindow::open(video_device, settings, sink))
+        .await
+        .wrap_err("preview thread crashed")
+        .and_then(|preview| preview)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capture_settings::PixelFormat;
+
+    #[test]
+    fn previews_use_the_recording_caps_when_there_are_settings() {
+        let video_device = VideoDevice::new("/dev/video1").expect("video device");
+        let settings = CaptureSettings {
+            format: PixelFormat::Mjpg,
+            ..Default::default()
+        };
+        assert_eq!(
+            PreviewWindow::pipeline_description(
+                &video_device,
+                Some(&settings),
+                PreviewSink::Headless
+            ),
+            r#"v4l2src device=/dev/video1 name="preview-source" ! capsfilter caps="image/jpeg, width=1920, height=1080, framerate=25/1" ! jpegdec ! videoconvert ! fakesink sync=false"#
+        );
+        assert_eq!(
+            PreviewWindow::pipeline_description(&video_device, None, PreviewSink::Window),
+            "v4l2src device=/dev/video1 ! decodebin ! videoconvert ! autovideosink"
+        );
+    }
+
+    #[test]
+    fn headless_previews_discard_frames() {
+        assert_eq!(PreviewSink::new(false), PreviewSink::Window);
+        assert_eq!(PreviewSink::new(true).element(), "fakesink sync=false");
+    }
+}